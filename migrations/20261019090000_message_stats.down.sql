@@ -0,0 +1,7 @@
+ALTER TABLE messages DROP COLUMN eval_duration;
+ALTER TABLE messages DROP COLUMN eval_count;
+ALTER TABLE messages DROP COLUMN prompt_eval_duration;
+ALTER TABLE messages DROP COLUMN prompt_eval_count;
+ALTER TABLE messages DROP COLUMN load_duration;
+ALTER TABLE messages DROP COLUMN total_duration;
+ALTER TABLE messages DROP COLUMN llm_model;
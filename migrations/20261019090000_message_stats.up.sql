@@ -0,0 +1,7 @@
+ALTER TABLE messages ADD COLUMN llm_model TEXT;
+ALTER TABLE messages ADD COLUMN total_duration INTEGER;
+ALTER TABLE messages ADD COLUMN load_duration INTEGER;
+ALTER TABLE messages ADD COLUMN prompt_eval_count INTEGER;
+ALTER TABLE messages ADD COLUMN prompt_eval_duration INTEGER;
+ALTER TABLE messages ADD COLUMN eval_count INTEGER;
+ALTER TABLE messages ADD COLUMN eval_duration INTEGER;
@@ -0,0 +1,8 @@
+CREATE TABLE IF NOT EXISTS attachments (
+    id TEXT NOT NULL PRIMARY KEY,
+    message_id TEXT,
+    mime_type TEXT NOT NULL,
+    data BLOB NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+CREATE INDEX idx_attachments_message_id ON attachments (message_id);
@@ -0,0 +1,6 @@
+DROP TABLE IF EXISTS message_citations;
+DROP INDEX IF EXISTS idx_document_chunks_conversation_id;
+DROP INDEX IF EXISTS idx_document_chunks_document_id;
+DROP TABLE IF EXISTS document_chunks;
+DROP INDEX IF EXISTS idx_documents_conversation_id;
+DROP TABLE IF EXISTS documents;
@@ -0,0 +1,25 @@
+CREATE TABLE IF NOT EXISTS documents (
+    id TEXT NOT NULL PRIMARY KEY,
+    conversation_id TEXT NOT NULL,
+    name TEXT NOT NULL,
+    mime_type TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+CREATE INDEX idx_documents_conversation_id ON documents (conversation_id);
+CREATE TABLE IF NOT EXISTS document_chunks (
+    id TEXT NOT NULL PRIMARY KEY,
+    document_id TEXT NOT NULL,
+    conversation_id TEXT NOT NULL,
+    chunk_index INTEGER NOT NULL,
+    content TEXT NOT NULL,
+    embedding BLOB NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+CREATE INDEX idx_document_chunks_document_id ON document_chunks (document_id);
+CREATE INDEX idx_document_chunks_conversation_id ON document_chunks (conversation_id);
+CREATE TABLE IF NOT EXISTS message_citations (
+    message_id TEXT NOT NULL,
+    chunk_id TEXT NOT NULL,
+    rank INTEGER NOT NULL,
+    PRIMARY KEY (message_id, chunk_id)
+);
@@ -0,0 +1,2 @@
+ALTER TABLE messages DROP COLUMN tool_name;
+ALTER TABLE messages DROP COLUMN tool_calls;
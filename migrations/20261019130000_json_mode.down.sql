@@ -0,0 +1,2 @@
+ALTER TABLE messages DROP COLUMN validation_errors;
+ALTER TABLE conversation_settings DROP COLUMN output_format;
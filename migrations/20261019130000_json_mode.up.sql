@@ -0,0 +1,2 @@
+ALTER TABLE conversation_settings ADD COLUMN output_format TEXT;
+ALTER TABLE messages ADD COLUMN validation_errors TEXT;
@@ -0,0 +1,4 @@
+DROP INDEX idx_messages_comparison_id;
+ALTER TABLE messages DROP COLUMN winner;
+ALTER TABLE messages DROP COLUMN comparison_id;
+ALTER TABLE conversation_settings DROP COLUMN comparison_models;
@@ -0,0 +1,4 @@
+ALTER TABLE conversation_settings ADD COLUMN comparison_models TEXT;
+ALTER TABLE messages ADD COLUMN comparison_id BLOB;
+ALTER TABLE messages ADD COLUMN winner BOOLEAN NOT NULL DEFAULT FALSE;
+CREATE INDEX idx_messages_comparison_id ON messages (comparison_id);
@@ -0,0 +1,7 @@
+CREATE TABLE IF NOT EXISTS message_feedback (
+    message_id TEXT NOT NULL PRIMARY KEY,
+    rating INTEGER NOT NULL CHECK (rating IN (-1, 1)),
+    note TEXT,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    updated_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
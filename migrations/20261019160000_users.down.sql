@@ -0,0 +1,6 @@
+ALTER TABLE attachments DROP COLUMN user_id;
+DROP INDEX IF EXISTS idx_conversations_user_id;
+ALTER TABLE conversations DROP COLUMN user_id;
+DROP INDEX IF EXISTS idx_sessions_user_id;
+DROP TABLE IF EXISTS sessions;
+DROP TABLE IF EXISTS users;
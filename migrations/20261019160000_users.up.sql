@@ -0,0 +1,18 @@
+CREATE TABLE IF NOT EXISTS users (
+    id TEXT NOT NULL PRIMARY KEY,
+    username TEXT NOT NULL UNIQUE,
+    password_hash TEXT NOT NULL,
+    is_admin BOOLEAN NOT NULL DEFAULT FALSE,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+CREATE TABLE IF NOT EXISTS sessions (
+    token_hash BLOB NOT NULL PRIMARY KEY,
+    user_id TEXT NOT NULL,
+    expires_at TIMESTAMP NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL
+);
+CREATE INDEX idx_sessions_user_id ON sessions (user_id);
+-- rows created before accounts existed stay without an owner until the first admin claims them
+ALTER TABLE conversations ADD COLUMN user_id TEXT;
+CREATE INDEX idx_conversations_user_id ON conversations (user_id);
+ALTER TABLE attachments ADD COLUMN user_id TEXT;
@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_users_proxy_subject;
+ALTER TABLE users DROP COLUMN proxy_subject;
+ALTER TABLE users DROP COLUMN email;
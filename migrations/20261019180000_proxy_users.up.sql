@@ -0,0 +1,4 @@
+ALTER TABLE users ADD COLUMN email TEXT;
+-- identity sent by the reverse proxy, local accounts have none and are never bound to it
+ALTER TABLE users ADD COLUMN proxy_subject TEXT;
+CREATE UNIQUE INDEX idx_users_proxy_subject ON users (proxy_subject);
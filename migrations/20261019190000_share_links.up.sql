@@ -0,0 +1,9 @@
+CREATE TABLE IF NOT EXISTS share_links (
+    id TEXT NOT NULL PRIMARY KEY,
+    conversation_id TEXT NOT NULL,
+    token_hash BLOB NOT NULL UNIQUE,
+    expires_at TIMESTAMP,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    FOREIGN KEY (conversation_id) REFERENCES conversations (id) ON DELETE CASCADE
+);
+CREATE INDEX idx_share_links_conversation_id ON share_links (conversation_id);
@@ -0,0 +1,7 @@
+ALTER TABLE conversation_settings DROP COLUMN system_prompt;
+ALTER TABLE conversations DROP COLUMN folder_id;
+DROP INDEX IF EXISTS idx_conversation_tags_tag_id;
+DROP TABLE IF EXISTS conversation_tags;
+DROP TABLE IF EXISTS tags;
+DROP INDEX IF EXISTS idx_folders_user_id;
+DROP TABLE IF EXISTS folders;
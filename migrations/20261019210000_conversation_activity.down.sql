@@ -0,0 +1,3 @@
+DROP INDEX IF EXISTS idx_conversations_user_id_folder_id_updated_at;
+DROP TRIGGER IF EXISTS trg_messages_conversation_activity;
+ALTER TABLE conversations DROP COLUMN updated_at;
@@ -0,0 +1,15 @@
+-- last activity, keeps the sidebar ordered without scanning messages
+ALTER TABLE conversations ADD COLUMN updated_at TIMESTAMP NOT NULL DEFAULT '1970-01-01T00:00:00+00:00';
+UPDATE conversations
+SET updated_at = COALESCE(
+    ( SELECT MAX(m.created_at) FROM messages m WHERE m.conversation_id = conversations.id ),
+    created_at
+);
+CREATE TRIGGER trg_messages_conversation_activity
+AFTER INSERT ON messages
+BEGIN
+    UPDATE conversations
+    SET updated_at = NEW.created_at
+    WHERE id = NEW.conversation_id AND updated_at < NEW.created_at;
+END;
+CREATE INDEX idx_conversations_user_id_folder_id_updated_at ON conversations (user_id, folder_id, updated_at, id);
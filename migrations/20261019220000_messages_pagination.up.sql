@@ -0,0 +1,2 @@
+-- keyset pagination of the message history
+CREATE INDEX idx_messages_conversation_id_created_at ON messages (conversation_id, created_at, id);
@@ -0,0 +1,32 @@
+CREATE TABLE messages_old (
+    id TEXT NOT NULL PRIMARY KEY,
+    role TEXT NOT NULL,
+    content TEXT NOT NULL,
+    conversation_id TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    llm_model TEXT,
+    total_duration INTEGER,
+    load_duration INTEGER,
+    prompt_eval_count INTEGER,
+    prompt_eval_duration INTEGER,
+    eval_count INTEGER,
+    eval_duration INTEGER,
+    tool_calls TEXT,
+    tool_name TEXT,
+    validation_errors TEXT,
+    comparison_id BLOB,
+    winner BOOLEAN NOT NULL DEFAULT FALSE
+);
+INSERT INTO messages_old SELECT * FROM messages;
+DROP TABLE messages;
+ALTER TABLE messages_old RENAME TO messages;
+CREATE INDEX idx_messages_conversation_id ON messages (conversation_id);
+CREATE INDEX idx_messages_comparison_id ON messages (comparison_id);
+CREATE INDEX idx_messages_conversation_id_created_at ON messages (conversation_id, created_at, id);
+CREATE TRIGGER trg_messages_conversation_activity
+AFTER INSERT ON messages
+BEGIN
+    UPDATE conversations
+    SET updated_at = NEW.created_at
+    WHERE id = NEW.conversation_id AND updated_at < NEW.created_at;
+END;
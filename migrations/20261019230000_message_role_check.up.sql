@@ -0,0 +1,46 @@
+-- only known roles, a single unexpected row used to crash inference
+CREATE TABLE messages_new (
+    id TEXT NOT NULL PRIMARY KEY,
+    role TEXT NOT NULL CHECK (role IN ('system', 'user', 'assistant', 'tool')),
+    content TEXT NOT NULL,
+    conversation_id TEXT NOT NULL,
+    created_at TIMESTAMP DEFAULT CURRENT_TIMESTAMP NOT NULL,
+    llm_model TEXT,
+    total_duration INTEGER,
+    load_duration INTEGER,
+    prompt_eval_count INTEGER,
+    prompt_eval_duration INTEGER,
+    eval_count INTEGER,
+    eval_duration INTEGER,
+    tool_calls TEXT,
+    tool_name TEXT,
+    validation_errors TEXT,
+    comparison_id BLOB,
+    winner BOOLEAN NOT NULL DEFAULT FALSE
+);
+-- roles used to be parsed case-insensitively, anything unknown is inferred from what the row carries
+INSERT INTO messages_new
+SELECT
+    id,
+    CASE
+        WHEN LOWER(TRIM(role)) IN ('system', 'user', 'assistant', 'tool') THEN LOWER(TRIM(role))
+        WHEN tool_name IS NOT NULL THEN 'tool'
+        WHEN tool_calls IS NOT NULL OR llm_model IS NOT NULL OR comparison_id IS NOT NULL THEN 'assistant'
+        ELSE 'user'
+    END,
+    content, conversation_id, created_at,
+    llm_model, total_duration, load_duration, prompt_eval_count, prompt_eval_duration, eval_count, eval_duration,
+    tool_calls, tool_name, validation_errors, comparison_id, winner
+FROM messages;
+DROP TABLE messages;
+ALTER TABLE messages_new RENAME TO messages;
+CREATE INDEX idx_messages_conversation_id ON messages (conversation_id);
+CREATE INDEX idx_messages_comparison_id ON messages (comparison_id);
+CREATE INDEX idx_messages_conversation_id_created_at ON messages (conversation_id, created_at, id);
+CREATE TRIGGER trg_messages_conversation_activity
+AFTER INSERT ON messages
+BEGIN
+    UPDATE conversations
+    SET updated_at = NEW.created_at
+    WHERE id = NEW.conversation_id AND updated_at < NEW.created_at;
+END;
@@ -0,0 +1,2 @@
+-- why the answer stopped before the model finished it
+ALTER TABLE messages ADD COLUMN generation_error TEXT;
@@ -0,0 +1,402 @@
+use argon2::password_hash::{rand_core::OsRng, PasswordHash, PasswordHasher, SaltString};
+use std::net::{IpAddr, SocketAddr};
+
+use argon2::{Argon2, PasswordVerifier};
+use axum::{
+    extract::{ConnectInfo, Request, State},
+    middleware::Next,
+    response::{IntoResponse, Redirect, Response},
+};
+use axum_extra::extract::cookie::{Cookie, CookieJar, SameSite};
+use base64::prelude::{Engine as _, BASE64_URL_SAFE_NO_PAD};
+use chrono::{Duration, Utc};
+use http::{header, HeaderMap, HeaderValue, Method, StatusCode};
+use once_cell::sync::Lazy;
+use rand::RngCore;
+use sha2::{Digest, Sha256};
+use tracing::{error, warn};
+
+use crate::config::{ProxyAuth, CONFIG};
+use crate::db;
+use crate::error::{Error, Result};
+use crate::models::{ApiToken, Session, TokenScope, User};
+use crate::state::AppState;
+
+pub const SESSION_COOKIE: &str = "lokai_session";
+pub const SESSION_DAYS: i64 = 30;
+const TOKEN_BYTES: usize = 32;
+
+/// Checked when the username is unknown, so that the answer takes as long as for a wrong password.
+static DUMMY_PASSWORD_HASH: Lazy<String> =
+    Lazy::new(|| hash_password("not a password").expect("hashing a constant password"));
+
+pub fn hash_password(password: &str) -> Result<String> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|err| Error::Auth(err.to_string()))
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    let Ok(password_hash) = PasswordHash::new(password_hash) else {
+        return false;
+    };
+    Argon2::default()
+        .verify_password(password.as_bytes(), &password_hash)
+        .is_ok()
+}
+
+/// The user if the password matches, unknown users take the time of a password check too.
+pub fn check_credentials(user: Option<User>, password: &str) -> Option<User> {
+    let password_hash = user
+        .as_ref()
+        .map_or(DUMMY_PASSWORD_HASH.as_str(), |user| &user.password_hash);
+    let verified = verify_password(password, password_hash);
+    user.filter(|_| verified)
+}
+
+/// Random secret handed out to the client, only its hash is stored.
+pub fn generate_token() -> String {
+    let mut bytes = [0u8; TOKEN_BYTES];
+    OsRng.fill_bytes(&mut bytes);
+    BASE64_URL_SAFE_NO_PAD.encode(bytes)
+}
+
+pub fn hash_token(token: &str) -> Vec<u8> {
+    Sha256::digest(token.as_bytes()).to_vec()
+}
+
+/// Starts a session for the user and adds its cookie to the jar.
+pub async fn login(state: &AppState, jar: CookieJar, user: &User) -> Result<CookieJar> {
+    let token = generate_token();
+    let session = Session::new(
+        hash_token(&token),
+        user.id,
+        Utc::now() + Duration::days(SESSION_DAYS),
+    );
+    db::create_session(state.sqlite.clone(), session).await?;
+
+    let cookie = Cookie::build((SESSION_COOKIE, token))
+        .path("/")
+        .http_only(true)
+        .same_site(SameSite::Lax)
+        .max_age(time::Duration::days(SESSION_DAYS))
+        .build();
+    Ok(jar.add(cookie))
+}
+
+pub async fn logout(state: &AppState, jar: CookieJar) -> Result<CookieJar> {
+    if let Some(cookie) = jar.get(SESSION_COOKIE) {
+        db::delete_session(state.sqlite.clone(), hash_token(cookie.value())).await?;
+    }
+    Ok(jar.remove(Cookie::build(SESSION_COOKIE).path("/")))
+}
+
+async fn session_user(state: &AppState, jar: &CookieJar) -> Result<Option<User>> {
+    let Some(cookie) = jar.get(SESSION_COOKIE) else {
+        return Ok(None);
+    };
+    db::get_session_user(state.sqlite.clone(), hash_token(cookie.value()), Utc::now()).await
+}
+
+/// Identity forwarded by a trusted proxy, the email doubles as the username when no user is sent.
+fn proxy_identity(
+    proxy_auth: &ProxyAuth,
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> Option<(String, Option<String>)> {
+    let header = |name: &str| {
+        headers
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(str::trim)
+            .filter(|value| !value.is_empty())
+            .map(str::to_string)
+    };
+    let user = header(&proxy_auth.user_header);
+    let email = header(&proxy_auth.email_header);
+    if user.is_none() && email.is_none() {
+        return None;
+    }
+    let peer = peer.map(|peer| peer.to_canonical());
+    if !peer.is_some_and(|peer| proxy_auth.trusted_proxies.contains(&peer)) {
+        warn!(?peer, "ignoring identity headers sent by an untrusted peer");
+        return None;
+    }
+
+    Some((user.or_else(|| email.clone())?, email))
+}
+
+async fn proxy_user(
+    state: &AppState,
+    peer: Option<IpAddr>,
+    headers: &HeaderMap,
+) -> Result<Option<User>> {
+    let Some(proxy_auth) = &CONFIG.lokai_proxy_auth else {
+        return Ok(None);
+    };
+    let Some((username, email)) = proxy_identity(proxy_auth, peer, headers) else {
+        return Ok(None);
+    };
+    let user = db::upsert_proxy_user(state.sqlite.clone(), username.clone(), email).await?;
+    if user.is_none() {
+        warn!(
+            username,
+            "proxy identity matches a local account, ignoring it"
+        );
+    }
+    Ok(user)
+}
+
+/// Rejects requests without a valid session or trusted proxy headers,
+/// handlers get the caller as `Extension<User>`.
+pub async fn require_user(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let peer = request
+        .extensions()
+        .get::<ConnectInfo<SocketAddr>>()
+        .map(|ConnectInfo(addr)| addr.ip());
+    let user = match proxy_user(&state, peer, request.headers()).await {
+        Ok(None) => session_user(&state, &jar).await,
+        user => user,
+    };
+    match user {
+        Ok(Some(user)) => {
+            request.extensions_mut().insert(user);
+            next.run(request).await
+        }
+        Ok(None) => unauthorized(&request),
+        Err(err) => {
+            error!("Error when authenticating request: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Like `require_user`, but also accepts an API token with the scope the request needs,
+/// which handlers get as `Extension<ApiToken>`.
+pub async fn require_api_user(
+    State(state): State<AppState>,
+    jar: CookieJar,
+    mut request: Request,
+    next: Next,
+) -> Response {
+    let Some(token) = bearer_token(request.headers()) else {
+        return require_user(State(state), jar, request, next).await;
+    };
+
+    match token_user(&state, &token).await {
+        Ok(Some((user, api_token))) if api_token.has_scope(required_scope(&request)) => {
+            // the token has its own request limit
+            request.extensions_mut().insert(api_token);
+            request.extensions_mut().insert(user);
+            next.run(request).await
+        }
+        Ok(Some(_)) => (
+            StatusCode::FORBIDDEN,
+            format!("token requires {} scope", required_scope(&request)),
+        )
+            .into_response(),
+        Ok(None) => (
+            StatusCode::UNAUTHORIZED,
+            [(header::WWW_AUTHENTICATE, "Bearer")],
+        )
+            .into_response(),
+        Err(err) => {
+            error!("Error when authenticating api token: {:?}", err);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+fn bearer_token(headers: &HeaderMap) -> Option<String> {
+    let value = headers.get(header::AUTHORIZATION)?.to_str().ok()?;
+    value
+        .strip_prefix("Bearer ")
+        .map(|token| token.trim().to_string())
+}
+
+async fn token_user(state: &AppState, token: &str) -> Result<Option<(User, ApiToken)>> {
+    let Some(api_token) =
+        db::use_api_token(state.sqlite.clone(), hash_token(token), Utc::now()).await?
+    else {
+        return Ok(None);
+    };
+    let maybe_user = db::get_user(state.sqlite.clone(), api_token.user_id).await?;
+    Ok(maybe_user.map(|user| (user, api_token)))
+}
+
+/// Prompts sent over the websocket create messages, hence need `write`.
+fn required_scope(request: &Request) -> TokenScope {
+    if request.uri().path() == "/ws" {
+        return TokenScope::Write;
+    }
+    match *request.method() {
+        Method::GET | Method::HEAD => TokenScope::Read,
+        _ => TokenScope::Write,
+    }
+}
+
+/// Lets only admins through, must be layered after `require_user`.
+pub async fn require_admin(request: Request, next: Next) -> Response {
+    match request.extensions().get::<User>() {
+        Some(user) if user.is_admin => next.run(request).await,
+        _ => StatusCode::FORBIDDEN.into_response(),
+    }
+}
+
+fn unauthorized(request: &Request) -> Response {
+    let path = request.uri().path();
+    if request.headers().contains_key("HX-Request") {
+        // HTMX follows HX-Redirect with a full page load
+        let mut headers = HeaderMap::new();
+        headers.insert("HX-Redirect", HeaderValue::from_static("/login"));
+        return (StatusCode::UNAUTHORIZED, headers).into_response();
+    }
+    if path.starts_with("/api") || path == "/ws" {
+        return StatusCode::UNAUTHORIZED.into_response();
+    }
+    Redirect::to("/login").into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_hash_roundtrip() {
+        // given:
+        let password_hash = hash_password("correct horse").unwrap();
+
+        // when:
+        let correct = verify_password("correct horse", &password_hash);
+        let wrong = verify_password("battery staple", &password_hash);
+        let malformed = verify_password("correct horse", "");
+
+        // then:
+        assert!(correct);
+        assert!(!wrong);
+        assert!(!malformed);
+    }
+
+    #[test]
+    fn test_check_credentials() {
+        // given:
+        let user = User::new(
+            "ann".to_string(),
+            hash_password("correct horse").unwrap(),
+            false,
+        );
+
+        // when:
+        let correct = check_credentials(Some(user.clone()), "correct horse");
+        let wrong = check_credentials(Some(user.clone()), "battery staple");
+        let unknown = check_credentials(None, "not a password");
+
+        // then:
+        assert_eq!(correct.map(|user| user.id), Some(user.id));
+        assert!(wrong.is_none());
+        assert!(unknown.is_none());
+    }
+
+    #[test]
+    fn test_tokens_are_unique_and_hashed() {
+        // given:
+        let (first, second) = (generate_token(), generate_token());
+
+        // then:
+        assert_ne!(first, second);
+        assert_eq!(hash_token(&first), hash_token(&first));
+        assert_eq!(hash_token(&first).len(), 32);
+    }
+
+    #[test]
+    fn test_bearer_token() {
+        // given:
+        let mut headers = HeaderMap::new();
+        assert_eq!(bearer_token(&headers), None);
+
+        // when:
+        headers.insert(
+            header::AUTHORIZATION,
+            HeaderValue::from_static("Bearer abc "),
+        );
+        let bearer = bearer_token(&headers);
+        headers.insert(header::AUTHORIZATION, HeaderValue::from_static("Basic abc"));
+        let basic = bearer_token(&headers);
+
+        // then:
+        assert_eq!(bearer, Some("abc".to_string()));
+        assert_eq!(basic, None);
+    }
+
+    #[test]
+    fn test_required_scope() {
+        let request = |method: Method, uri: &str| {
+            Request::builder()
+                .method(method)
+                .uri(uri)
+                .body(axum::body::Body::empty())
+                .unwrap()
+        };
+
+        assert_eq!(
+            required_scope(&request(Method::GET, "/api/stats")),
+            TokenScope::Read
+        );
+        assert_eq!(
+            required_scope(&request(Method::DELETE, "/api/conversations/1")),
+            TokenScope::Write
+        );
+        assert_eq!(
+            required_scope(&request(Method::GET, "/ws")),
+            TokenScope::Write
+        );
+    }
+
+    #[test]
+    fn test_proxy_identity() {
+        // given:
+        let proxy_auth = ProxyAuth {
+            user_header: "X-Forwarded-User".to_string(),
+            email_header: "X-Forwarded-Email".to_string(),
+            trusted_proxies: vec!["10.0.0.1".parse().unwrap()],
+        };
+        let proxy = Some("10.0.0.1".parse().unwrap());
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            "x-forwarded-email",
+            HeaderValue::from_static("ann@example.com"),
+        );
+
+        // when:
+        let email_only = proxy_identity(&proxy_auth, proxy, &headers);
+        headers.insert("X-Forwarded-User", HeaderValue::from_static("ann"));
+        let both = proxy_identity(&proxy_auth, proxy, &headers);
+        let mapped = proxy_identity(
+            &proxy_auth,
+            Some("::ffff:10.0.0.1".parse().unwrap()),
+            &headers,
+        );
+        let untrusted = proxy_identity(&proxy_auth, Some("10.0.0.2".parse().unwrap()), &headers);
+        let unknown_peer = proxy_identity(&proxy_auth, None, &headers);
+        let no_headers = proxy_identity(&proxy_auth, proxy, &HeaderMap::new());
+
+        // then:
+        let email = Some("ann@example.com".to_string());
+        assert_eq!(
+            email_only,
+            Some(("ann@example.com".to_string(), email.clone()))
+        );
+        assert_eq!(both, Some(("ann".to_string(), email)));
+        assert_eq!(mapped, both);
+        assert_eq!(untrusted, None);
+        assert_eq!(unknown_peer, None);
+        assert_eq!(no_headers, None);
+    }
+}
@@ -0,0 +1,115 @@
+// Layout for server-rendered SVG charts, the markup lives in `templates/stats/bar_chart.html`
+const WIDTH: f64 = 640.0;
+const HEIGHT: f64 = 240.0;
+const PADDING_TOP: f64 = 20.0;
+const PADDING_BOTTOM: f64 = 40.0;
+const PADDING_X: f64 = 10.0;
+const BAR_GAP: f64 = 4.0;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bar {
+    pub label: String,
+    pub value: f64,
+    pub x: f64,
+    pub y: f64,
+    pub width: f64,
+    pub height: f64,
+}
+
+impl Bar {
+    pub fn center_x(&self) -> f64 {
+        self.x + self.width / 2.0
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct BarChart {
+    pub title: String,
+    pub unit: String,
+    pub bars: Vec<Bar>,
+}
+
+impl BarChart {
+    pub fn new(title: &str, unit: &str, data: Vec<(String, f64)>) -> Self {
+        let max_value = data.iter().map(|(_, value)| *value).fold(0.0, f64::max);
+        let plot_height = HEIGHT - PADDING_TOP - PADDING_BOTTOM;
+        let slot_width = (WIDTH - 2.0 * PADDING_X) / data.len().max(1) as f64;
+
+        let bars = data
+            .into_iter()
+            .enumerate()
+            .map(|(i, (label, value))| {
+                let height = if max_value > 0.0 {
+                    value / max_value * plot_height
+                } else {
+                    0.0
+                };
+                Bar {
+                    label,
+                    value,
+                    x: PADDING_X + i as f64 * slot_width + BAR_GAP / 2.0,
+                    y: PADDING_TOP + plot_height - height,
+                    width: (slot_width - BAR_GAP).max(1.0),
+                    height,
+                }
+            })
+            .collect();
+
+        Self {
+            title: title.to_string(),
+            unit: unit.to_string(),
+            bars,
+        }
+    }
+
+    pub fn width(&self) -> f64 {
+        WIDTH
+    }
+
+    pub fn height(&self) -> f64 {
+        HEIGHT
+    }
+
+    pub fn baseline_y(&self) -> f64 {
+        HEIGHT - PADDING_BOTTOM
+    }
+
+    pub fn label_y(&self) -> f64 {
+        HEIGHT - PADDING_BOTTOM + 14.0
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_bar_chart_scales_to_max_value() {
+        // given:
+        let data = vec![("a".to_string(), 10.0), ("b".to_string(), 5.0)];
+
+        // when:
+        let chart = BarChart::new("title", "unit", data);
+
+        // then:
+        let plot_height = HEIGHT - PADDING_TOP - PADDING_BOTTOM;
+        assert_eq!(chart.bars.len(), 2);
+        assert_eq!(chart.bars[0].height, plot_height);
+        assert_eq!(chart.bars[1].height, plot_height / 2.0);
+        assert_eq!(chart.bars[0].y + chart.bars[0].height, chart.baseline_y());
+        assert!(chart.bars[0].x + chart.bars[0].width < chart.bars[1].x);
+    }
+
+    #[test]
+    fn test_bar_chart_with_zero_values() {
+        // given:
+        let data = vec![("a".to_string(), 0.0)];
+
+        // when:
+        let chart = BarChart::new("title", "unit", data);
+
+        // then:
+        assert_eq!(chart.bars[0].height, 0.0);
+        assert_eq!(chart.bars[0].y, chart.baseline_y());
+    }
+}
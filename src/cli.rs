@@ -0,0 +1,412 @@
+use std::collections::{hash_map::Entry, HashMap};
+use std::fs::{self, File};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+
+use chrono::Utc;
+use clap::{Parser, Subcommand};
+use sqlx::migrate::{Migrate, Migrator};
+use sqlx::SqlitePool;
+
+use crate::config::{self, CONFIG};
+use crate::error::{Error, Result};
+use crate::models::{ConversationExport, Export, User};
+use crate::{auth, db, validation};
+
+/// Chat with local LLMs served by Ollama.
+#[derive(Parser)]
+#[command(version)]
+pub struct Cli {
+    #[command(flatten)]
+    pub flags: config::Flags,
+    /// `serve` when not given
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Run the web server
+    Serve {
+        /// Refuse to start with pending migrations instead of applying them
+        #[arg(long)]
+        skip_migrations: bool,
+    },
+    /// Apply, revert or list database migrations
+    #[command(subcommand)]
+    Migrate(MigrateCommand),
+    /// Inspect the configuration
+    #[command(subcommand)]
+    Config(ConfigCommand),
+    #[command(flatten)]
+    Admin(AdminCommand),
+}
+
+#[derive(Subcommand)]
+pub enum ConfigCommand {
+    /// Print the effective configuration and where each value comes from
+    Show,
+}
+
+/// Maintenance of the data, expects an up to date database schema.
+#[derive(Subcommand)]
+pub enum AdminCommand {
+    /// Write conversations and their messages as JSON, without attachments and documents
+    Export {
+        /// Only conversations of this user, everyone's by default,
+        /// including the ones from before user accounts
+        #[arg(long, value_name = "USERNAME")]
+        user: Option<String>,
+        /// File to write, standard output by default
+        #[arg(long, short, value_name = "PATH")]
+        output: Option<PathBuf>,
+    },
+    /// Add conversations written by `lokai export`
+    Import {
+        #[arg(value_name = "PATH")]
+        file: PathBuf,
+        /// Owner of all imported conversations, the exported owners by default.
+        /// Required for conversations exported from before user accounts
+        #[arg(long, value_name = "USERNAME")]
+        user: Option<String>,
+    },
+    /// Manage user accounts
+    #[command(subcommand)]
+    User(UserCommand),
+    /// Database upkeep
+    #[command(subcommand)]
+    Db(DbCommand),
+}
+
+#[derive(Subcommand)]
+pub enum MigrateCommand {
+    /// Apply pending migrations
+    Up,
+    /// Revert the latest migration
+    Down {
+        /// Revert every migration newer than this version instead
+        #[arg(long, value_name = "VERSION")]
+        target: Option<i64>,
+    },
+    /// List migrations and whether they are applied
+    Status,
+}
+
+#[derive(Subcommand)]
+pub enum UserCommand {
+    /// Create a user, the password is asked for
+    Add {
+        username: String,
+        #[arg(long)]
+        admin: bool,
+        /// Read the password from the first line of standard input
+        #[arg(long)]
+        password_stdin: bool,
+    },
+    /// Set a new password and end all sessions of the user
+    ResetPassword {
+        username: String,
+        /// Read the password from the first line of standard input
+        #[arg(long)]
+        password_stdin: bool,
+    },
+}
+
+#[derive(Subcommand)]
+pub enum DbCommand {
+    /// Reclaim the space of deleted rows
+    Vacuum,
+}
+
+pub async fn run(command: AdminCommand, sqlite: SqlitePool, migrator: &Migrator) -> Result<()> {
+    check_migrations(&sqlite, migrator).await?;
+
+    match command {
+        AdminCommand::Export { user, output } => export(sqlite, user, output).await,
+        AdminCommand::Import { file, user } => import(sqlite, file, user).await,
+        AdminCommand::User(UserCommand::Add {
+            username,
+            admin,
+            password_stdin,
+        }) => add_user(sqlite, username, admin, password_stdin).await,
+        AdminCommand::User(UserCommand::ResetPassword {
+            username,
+            password_stdin,
+        }) => reset_password(sqlite, username, password_stdin).await,
+        AdminCommand::Db(DbCommand::Vacuum) => {
+            let (before, after) = db::vacuum(sqlite).await?;
+            println!(
+                "Database shrunk from {} KiB to {} KiB",
+                before / 1024,
+                after / 1024
+            );
+            Ok(())
+        }
+    }
+}
+
+pub fn show_config(flags: &config::Flags) {
+    let settings = config::Settings::load(flags).unwrap_or_else(|errors| exit_with_errors(&errors));
+    print!("{}", settings.show());
+    if let Err(errors) = config::Config::from_settings(&settings) {
+        exit_with_errors(&errors);
+    }
+}
+
+pub fn exit_with_errors(errors: &[String]) -> ! {
+    eprintln!("Invalid configuration:");
+    for error in errors {
+        eprintln!("  {error}");
+    }
+    std::process::exit(1);
+}
+
+async fn applied_versions(sqlite: &SqlitePool) -> Result<Vec<i64>> {
+    let mut connection = sqlite.acquire().await?;
+    connection
+        .ensure_migrations_table()
+        .await
+        .map_err(sqlx::Error::from)?;
+    let mut versions: Vec<i64> = connection
+        .list_applied_migrations()
+        .await
+        .map_err(sqlx::Error::from)?
+        .into_iter()
+        .map(|migration| migration.version)
+        .collect();
+    versions.sort();
+
+    Ok(versions)
+}
+
+/// Commands other than `migrate` expect the schema they were built for.
+pub async fn check_migrations(sqlite: &SqlitePool, migrator: &Migrator) -> Result<()> {
+    let applied = applied_versions(sqlite).await?;
+    let pending = migrator
+        .iter()
+        .filter(|migration| !migration.migration_type.is_down_migration())
+        .filter(|migration| !applied.contains(&migration.version))
+        .count();
+    if pending > 0 {
+        return Err(Error::Validation(format!(
+            "The database has {pending} pending migrations, apply them with `lokai migrate up`"
+        )));
+    }
+    Ok(())
+}
+
+pub async fn migrate(
+    command: MigrateCommand,
+    sqlite: SqlitePool,
+    migrator: &Migrator,
+) -> Result<()> {
+    let applied = applied_versions(&sqlite).await?;
+    let up_migrations = || {
+        migrator
+            .iter()
+            .filter(|migration| !migration.migration_type.is_down_migration())
+    };
+
+    match command {
+        MigrateCommand::Up => {
+            migrator.run(&sqlite).await.map_err(sqlx::Error::from)?;
+            let mut pending = up_migrations()
+                .filter(|migration| !applied.contains(&migration.version))
+                .peekable();
+            if pending.peek().is_none() {
+                println!("No pending migrations");
+            }
+            for migration in pending {
+                println!("Applied {} {}", migration.version, migration.description);
+            }
+        }
+        MigrateCommand::Down { target } => {
+            let Some(latest) = applied.last() else {
+                println!("No applied migrations");
+                return Ok(());
+            };
+            let previous = applied.iter().rev().nth(1).copied().unwrap_or_default();
+            let target = target.unwrap_or(previous);
+            migrator
+                .undo(&sqlite, target)
+                .await
+                .map_err(sqlx::Error::from)?;
+            for migration in up_migrations()
+                .filter(|migration| migration.version > target && migration.version <= *latest)
+                .filter(|migration| applied.contains(&migration.version))
+            {
+                println!("Reverted {} {}", migration.version, migration.description);
+            }
+        }
+        MigrateCommand::Status => {
+            for migration in up_migrations() {
+                let status = match applied.contains(&migration.version) {
+                    true => "applied",
+                    false => "pending",
+                };
+                println!("{status}  {}  {}", migration.version, migration.description);
+            }
+        }
+    }
+
+    Ok(())
+}
+
+async fn find_user(sqlite: &SqlitePool, username: &str) -> Result<User> {
+    db::get_user_by_username(sqlite.clone(), username)
+        .await?
+        .ok_or_else(|| Error::NotFound(format!("user {username}")))
+}
+
+async fn export(sqlite: SqlitePool, user: Option<String>, output: Option<PathBuf>) -> Result<()> {
+    let owners = match user {
+        Some(username) => vec![Some(find_user(&sqlite, &username).await?)],
+        // conversations from before user accounts have no owner
+        None => db::get_users(sqlite.clone())
+            .await?
+            .into_iter()
+            .map(Some)
+            .chain([None])
+            .collect(),
+    };
+
+    let mut conversations = Vec::new();
+    for owner in owners {
+        let user_id = owner.as_ref().map(|owner| owner.id);
+        for conversation in db::get_user_conversations(sqlite.clone(), user_id).await? {
+            let settings = db::get_conversation_settings(sqlite.clone(), conversation.id).await?;
+            let messages = db::get_conversation_messages(sqlite.clone(), conversation.id).await?;
+            conversations.push(ConversationExport {
+                owner: owner.as_ref().map(|owner| owner.username.clone()),
+                conversation,
+                settings,
+                messages,
+            });
+        }
+    }
+    let export = Export {
+        exported_at: Utc::now(),
+        conversations,
+    };
+
+    match output {
+        Some(path) => {
+            let mut writer = BufWriter::new(File::create(&path)?);
+            serde_json::to_writer_pretty(&mut writer, &export).map_err(io::Error::from)?;
+            writer.flush()?;
+            eprintln!(
+                "Exported {} conversations to {}",
+                export.conversations.len(),
+                path.display()
+            );
+        }
+        None => {
+            let mut stdout = io::stdout().lock();
+            serde_json::to_writer_pretty(&mut stdout, &export).map_err(io::Error::from)?;
+            writeln!(stdout)?;
+        }
+    }
+
+    Ok(())
+}
+
+async fn import(sqlite: SqlitePool, file: PathBuf, user: Option<String>) -> Result<()> {
+    let content = fs::read_to_string(&file)?;
+    let export: Export = serde_json::from_str(&content)
+        .map_err(|err| Error::Validation(format!("Cannot parse {}: {err}", file.display())))?;
+
+    // every owner has to exist before anything is imported
+    let mut owners: HashMap<String, User> = HashMap::new();
+    let mut conversations = Vec::new();
+    for conversation in export.conversations {
+        let Some(username) = user.clone().or(conversation.owner.clone()) else {
+            return Err(Error::Validation(format!(
+                "Conversation {} is from before user accounts, import it with --user",
+                conversation.conversation.name
+            )));
+        };
+        let owner = match owners.entry(username) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                let owner = find_user(&sqlite, entry.key()).await?;
+                entry.insert(owner)
+            }
+        };
+        conversations.push(conversation.with_new_ids(owner.id));
+    }
+
+    let imported = db::import_conversations(
+        sqlite,
+        conversations,
+        CONFIG.lokai_default_llm_model.clone(),
+    )
+    .await?;
+    println!("Imported {} conversations", imported.len());
+
+    Ok(())
+}
+
+fn read_password(from_stdin: bool) -> Result<String> {
+    if from_stdin {
+        let mut password = String::new();
+        io::stdin().read_line(&mut password)?;
+        return Ok(password.trim_end_matches(['\r', '\n']).to_string());
+    }
+    let prompt = |prompt| {
+        rpassword::prompt_password(prompt).map_err(|err| {
+            Error::Validation(format!(
+                "Cannot read the password from the terminal, use --password-stdin: {err}"
+            ))
+        })
+    };
+    let password = prompt("Password: ")?;
+    if prompt("Repeat password: ")? != password {
+        return Err(Error::Validation("Passwords don't match".to_string()));
+    }
+    Ok(password)
+}
+
+async fn add_user(
+    sqlite: SqlitePool,
+    username: String,
+    admin: bool,
+    password_stdin: bool,
+) -> Result<()> {
+    if db::get_user_by_username(sqlite.clone(), username.trim())
+        .await?
+        .is_some()
+    {
+        return Err(Error::Validation(format!(
+            "User {} already exists",
+            username.trim()
+        )));
+    }
+    let password = read_password(password_stdin)?;
+    let username = validation::credentials(&username, &password)?;
+    let user = User::new(username, auth::hash_password(&password)?, admin);
+
+    // like the setup page, the first admin takes over conversations from before user accounts
+    let user = match admin && db::count_users(sqlite.clone()).await? == 0 {
+        true => db::create_first_admin(sqlite.clone(), user.clone())
+            .await?
+            .unwrap_or(user),
+        false => db::create_user(sqlite, user).await?,
+    };
+    let role = if user.is_admin { "admin" } else { "user" };
+    println!("Created {role} {}", user.username);
+
+    Ok(())
+}
+
+async fn reset_password(sqlite: SqlitePool, username: String, password_stdin: bool) -> Result<()> {
+    let user = find_user(&sqlite, &username).await?;
+    let password = read_password(password_stdin)?;
+    validation::password_strength(&password)?;
+    db::reset_password(sqlite, user.id, auth::hash_password(&password)?).await?;
+    println!(
+        "Password of {} changed, all of their sessions ended",
+        user.username
+    );
+
+    Ok(())
+}
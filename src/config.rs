@@ -1,9 +1,407 @@
-use std::env;
+use std::net::IpAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::{env, fmt, fs};
 
-use once_cell::sync::Lazy;
+use clap::Args;
+use once_cell::sync::{Lazy, OnceCell};
+use url::Url;
 
-fn get_env_var<'a, 'b>(env_var: &'a str, default: &'b str) -> String {
-    env::var(env_var).unwrap_or(default.to_string())
+/// Read from the working directory when no other config file is given.
+const DEFAULT_CONFIG_FILE: &str = "lokai.toml";
+
+/// Setting known by its key in the config file, `--key` flag and env var.
+struct Definition {
+    key: &'static str,
+    env: &'static str,
+    default: &'static str,
+}
+
+const DEFINITIONS: &[Definition] = &[
+    Definition {
+        key: "database_url",
+        env: "DATABASE_URL",
+        default: "sqlite://db.sqlite3",
+    },
+    Definition {
+        key: "ollama_url",
+        env: "OLLAMA_URL",
+        default: "http://host.docker.internal:11434",
+    },
+    Definition {
+        key: "default_llm_model",
+        env: "LOKAI_DEFAULT_LLM_MODEL",
+        default: "phi3:3.8b",
+    },
+    Definition {
+        key: "embedding_model",
+        env: "LOKAI_EMBEDDING_MODEL",
+        default: "nomic-embed-text",
+    },
+    Definition {
+        key: "rag_top_k",
+        env: "LOKAI_RAG_TOP_K",
+        default: "4",
+    },
+    Definition {
+        key: "max_concurrent_generations",
+        env: "LOKAI_MAX_CONCURRENT_GENERATIONS",
+        default: "2",
+    },
+    Definition {
+        key: "max_conversation_name_length",
+        env: "LOKAI_MAX_CONVERSATION_NAME_LENGTH",
+        default: "100",
+    },
+    Definition {
+        key: "max_prompt_bytes",
+        env: "LOKAI_MAX_PROMPT_BYTES",
+        default: "32768",
+    },
+    Definition {
+        key: "requests_per_minute",
+        env: "LOKAI_REQUESTS_PER_MINUTE",
+        default: "120",
+    },
+    Definition {
+        key: "max_streams_per_user",
+        env: "LOKAI_MAX_STREAMS_PER_USER",
+        default: "2",
+    },
+    Definition {
+        key: "daily_token_quota",
+        env: "LOKAI_DAILY_TOKEN_QUOTA",
+        default: "0",
+    },
+    Definition {
+        key: "host",
+        env: "LOKAI_HOST",
+        default: "0.0.0.0",
+    },
+    Definition {
+        key: "port",
+        env: "LOKAI_PORT",
+        default: "3000",
+    },
+    Definition {
+        key: "auth_mode",
+        env: "LOKAI_AUTH_MODE",
+        default: "local",
+    },
+    Definition {
+        key: "proxy_user_header",
+        env: "LOKAI_PROXY_USER_HEADER",
+        default: "X-Forwarded-User",
+    },
+    Definition {
+        key: "proxy_email_header",
+        env: "LOKAI_PROXY_EMAIL_HEADER",
+        default: "X-Forwarded-Email",
+    },
+    Definition {
+        key: "trusted_proxies",
+        env: "LOKAI_TRUSTED_PROXIES",
+        default: "127.0.0.1,::1",
+    },
+];
+
+/// Command line flags, they override the config file and are overridden by env vars.
+#[derive(Args, Debug, Clone, Default)]
+pub struct Flags {
+    /// TOML config file, defaults to `LOKAI_CONFIG` or `lokai.toml` when it exists
+    #[arg(long, global = true, value_name = "PATH")]
+    pub config: Option<PathBuf>,
+    /// Sqlite database URL
+    #[arg(long, global = true, value_name = "URL")]
+    pub database_url: Option<String>,
+    /// URL of the Ollama server
+    #[arg(long, global = true, value_name = "URL")]
+    pub ollama_url: Option<String>,
+    /// LLM model of new conversations
+    #[arg(long, global = true, value_name = "MODEL")]
+    pub default_llm_model: Option<String>,
+    /// Model embedding uploaded documents
+    #[arg(long, global = true, value_name = "MODEL")]
+    pub embedding_model: Option<String>,
+    /// Document chunks added to a prompt
+    #[arg(long, global = true, value_name = "N")]
+    pub rag_top_k: Option<String>,
+    /// Generations running at once, further prompts are queued
+    #[arg(long, global = true, value_name = "N")]
+    pub max_concurrent_generations: Option<String>,
+    /// Maximum number of characters in a conversation name
+    #[arg(long, global = true, value_name = "N")]
+    pub max_conversation_name_length: Option<String>,
+    /// Maximum size of a prompt
+    #[arg(long, global = true, value_name = "BYTES")]
+    pub max_prompt_bytes: Option<String>,
+    /// API requests and prompts per user or API token per minute, 0 for no limit
+    #[arg(long, global = true, value_name = "N")]
+    pub requests_per_minute: Option<String>,
+    /// Answers generated at once for a user, 0 for no limit
+    #[arg(long, global = true, value_name = "N")]
+    pub max_streams_per_user: Option<String>,
+    /// Tokens per user per day, 0 for no limit
+    #[arg(long, global = true, value_name = "TOKENS")]
+    pub daily_token_quota: Option<String>,
+    /// Address LokAI listens on
+    #[arg(long, global = true)]
+    pub host: Option<String>,
+    /// Port LokAI listens on
+    #[arg(long, global = true)]
+    pub port: Option<String>,
+    /// `proxy` trusts identity headers of a reverse proxy
+    #[arg(long, global = true, value_name = "local|proxy")]
+    pub auth_mode: Option<String>,
+    /// Header with the username in `proxy` mode
+    #[arg(long, global = true, value_name = "HEADER")]
+    pub proxy_user_header: Option<String>,
+    /// Header with the email in `proxy` mode
+    #[arg(long, global = true, value_name = "HEADER")]
+    pub proxy_email_header: Option<String>,
+    /// Comma separated IPs allowed to send identity headers
+    #[arg(long, global = true, value_name = "IPS")]
+    pub trusted_proxies: Option<String>,
+}
+
+impl Flags {
+    fn get(&self, key: &str) -> Option<&String> {
+        match key {
+            "database_url" => self.database_url.as_ref(),
+            "ollama_url" => self.ollama_url.as_ref(),
+            "default_llm_model" => self.default_llm_model.as_ref(),
+            "embedding_model" => self.embedding_model.as_ref(),
+            "rag_top_k" => self.rag_top_k.as_ref(),
+            "max_concurrent_generations" => self.max_concurrent_generations.as_ref(),
+            "max_conversation_name_length" => self.max_conversation_name_length.as_ref(),
+            "max_prompt_bytes" => self.max_prompt_bytes.as_ref(),
+            "requests_per_minute" => self.requests_per_minute.as_ref(),
+            "max_streams_per_user" => self.max_streams_per_user.as_ref(),
+            "daily_token_quota" => self.daily_token_quota.as_ref(),
+            "host" => self.host.as_ref(),
+            "port" => self.port.as_ref(),
+            "auth_mode" => self.auth_mode.as_ref(),
+            "proxy_user_header" => self.proxy_user_header.as_ref(),
+            "proxy_email_header" => self.proxy_email_header.as_ref(),
+            "trusted_proxies" => self.trusted_proxies.as_ref(),
+            _ => None,
+        }
+    }
+}
+
+/// Where the effective value of a setting comes from.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Source {
+    Default,
+    File(PathBuf),
+    Flag(&'static str),
+    Env(&'static str),
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Source::Default => write!(f, "default"),
+            Source::File(path) => write!(f, "file {}", path.display()),
+            Source::Flag(key) => write!(f, "flag --{}", key.replace('_', "-")),
+            Source::Env(name) => write!(f, "env {name}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Setting {
+    pub key: &'static str,
+    pub value: String,
+    pub source: Source,
+}
+
+/// Raw values of all settings, layered as defaults, config file, flags and env vars.
+#[derive(Debug, Clone)]
+pub struct Settings(Vec<Setting>);
+
+impl Settings {
+    pub fn load(flags: &Flags) -> Result<Self, Vec<String>> {
+        let path = flags
+            .config
+            .clone()
+            .or_else(|| env::var_os("LOKAI_CONFIG").map(PathBuf::from));
+        let file = match path {
+            Some(path) => Some(read_config_file(path)?),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Some(read_config_file(PathBuf::from(DEFAULT_CONFIG_FILE))?)
+            }
+            None => None,
+        };
+        Self::resolve(file, flags, |name| env::var(name).ok())
+    }
+
+    fn resolve(
+        file: Option<(PathBuf, toml::Table)>,
+        flags: &Flags,
+        env: impl Fn(&str) -> Option<String>,
+    ) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+        if let Some((path, table)) = &file {
+            for key in table.keys() {
+                if !DEFINITIONS.iter().any(|definition| definition.key == key) {
+                    errors.push(format!("unknown setting `{key}` in {}", path.display()));
+                }
+            }
+        }
+
+        let mut settings = Vec::with_capacity(DEFINITIONS.len());
+        for definition in DEFINITIONS {
+            let mut setting = Setting {
+                key: definition.key,
+                value: definition.default.to_string(),
+                source: Source::Default,
+            };
+            if let Some((path, table)) = &file {
+                if let Some(value) = table.get(definition.key) {
+                    match toml_value(value) {
+                        Some(value) => {
+                            setting.value = value;
+                            setting.source = Source::File(path.clone());
+                        }
+                        None => errors.push(format!(
+                            "`{}` in {} must be a string, number, boolean or list",
+                            definition.key,
+                            path.display()
+                        )),
+                    }
+                }
+            }
+            if let Some(value) = flags.get(definition.key) {
+                setting.value = value.clone();
+                setting.source = Source::Flag(definition.key);
+            }
+            if let Some(value) = env(definition.env) {
+                setting.value = value;
+                setting.source = Source::Env(definition.env);
+            }
+            settings.push(setting);
+        }
+
+        if errors.is_empty() {
+            Ok(Self(settings))
+        } else {
+            Err(errors)
+        }
+    }
+
+    fn get(&self, key: &str) -> &Setting {
+        self.0
+            .iter()
+            .find(|setting| setting.key == key)
+            .expect("every setting has a definition")
+    }
+
+    fn value(&self, key: &str) -> String {
+        self.get(key).value.trim().to_string()
+    }
+
+    fn parse<T: FromStr + Default>(&self, key: &str, errors: &mut Vec<String>) -> T {
+        self.value(key).parse().unwrap_or_else(|_| {
+            errors.push(self.error(key, "is not a valid number"));
+            T::default()
+        })
+    }
+
+    /// Limits where 0 would break LokAI rather than turn the limit off.
+    fn parse_positive(&self, key: &str, errors: &mut Vec<String>) -> usize {
+        match self.value(key).parse() {
+            Ok(0) => errors.push(self.error(key, "must be greater than 0")),
+            Ok(value) => return value,
+            Err(_) => errors.push(self.error(key, "is not a valid number")),
+        }
+        0
+    }
+
+    fn error(&self, key: &str, reason: &str) -> String {
+        let setting = self.get(key);
+        format!("{key} = {:?} ({}) {reason}", setting.value, setting.source)
+    }
+
+    /// Effective configuration, one `key = value` line per setting followed by its source.
+    pub fn show(&self) -> String {
+        let width = DEFINITIONS
+            .iter()
+            .map(|definition| definition.key.len())
+            .max()
+            .unwrap_or_default();
+        self.0
+            .iter()
+            .map(|setting| {
+                format!(
+                    "{:width$} = {:?}  # {}\n",
+                    setting.key, setting.value, setting.source
+                )
+            })
+            .collect()
+    }
+}
+
+fn read_config_file(path: PathBuf) -> Result<(PathBuf, toml::Table), Vec<String>> {
+    let content = fs::read_to_string(&path)
+        .map_err(|err| vec![format!("cannot read {}: {err}", path.display())])?;
+    let table = content
+        .parse::<toml::Table>()
+        .map_err(|err| vec![format!("cannot parse {}: {err}", path.display())])?;
+    Ok((path, table))
+}
+
+/// Config file values are kept as strings, just like flags and env vars.
+fn toml_value(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(value) => Some(value.clone()),
+        toml::Value::Integer(value) => Some(value.to_string()),
+        toml::Value::Float(value) => Some(value.to_string()),
+        toml::Value::Boolean(value) => Some(value.to_string()),
+        toml::Value::Array(values) => values
+            .iter()
+            .map(toml_value)
+            .collect::<Option<Vec<_>>>()
+            .map(|values| values.join(",")),
+        toml::Value::Datetime(_) | toml::Value::Table(_) => None,
+    }
+}
+
+/// Identity headers set by an authenticating reverse proxy, e.g. oauth2-proxy or Authelia.
+#[derive(Clone, Debug)]
+pub struct ProxyAuth {
+    pub user_header: String,
+    pub email_header: String,
+    /// headers from any other peer are ignored
+    pub trusted_proxies: Vec<IpAddr>,
+}
+
+impl ProxyAuth {
+    fn from_settings(settings: &Settings, errors: &mut Vec<String>) -> Option<Self> {
+        match settings.value("auth_mode").as_str() {
+            "local" => return None,
+            "proxy" => {}
+            _ => {
+                errors.push(settings.error("auth_mode", "must be `local` or `proxy`"));
+                return None;
+            }
+        }
+        let mut trusted_proxies = Vec::new();
+        for ip in settings.value("trusted_proxies").split(',') {
+            match ip.trim().parse() {
+                Ok(ip) => trusted_proxies.push(ip),
+                Err(_) => errors.push(settings.error(
+                    "trusted_proxies",
+                    &format!("contains `{}` which isn't an IP address", ip.trim()),
+                )),
+            }
+        }
+        Some(Self {
+            user_header: settings.value("proxy_user_header"),
+            email_header: settings.value("proxy_email_header"),
+            trusted_proxies,
+        })
+    }
 }
 
 #[derive(Clone)]
@@ -11,18 +409,75 @@ pub struct Config {
     pub database_url: String,
     pub ollama_url: String,
     pub lokai_default_llm_model: String,
+    pub lokai_embedding_model: String,
+    pub lokai_rag_top_k: usize,
+    /// generations running at once against a single Ollama instance, others wait in a queue
+    pub lokai_max_concurrent_generations: usize,
+    pub lokai_max_conversation_name_length: usize,
+    pub lokai_max_prompt_bytes: usize,
+    /// per user limits, 0 turns a limit off
+    pub lokai_requests_per_minute: usize,
+    pub lokai_max_streams_per_user: usize,
+    pub lokai_daily_token_quota: i64,
     pub lokai_host: String,
-    pub lokai_port: String,
+    pub lokai_port: u16,
+    /// set when `auth_mode = "proxy"`
+    pub lokai_proxy_auth: Option<ProxyAuth>,
 }
 
 impl Config {
-    fn new() -> Self {
-        Self {
-            database_url: get_env_var("DATABASE_URL", "sqlite://db.sqlite3"),
-            ollama_url: get_env_var("OLLAMA_URL", "http://host.docker.internal:11434"),
-            lokai_default_llm_model: get_env_var("LOKAI_DEFAULT_LLM_MODEL", "phi3:3.8b"),
-            lokai_host: get_env_var("LOKAI_HOST", "0.0.0.0"),
-            lokai_port: get_env_var("LOKAI_PORT", "3000"),
+    pub fn load(flags: &Flags) -> Result<Self, Vec<String>> {
+        Self::from_settings(&Settings::load(flags)?)
+    }
+
+    /// Checks all settings at once, so a single run reports every mistake.
+    pub fn from_settings(settings: &Settings) -> Result<Self, Vec<String>> {
+        let mut errors = Vec::new();
+
+        let database_url = settings.value("database_url");
+        if !database_url.starts_with("sqlite:") {
+            errors.push(settings.error("database_url", "must start with `sqlite:`"));
+        }
+        let ollama_url = settings.value("ollama_url");
+        match Url::parse(&ollama_url) {
+            Ok(url) if matches!(url.scheme(), "http" | "https") && url.has_host() => {}
+            Ok(_) => errors.push(settings.error("ollama_url", "must be an http(s) URL")),
+            Err(err) => errors.push(settings.error("ollama_url", &format!("is invalid: {err}"))),
+        }
+        for key in ["default_llm_model", "embedding_model", "host"] {
+            if settings.value(key).is_empty() {
+                errors.push(settings.error(key, "cannot be empty"));
+            }
+        }
+        let lokai_port = settings.value("port").parse().unwrap_or_default();
+        if lokai_port == 0 {
+            errors.push(settings.error("port", "must be between 1 and 65535"));
+        }
+
+        let config = Self {
+            // paths are appended to the URL, e.g. `{ollama_url}/api/chat`
+            ollama_url: ollama_url.trim_end_matches('/').to_string(),
+            database_url,
+            lokai_default_llm_model: settings.value("default_llm_model"),
+            lokai_embedding_model: settings.value("embedding_model"),
+            lokai_rag_top_k: settings.parse_positive("rag_top_k", &mut errors),
+            lokai_max_concurrent_generations: settings
+                .parse_positive("max_concurrent_generations", &mut errors),
+            lokai_max_conversation_name_length: settings
+                .parse_positive("max_conversation_name_length", &mut errors),
+            lokai_max_prompt_bytes: settings.parse_positive("max_prompt_bytes", &mut errors),
+            lokai_requests_per_minute: settings.parse("requests_per_minute", &mut errors),
+            lokai_max_streams_per_user: settings.parse("max_streams_per_user", &mut errors),
+            lokai_daily_token_quota: settings.parse("daily_token_quota", &mut errors),
+            lokai_host: settings.value("host"),
+            lokai_port,
+            lokai_proxy_auth: ProxyAuth::from_settings(settings, &mut errors),
+        };
+
+        if errors.is_empty() {
+            Ok(config)
+        } else {
+            Err(errors)
         }
     }
 
@@ -31,4 +486,104 @@ impl Config {
     }
 }
 
-pub static CONFIG: Lazy<Config> = Lazy::new(|| Config::new());
+static LOADED: OnceCell<Config> = OnceCell::new();
+
+/// Configuration passed to `init` at startup, defaults and env vars when there wasn't any.
+pub static CONFIG: Lazy<&'static Config> = Lazy::new(|| {
+    LOADED.get_or_init(|| Config::load(&Flags::default()).expect("Invalid configuration"))
+});
+
+pub fn init(config: Config) {
+    if LOADED.set(config).is_err() {
+        panic!("Configuration is already initialized");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn file(content: &str) -> Option<(PathBuf, toml::Table)> {
+        Some((PathBuf::from("lokai.toml"), content.parse().unwrap()))
+    }
+
+    #[test]
+    fn test_settings_layers() {
+        // given:
+        let file =
+            file("port = 4000\nhost = \"127.0.0.1\"\ntrusted_proxies = [\"10.0.0.1\", \"::1\"]");
+        let flags = Flags {
+            port: Some("5000".to_string()),
+            ollama_url: Some("http://ollama:11434".to_string()),
+            ..Flags::default()
+        };
+        let env = |name: &str| (name == "LOKAI_PORT").then(|| "6000".to_string());
+
+        // when:
+        let settings = Settings::resolve(file, &flags, env).unwrap();
+
+        // then:
+        let source = |key| settings.get(key).source.to_string();
+        assert_eq!(settings.value("port"), "6000");
+        assert_eq!(source("port"), "env LOKAI_PORT");
+        assert_eq!(settings.value("ollama_url"), "http://ollama:11434");
+        assert_eq!(source("ollama_url"), "flag --ollama-url");
+        assert_eq!(settings.value("host"), "127.0.0.1");
+        assert_eq!(source("host"), "file lokai.toml");
+        assert_eq!(settings.value("trusted_proxies"), "10.0.0.1,::1");
+        assert_eq!(settings.value("rag_top_k"), "4");
+        assert_eq!(source("rag_top_k"), "default");
+    }
+
+    #[test]
+    fn test_settings_unknown_key() {
+        // given:
+        let file = file("ollama_uri = \"http://ollama:11434\"\nport = { number = 1 }");
+
+        // when:
+        let errors = Settings::resolve(file, &Flags::default(), |_| None).unwrap_err();
+
+        // then:
+        assert_eq!(
+            errors,
+            vec![
+                "unknown setting `ollama_uri` in lokai.toml",
+                "`port` in lokai.toml must be a string, number, boolean or list",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_config_from_settings() {
+        // given:
+        let valid = Settings::resolve(None, &Flags::default(), |_| None).unwrap();
+        let flags = Flags {
+            ollama_url: Some("ollama:11434".to_string()),
+            port: Some("70000".to_string()),
+            rag_top_k: Some("four".to_string()),
+            auth_mode: Some("proxy".to_string()),
+            trusted_proxies: Some("127.0.0.1, proxy".to_string()),
+            ..Flags::default()
+        };
+        let invalid = Settings::resolve(None, &flags, |_| None).unwrap();
+
+        // when:
+        let config = Config::from_settings(&valid);
+        let errors = Config::from_settings(&invalid).err().unwrap();
+
+        // then:
+        let config = config.ok().unwrap();
+        assert_eq!(config.lokai_url(), "0.0.0.0:3000");
+        assert_eq!(config.ollama_url, "http://host.docker.internal:11434");
+        assert!(config.lokai_proxy_auth.is_none());
+        assert_eq!(
+            errors,
+            vec![
+                "ollama_url = \"ollama:11434\" (flag --ollama-url) must be an http(s) URL",
+                "port = \"70000\" (flag --port) must be between 1 and 65535",
+                "rag_top_k = \"four\" (flag --rag-top-k) is not a valid number",
+                "trusted_proxies = \"127.0.0.1, proxy\" (flag --trusted-proxies) contains `proxy` which isn't an IP address",
+            ]
+        );
+    }
+}
@@ -1,9 +1,17 @@
-use sqlx::SqlitePool;
+use std::collections::HashMap;
+
+use chrono::{DateTime, Duration, Utc};
+use sqlx::types::Json;
+use sqlx::{FromRow, QueryBuilder, Sqlite, SqliteConnection, SqlitePool};
 use tracing::debug;
 use uuid::Uuid;
 
 use crate::error::Result;
-use crate::models::{Conversation, ConversationSettings, Message};
+use crate::models::{
+    ApiToken, Attachment, Citation, Conversation, ConversationExport, ConversationSettings,
+    ConversationUsage, Cursor, DailyUsage, Document, DocumentChunk, Feedback, FeedbackExample,
+    Folder, Message, MessagePage, MessageSearchResult, ModelUsage, Session, ShareLink, Tag, User,
+};
 
 pub async fn get_conversation_messages(
     sqlite: SqlitePool,
@@ -14,13 +22,214 @@ pub async fn get_conversation_messages(
 SELECT *
 FROM messages
 WHERE conversation_id = ?
-ORDER BY created_at ASC
+ORDER BY created_at ASC, id ASC
+        "#,
+    )
+    .bind(conversation_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    with_message_details(sqlite, conversation_id, messages).await
+}
+
+/// Most recent `limit` messages preceding `before`, a comparison is never split between pages.
+pub async fn get_conversation_messages_page(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+    before: Option<Cursor>,
+    limit: i64,
+) -> Result<MessagePage> {
+    // one extra row tells whether there are older messages
+    let mut messages: Vec<Message> = sqlx::query_as(
+        r#"
+SELECT *
+FROM messages
+WHERE conversation_id = ?1 AND (?2 IS NULL OR (created_at, id) < (?2, ?3))
+ORDER BY created_at DESC, id DESC
+LIMIT ?4
+        "#,
+    )
+    .bind(conversation_id)
+    .bind(before.map(|cursor| cursor.at))
+    .bind(before.map(|cursor| cursor.id))
+    .bind(limit + 1)
+    .fetch_all(&sqlite)
+    .await?;
+    let has_older = messages.len() as i64 > limit;
+    messages.truncate(limit as usize);
+    messages.reverse();
+
+    if let Some(first) = messages.first().filter(|_| has_older) {
+        if let Some(comparison_id) = first.comparison_id {
+            let mut answers: Vec<Message> = sqlx::query_as(
+                r#"
+SELECT *
+FROM messages
+WHERE conversation_id = ?1 AND comparison_id = ?2 AND (created_at, id) < (?3, ?4)
+ORDER BY created_at ASC, id ASC
+                "#,
+            )
+            .bind(conversation_id)
+            .bind(comparison_id)
+            .bind(first.created_at)
+            .bind(first.id)
+            .fetch_all(&sqlite)
+            .await?;
+            answers.append(&mut messages);
+            messages = answers;
+        }
+    }
+
+    let older = messages.first().filter(|_| has_older).map(Message::cursor);
+    let messages = with_message_details(sqlite, conversation_id, messages).await?;
+    Ok(MessagePage {
+        messages,
+        older,
+        newer: None,
+    })
+}
+
+/// Oldest `limit` messages following `after`, from the start of the conversation when not given.
+pub async fn get_conversation_messages_page_after(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+    after: Option<Cursor>,
+    limit: i64,
+) -> Result<MessagePage> {
+    // one extra row tells whether there are newer messages
+    let mut messages: Vec<Message> = sqlx::query_as(
+        r#"
+SELECT *
+FROM messages
+WHERE conversation_id = ?1 AND (?2 IS NULL OR (created_at, id) > (?2, ?3))
+ORDER BY created_at ASC, id ASC
+LIMIT ?4
+        "#,
+    )
+    .bind(conversation_id)
+    .bind(after.map(|cursor| cursor.at))
+    .bind(after.map(|cursor| cursor.id))
+    .bind(limit + 1)
+    .fetch_all(&sqlite)
+    .await?;
+    let has_newer = messages.len() as i64 > limit;
+    messages.truncate(limit as usize);
+
+    if let Some(last) = messages.last().filter(|_| has_newer) {
+        if let Some(comparison_id) = last.comparison_id {
+            let mut answers: Vec<Message> = sqlx::query_as(
+                r#"
+SELECT *
+FROM messages
+WHERE conversation_id = ?1 AND comparison_id = ?2 AND (created_at, id) > (?3, ?4)
+ORDER BY created_at ASC, id ASC
+                "#,
+            )
+            .bind(conversation_id)
+            .bind(comparison_id)
+            .bind(last.created_at)
+            .bind(last.id)
+            .fetch_all(&sqlite)
+            .await?;
+            messages.append(&mut answers);
+        }
+    }
+
+    let newer = messages.last().filter(|_| has_newer).map(Message::cursor);
+    let messages = with_message_details(sqlite, conversation_id, messages).await?;
+    Ok(MessagePage {
+        messages,
+        older: None,
+        newer,
+    })
+}
+
+/// Fills attachments, citations and feedback of chronologically ordered messages.
+async fn with_message_details(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+    messages: Vec<Message>,
+) -> Result<Vec<Message>> {
+    let (Some(first), Some(last)) = (
+        messages.first().map(Message::cursor),
+        messages.last().map(Message::cursor),
+    ) else {
+        return Ok(messages);
+    };
+
+    let attachment_ids: Vec<(Uuid, Uuid)> = sqlx::query_as(
+        r#"
+SELECT a.id, a.message_id
+FROM attachments a
+JOIN messages m ON m.id = a.message_id
+WHERE m.conversation_id = ?1 AND (m.created_at, m.id) BETWEEN (?2, ?3) AND (?4, ?5)
+ORDER BY a.created_at ASC
+        "#,
+    )
+    .bind(conversation_id)
+    .bind(first.at)
+    .bind(first.id)
+    .bind(last.at)
+    .bind(last.id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    let citations: Vec<Citation> = sqlx::query_as(
+        r#"
+SELECT mc.message_id, mc.chunk_id, mc.rank, d.id AS document_id, d.name AS document_name, dc.chunk_index
+FROM message_citations mc
+JOIN messages m ON m.id = mc.message_id
+JOIN document_chunks dc ON dc.id = mc.chunk_id
+JOIN documents d ON d.id = dc.document_id
+WHERE m.conversation_id = ?1 AND (m.created_at, m.id) BETWEEN (?2, ?3) AND (?4, ?5)
+ORDER BY mc.rank ASC
+        "#,
+    )
+    .bind(conversation_id)
+    .bind(first.at)
+    .bind(first.id)
+    .bind(last.at)
+    .bind(last.id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    let feedback: Vec<Feedback> = sqlx::query_as(
+        r#"
+SELECT mf.*
+FROM message_feedback mf
+JOIN messages m ON m.id = mf.message_id
+WHERE m.conversation_id = ?1 AND (m.created_at, m.id) BETWEEN (?2, ?3) AND (?4, ?5)
         "#,
     )
     .bind(conversation_id)
+    .bind(first.at)
+    .bind(first.id)
+    .bind(last.at)
+    .bind(last.id)
     .fetch_all(&sqlite)
     .await?;
 
+    let messages = messages
+        .into_iter()
+        .map(|mut message| {
+            message.attachment_ids = attachment_ids
+                .iter()
+                .filter(|(_, message_id)| *message_id == message.id)
+                .map(|(id, _)| *id)
+                .collect();
+            message.citations = citations
+                .iter()
+                .filter(|citation| citation.message_id == message.id)
+                .cloned()
+                .collect();
+            message.feedback = feedback
+                .iter()
+                .find(|feedback| feedback.message_id == message.id)
+                .cloned();
+            message
+        })
+        .collect();
+
     Ok(messages)
 }
 
@@ -28,10 +237,29 @@ ORDER BY created_at ASC
 pub async fn create_message(sqlite: SqlitePool, message: Message) -> Result<Message> {
     debug!(message_id = message.id.to_string(), "saving message to db");
 
-    let new_message: Message = sqlx::query_as(
+    let mut transaction = sqlite.begin().await?;
+    let new_message = insert_message(&mut transaction, message).await?;
+    transaction.commit().await?;
+
+    debug!(
+        message_id = new_message.id.to_string(),
+        "message saved to db"
+    );
+
+    Ok(new_message)
+}
+
+/// Inserts the message and links its attachments and citations.
+async fn insert_message(connection: &mut SqliteConnection, message: Message) -> Result<Message> {
+    let mut new_message: Message = sqlx::query_as(
         r#"
-INSERT INTO messages ( id, role, content, conversation_id, created_at )
-VALUES ( ?1, ?2, ?3, ?4, ?5 )
+INSERT INTO messages (
+    id, role, content, conversation_id, created_at,
+    llm_model, total_duration, load_duration, prompt_eval_count,
+    prompt_eval_duration, eval_count, eval_duration, tool_calls, tool_name,
+    validation_errors, generation_error, comparison_id, winner
+)
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11, ?12, ?13, ?14, ?15, ?16, ?17, ?18 )
 RETURNING *
         "#,
     )
@@ -40,345 +268,3116 @@ RETURNING *
     .bind(message.content)
     .bind(message.conversation_id)
     .bind(message.created_at)
-    .fetch_one(&sqlite)
+    .bind(message.stats.llm_model)
+    .bind(message.stats.total_duration)
+    .bind(message.stats.load_duration)
+    .bind(message.stats.prompt_eval_count)
+    .bind(message.stats.prompt_eval_duration)
+    .bind(message.stats.eval_count)
+    .bind(message.stats.eval_duration)
+    .bind(message.tool_calls)
+    .bind(message.tool_name)
+    .bind(message.validation_errors)
+    .bind(message.generation_error)
+    .bind(message.comparison_id)
+    .bind(message.winner)
+    .fetch_one(&mut *connection)
     .await?;
 
-    debug!(
-        message_id = new_message.id.to_string(),
-        "message saved to db"
-    );
+    // only attachments which aren't linked to any message yet can be claimed
+    for attachment_id in message.attachment_ids {
+        let linked = sqlx::query(
+            r#"
+UPDATE attachments
+SET message_id = ?1
+WHERE id = ?2 AND message_id IS NULL
+            "#,
+        )
+        .bind(new_message.id)
+        .bind(attachment_id)
+        .execute(&mut *connection)
+        .await?;
+        if linked.rows_affected() == 1 {
+            new_message.attachment_ids.push(attachment_id);
+        }
+    }
+
+    for citation in message.citations {
+        sqlx::query(
+            r#"
+INSERT INTO message_citations ( message_id, chunk_id, rank )
+VALUES ( ?1, ?2, ?3 )
+            "#,
+        )
+        .bind(new_message.id)
+        .bind(citation.chunk_id)
+        .bind(citation.rank)
+        .execute(&mut *connection)
+        .await?;
+        new_message.citations.push(Citation {
+            message_id: new_message.id,
+            ..citation
+        });
+    }
 
     Ok(new_message)
 }
 
-pub async fn get_conversation(
+/// Stores partial content of a message which is still being generated.
+pub async fn update_message_content(
     sqlite: SqlitePool,
-    conversation_id: Uuid,
-) -> Result<Option<Conversation>> {
+    message_id: Uuid,
+    content: &str,
+) -> Result<()> {
+    sqlx::query(
+        r#"
+UPDATE messages
+SET content = ?1
+WHERE id = ?2
+        "#,
+    )
+    .bind(content)
+    .bind(message_id)
+    .execute(&sqlite)
+    .await?;
+
+    Ok(())
+}
+
+/// Stores the complete or failed answer, its stats and citations, of a message created before generation.
+pub async fn finish_message(sqlite: SqlitePool, message: &Message) -> Result<()> {
+    debug!(message_id = message.id.to_string(), "finishing message");
+
+    let mut transaction = sqlite.begin().await?;
+
+    sqlx::query(
+        r#"
+UPDATE messages
+SET content = ?1, llm_model = ?2, total_duration = ?3, load_duration = ?4,
+    prompt_eval_count = ?5, prompt_eval_duration = ?6, eval_count = ?7, eval_duration = ?8,
+    tool_calls = ?9, validation_errors = ?10, generation_error = ?11
+WHERE id = ?12
+        "#,
+    )
+    .bind(&message.content)
+    .bind(&message.stats.llm_model)
+    .bind(message.stats.total_duration)
+    .bind(message.stats.load_duration)
+    .bind(message.stats.prompt_eval_count)
+    .bind(message.stats.prompt_eval_duration)
+    .bind(message.stats.eval_count)
+    .bind(message.stats.eval_duration)
+    .bind(&message.tool_calls)
+    .bind(&message.validation_errors)
+    .bind(&message.generation_error)
+    .bind(message.id)
+    .execute(&mut *transaction)
+    .await?;
+
+    // a model calling tools answers with a later message, citations go there
+    sqlx::query(
+        r#"
+DELETE FROM message_citations
+WHERE message_id = ?1
+        "#,
+    )
+    .bind(message.id)
+    .execute(&mut *transaction)
+    .await?;
+    for citation in &message.citations {
+        sqlx::query(
+            r#"
+INSERT INTO message_citations ( message_id, chunk_id, rank )
+VALUES ( ?1, ?2, ?3 )
+            "#,
+        )
+        .bind(message.id)
+        .bind(citation.chunk_id)
+        .bind(citation.rank)
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+pub async fn search_messages(
+    sqlite: SqlitePool,
+    user_id: Uuid,
+    query: &str,
+    limit: i64,
+) -> Result<Vec<MessageSearchResult>> {
+    let results: Vec<MessageSearchResult> = sqlx::query_as(
+        r#"
+SELECT
+    c.id AS conversation_id,
+    c.name AS conversation_name,
+    m.role,
+    m.content,
+    m.created_at
+FROM messages m
+JOIN conversations c ON c.id = m.conversation_id
+WHERE c.user_id = ?1
+    AND m.role IN ('user', 'assistant')
+    AND m.content LIKE '%' || ?2 || '%' ESCAPE '\'
+ORDER BY m.created_at DESC
+LIMIT ?3
+        "#,
+    )
+    .bind(user_id)
+    .bind(
+        query
+            .replace('\\', "\\\\")
+            .replace('%', "\\%")
+            .replace('_', "\\_"),
+    )
+    .bind(limit)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(results)
+}
+
+pub async fn create_attachment(sqlite: SqlitePool, attachment: Attachment) -> Result<Attachment> {
     debug!(
-        conversation_id = conversation_id.to_string(),
-        "getting conversation from db"
+        attachment_id = attachment.id.to_string(),
+        "saving attachment to db"
     );
-    let maybe_conversation: Option<Conversation> = sqlx::query_as(
+
+    let new_attachment: Attachment = sqlx::query_as(
+        r#"
+INSERT INTO attachments ( id, message_id, mime_type, data, created_at, user_id )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6 )
+RETURNING *
+        "#,
+    )
+    .bind(attachment.id)
+    .bind(attachment.message_id)
+    .bind(attachment.mime_type)
+    .bind(attachment.data)
+    .bind(attachment.created_at)
+    .bind(attachment.user_id)
+    .fetch_one(&sqlite)
+    .await?;
+
+    Ok(new_attachment)
+}
+
+pub async fn get_attachment(
+    sqlite: SqlitePool,
+    attachment_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<Attachment>> {
+    let maybe_attachment: Option<Attachment> = sqlx::query_as(
         r#"
 SELECT *
-FROM conversations
-WHERE id = ?
+FROM attachments
+WHERE id = ? AND user_id = ?
         "#,
     )
-    .bind(conversation_id)
+    .bind(attachment_id)
+    .bind(user_id)
     .fetch_optional(&sqlite)
     .await?;
 
-    Ok(maybe_conversation)
+    Ok(maybe_attachment)
 }
 
-pub async fn get_conversations(sqlite: SqlitePool) -> Result<Vec<Conversation>> {
-    let conversations: Vec<Conversation> = sqlx::query_as(
+pub async fn get_message_attachments(
+    sqlite: SqlitePool,
+    message_id: Uuid,
+) -> Result<Vec<Attachment>> {
+    let attachments: Vec<Attachment> = sqlx::query_as(
         r#"
 SELECT *
-FROM conversations
+FROM attachments
+WHERE message_id = ?
 ORDER BY created_at ASC
         "#,
     )
+    .bind(message_id)
     .fetch_all(&sqlite)
     .await?;
 
-    Ok(conversations)
+    Ok(attachments)
 }
 
-pub async fn create_conversation(
+pub async fn create_document(
     sqlite: SqlitePool,
-    conversation: Conversation,
-    llm_model: String,
-) -> Result<Conversation> {
+    document: Document,
+    chunks: Vec<DocumentChunk>,
+) -> Result<Document> {
     debug!(
-        conversation_id = conversation.id.to_string(),
-        "saving conversation to db"
+        document_id = document.id.to_string(),
+        chunks = chunks.len(),
+        "saving document to db"
+    );
+
+    let mut transaction = sqlite.begin().await?;
+
+    let new_document: Document = sqlx::query_as(
+        r#"
+INSERT INTO documents ( id, conversation_id, name, mime_type, created_at )
+VALUES ( ?1, ?2, ?3, ?4, ?5 )
+RETURNING *
+        "#,
+    )
+    .bind(document.id)
+    .bind(document.conversation_id)
+    .bind(document.name)
+    .bind(document.mime_type)
+    .bind(document.created_at)
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    for chunk in chunks {
+        sqlx::query(
+            r#"
+INSERT INTO document_chunks ( id, document_id, conversation_id, chunk_index, content, embedding, created_at )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 )
+            "#,
+        )
+        .bind(chunk.id)
+        .bind(chunk.document_id)
+        .bind(chunk.conversation_id)
+        .bind(chunk.chunk_index)
+        .bind(chunk.content)
+        .bind(chunk.embedding)
+        .bind(chunk.created_at)
+        .execute(&mut *transaction)
+        .await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(new_document)
+}
+
+pub async fn get_document(
+    sqlite: SqlitePool,
+    document_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<Document>> {
+    let maybe_document: Option<Document> = sqlx::query_as(
+        r#"
+SELECT d.*
+FROM documents d
+JOIN conversations c ON c.id = d.conversation_id
+WHERE d.id = ? AND c.user_id = ?
+        "#,
+    )
+    .bind(document_id)
+    .bind(user_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_document)
+}
+
+pub async fn get_conversation_documents(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+) -> Result<Vec<Document>> {
+    let documents: Vec<Document> = sqlx::query_as(
+        r#"
+SELECT *
+FROM documents
+WHERE conversation_id = ?
+ORDER BY created_at ASC
+        "#,
+    )
+    .bind(conversation_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(documents)
+}
+
+pub async fn get_document_chunks(
+    sqlite: SqlitePool,
+    document_id: Uuid,
+) -> Result<Vec<DocumentChunk>> {
+    let chunks: Vec<DocumentChunk> = sqlx::query_as(
+        r#"
+SELECT *
+FROM document_chunks
+WHERE document_id = ?
+ORDER BY chunk_index ASC
+        "#,
+    )
+    .bind(document_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(chunks)
+}
+
+pub async fn get_conversation_chunks(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+) -> Result<Vec<DocumentChunk>> {
+    let chunks: Vec<DocumentChunk> = sqlx::query_as(
+        r#"
+SELECT *
+FROM document_chunks
+WHERE conversation_id = ?
+        "#,
+    )
+    .bind(conversation_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(chunks)
+}
+
+pub async fn delete_document(sqlite: SqlitePool, document_id: Uuid) -> Result<Option<Document>> {
+    debug!(
+        document_id = document_id.to_string(),
+        "deleting document from db"
     );
 
-    let mut transaction = sqlite.begin().await?;
+    let mut transaction = sqlite.begin().await?;
+
+    sqlx::query(
+        r#"
+DELETE FROM message_citations
+WHERE chunk_id IN ( SELECT id FROM document_chunks WHERE document_id = ?1 )
+        "#,
+    )
+    .bind(document_id)
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        r#"
+DELETE FROM document_chunks
+WHERE document_id = ?1
+        "#,
+    )
+    .bind(document_id)
+    .execute(&mut *transaction)
+    .await?;
+
+    let maybe_document: Option<Document> = sqlx::query_as(
+        r#"
+DELETE FROM documents
+WHERE id = ?1
+RETURNING *
+        "#,
+    )
+    .bind(document_id)
+    .fetch_optional(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(maybe_document)
+}
+
+pub async fn get_conversation_settings(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+) -> Result<Option<ConversationSettings>> {
+    let maybe_settings: Option<ConversationSettings> = sqlx::query_as(
+        r#"
+SELECT *
+FROM conversation_settings
+WHERE conversation_id = ?
+        "#,
+    )
+    .bind(conversation_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_settings)
+}
+
+pub async fn update_conversation_output_format(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+    output_format: Option<String>,
+) -> Result<Option<ConversationSettings>> {
+    let maybe_settings: Option<ConversationSettings> = sqlx::query_as(
+        r#"
+UPDATE conversation_settings
+SET output_format = ?1
+WHERE conversation_id = ?2
+RETURNING *
+        "#,
+    )
+    .bind(output_format)
+    .bind(conversation_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_settings)
+}
+
+pub async fn update_conversation_comparison_models(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+    comparison_models: Option<Vec<String>>,
+) -> Result<Option<ConversationSettings>> {
+    let maybe_settings: Option<ConversationSettings> = sqlx::query_as(
+        r#"
+UPDATE conversation_settings
+SET comparison_models = ?1
+WHERE conversation_id = ?2
+RETURNING *
+        "#,
+    )
+    .bind(comparison_models.map(Json))
+    .bind(conversation_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_settings)
+}
+
+/// Marks the message as the best answer of its comparison, returns all answers of the comparison.
+pub async fn pick_comparison_winner(sqlite: SqlitePool, message_id: Uuid) -> Result<Vec<Message>> {
+    let messages: Vec<Message> = sqlx::query_as(
+        r#"
+UPDATE messages
+SET winner = ( id = ?1 )
+WHERE comparison_id = ( SELECT comparison_id FROM messages WHERE id = ?1 )
+RETURNING *
+        "#,
+    )
+    .bind(message_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    let mut messages = messages;
+    messages.sort_by_key(|message| message.created_at);
+    Ok(messages)
+}
+
+pub async fn upsert_feedback(sqlite: SqlitePool, feedback: Feedback) -> Result<Feedback> {
+    debug!(
+        message_id = feedback.message_id.to_string(),
+        "saving feedback to db"
+    );
+
+    let feedback: Feedback = sqlx::query_as(
+        r#"
+INSERT INTO message_feedback ( message_id, rating, note, created_at, updated_at )
+VALUES ( ?1, ?2, ?3, ?4, ?5 )
+ON CONFLICT ( message_id ) DO UPDATE
+SET rating = excluded.rating, note = excluded.note, updated_at = excluded.updated_at
+RETURNING *
+        "#,
+    )
+    .bind(feedback.message_id)
+    .bind(feedback.rating)
+    .bind(feedback.note)
+    .bind(feedback.created_at)
+    .bind(feedback.updated_at)
+    .fetch_one(&sqlite)
+    .await?;
+
+    Ok(feedback)
+}
+
+pub async fn get_message(
+    sqlite: SqlitePool,
+    message_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<Message>> {
+    let maybe_message: Option<Message> = sqlx::query_as(
+        r#"
+SELECT m.*
+FROM messages m
+JOIN conversations c ON c.id = m.conversation_id
+WHERE m.id = ? AND c.user_id = ?
+        "#,
+    )
+    .bind(message_id)
+    .bind(user_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_message)
+}
+
+/// Rated assistant messages of existing conversations together with the messages preceding them.
+pub async fn get_feedback_examples(
+    sqlite: SqlitePool,
+    user_id: Uuid,
+) -> Result<Vec<FeedbackExample>> {
+    let feedback: Vec<Feedback> = sqlx::query_as(
+        r#"
+SELECT mf.*
+FROM message_feedback mf
+JOIN messages m ON m.id = mf.message_id
+JOIN conversations c ON c.id = m.conversation_id
+WHERE c.user_id = ?
+ORDER BY mf.created_at ASC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    // every message of the conversations with rated answers, instead of a query per answer
+    let messages: Vec<Message> = sqlx::query_as(
+        r#"
+SELECT *
+FROM messages
+WHERE conversation_id IN (
+    SELECT m.conversation_id
+    FROM message_feedback mf
+    JOIN messages m ON m.id = mf.message_id
+    JOIN conversations c ON c.id = m.conversation_id
+    WHERE c.user_id = ?
+)
+ORDER BY created_at ASC, id ASC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&sqlite)
+    .await?;
+    let mut conversations: HashMap<Uuid, Vec<Message>> = HashMap::new();
+    for message in messages {
+        conversations
+            .entry(message.conversation_id)
+            .or_default()
+            .push(message);
+    }
+    let answers: HashMap<Uuid, &Message> = conversations
+        .values()
+        .flatten()
+        .map(|message| (message.id, message))
+        .collect();
+
+    let mut examples = Vec::with_capacity(feedback.len());
+    for feedback in feedback {
+        let Some(answer) = answers.get(&feedback.message_id) else {
+            continue;
+        };
+        let history = conversations[&answer.conversation_id]
+            .iter()
+            .take_while(|message| message.created_at < answer.created_at)
+            .cloned()
+            .collect();
+        examples.push(FeedbackExample::new((*answer).clone(), history, feedback));
+    }
+
+    Ok(examples)
+}
+
+pub async fn get_conversation(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<Conversation>> {
+    debug!(
+        conversation_id = conversation_id.to_string(),
+        "getting conversation from db"
+    );
+    let maybe_conversation: Option<Conversation> = sqlx::query_as(
+        r#"
+SELECT *
+FROM conversations
+WHERE id = ? AND user_id = ?
+        "#,
+    )
+    .bind(conversation_id)
+    .bind(user_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_conversation)
+}
+
+/// Indexed lookup used to validate IDs coming from the client.
+pub async fn conversation_exists(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+    user_id: Uuid,
+) -> Result<bool> {
+    let exists: bool = sqlx::query_scalar(
+        r#"
+SELECT EXISTS ( SELECT 1 FROM conversations WHERE id = ? AND user_id = ? )
+        "#,
+    )
+    .bind(conversation_id)
+    .bind(user_id)
+    .fetch_one(&sqlite)
+    .await?;
+
+    Ok(exists)
+}
+
+/// Page of the user's conversations in `folder_id` (outside of folders when `None`)
+/// together with their tags, most recently active first and starting right after `after`.
+/// Only the ones tagged with `tag_id` if given.
+pub async fn get_conversations(
+    sqlite: SqlitePool,
+    user_id: Uuid,
+    folder_id: Option<Uuid>,
+    tag_id: Option<Uuid>,
+    after: Option<Cursor>,
+    limit: i64,
+) -> Result<Vec<Conversation>> {
+    let conversations: Vec<Conversation> = sqlx::query_as(
+        r#"
+SELECT *
+FROM conversations
+WHERE user_id = ?1
+    AND folder_id IS ?2
+    AND (?3 IS NULL OR id IN ( SELECT conversation_id FROM conversation_tags WHERE tag_id = ?3 ))
+    AND (?4 IS NULL OR (updated_at, id) < (?4, ?5))
+ORDER BY updated_at DESC, id DESC
+LIMIT ?6
+        "#,
+    )
+    .bind(user_id)
+    .bind(folder_id)
+    .bind(tag_id)
+    .bind(after.map(|cursor| cursor.at))
+    .bind(after.map(|cursor| cursor.id))
+    .bind(limit)
+    .fetch_all(&sqlite)
+    .await?;
+
+    with_conversation_tags(sqlite, conversations).await
+}
+
+#[derive(FromRow)]
+struct ConversationTag {
+    conversation_id: Uuid,
+    #[sqlx(flatten)]
+    tag: Tag,
+}
+
+// stays below the limit of bound parameters of SQLite
+const TAGS_QUERY_CONVERSATIONS: usize = 500;
+
+/// Fills the tags of the conversations, one query per few hundred conversations.
+async fn with_conversation_tags(
+    sqlite: SqlitePool,
+    mut conversations: Vec<Conversation>,
+) -> Result<Vec<Conversation>> {
+    let mut tags: HashMap<Uuid, Vec<Tag>> = HashMap::new();
+    for chunk in conversations.chunks(TAGS_QUERY_CONVERSATIONS) {
+        let mut query: QueryBuilder<Sqlite> = QueryBuilder::new(
+            r#"
+SELECT ct.conversation_id, t.*
+FROM tags t
+JOIN conversation_tags ct ON ct.tag_id = t.id
+WHERE ct.conversation_id IN ("#,
+        );
+        let mut ids = query.separated(", ");
+        for conversation in chunk {
+            ids.push_bind(conversation.id);
+        }
+        query.push(") ORDER BY t.name ASC");
+        let conversation_tags: Vec<ConversationTag> =
+            query.build_query_as().fetch_all(&sqlite).await?;
+
+        for conversation_tag in conversation_tags {
+            tags.entry(conversation_tag.conversation_id)
+                .or_default()
+                .push(conversation_tag.tag);
+        }
+    }
+    for conversation in conversations.iter_mut() {
+        conversation.tags = tags.remove(&conversation.id).unwrap_or_default();
+    }
+
+    Ok(conversations)
+}
+
+/// Every conversation of the user regardless of its folder, oldest first.
+/// The ones from before user accounts when `user_id` is `None`.
+pub async fn get_user_conversations(
+    sqlite: SqlitePool,
+    user_id: Option<Uuid>,
+) -> Result<Vec<Conversation>> {
+    let conversations: Vec<Conversation> = sqlx::query_as(
+        r#"
+SELECT *
+FROM conversations
+WHERE user_id IS ?
+ORDER BY created_at ASC, id ASC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    with_conversation_tags(sqlite, conversations).await
+}
+
+/// Recreates exported conversations together with their tags, all of them or nothing.
+pub async fn import_conversations(
+    sqlite: SqlitePool,
+    exports: Vec<ConversationExport>,
+    llm_model: String,
+) -> Result<Vec<Conversation>> {
+    let mut transaction = sqlite.begin().await?;
+
+    let mut imported = Vec::new();
+    for export in exports {
+        imported.push(import_conversation(&mut transaction, export, llm_model.clone()).await?);
+    }
+
+    transaction.commit().await?;
+
+    Ok(imported)
+}
+
+async fn import_conversation(
+    connection: &mut SqliteConnection,
+    export: ConversationExport,
+    llm_model: String,
+) -> Result<Conversation> {
+    let conversation = export.conversation;
+    let settings = export
+        .settings
+        .unwrap_or_else(|| ConversationSettings::new(llm_model, conversation.id));
+
+    let mut new_conversation: Conversation = sqlx::query_as(
+        r#"
+INSERT INTO conversations ( id, name, created_at, user_id, folder_id, updated_at )
+VALUES ( ?1, ?2, ?3, ?4, NULL, ?5 )
+RETURNING *
+        "#,
+    )
+    .bind(conversation.id)
+    .bind(conversation.name)
+    .bind(conversation.created_at)
+    .bind(conversation.user_id)
+    .bind(conversation.updated_at)
+    .fetch_one(&mut *connection)
+    .await?;
+
+    sqlx::query(
+        r#"
+INSERT INTO conversation_settings (
+    id, llm_model, conversation_id, created_at, output_format, comparison_models, system_prompt
+)
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 )
+        "#,
+    )
+    .bind(settings.id)
+    .bind(settings.llm_model)
+    .bind(new_conversation.id)
+    .bind(settings.created_at)
+    .bind(settings.output_format)
+    .bind(settings.comparison_models)
+    .bind(settings.system_prompt)
+    .execute(&mut *connection)
+    .await?;
+
+    for message in export.messages {
+        insert_message(connection, message).await?;
+    }
+    for tag in conversation.tags {
+        let tag = insert_tag(connection, new_conversation.id, tag).await?;
+        new_conversation.tags.push(tag);
+    }
+
+    Ok(new_conversation)
+}
+
+pub async fn create_conversation(
+    sqlite: SqlitePool,
+    conversation: Conversation,
+    llm_model: String,
+) -> Result<Conversation> {
+    debug!(
+        conversation_id = conversation.id.to_string(),
+        "saving conversation to db"
+    );
+
+    let mut transaction = sqlite.begin().await?;
+
+    // conversations created inside a folder start with the folder defaults
+    let folder: Option<Folder> = sqlx::query_as(
+        r#"
+SELECT *
+FROM folders
+WHERE id = ? AND user_id = ?
+        "#,
+    )
+    .bind(conversation.folder_id)
+    .bind(conversation.user_id)
+    .fetch_optional(&mut *transaction)
+    .await?;
+    let mut settings = ConversationSettings::new(llm_model, conversation.id);
+    if let Some(folder) = &folder {
+        if let Some(llm_model) = &folder.llm_model {
+            settings.llm_model = llm_model.clone();
+        }
+        settings.system_prompt = folder.system_prompt.clone();
+    }
+
+    let new_conversation: Conversation = sqlx::query_as(
+        r#"
+INSERT INTO conversations ( id, name, created_at, user_id, folder_id, updated_at )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6 )
+RETURNING *
+        "#,
+    )
+    .bind(conversation.id)
+    .bind(conversation.name)
+    .bind(conversation.created_at)
+    .bind(conversation.user_id)
+    .bind(folder.map(|folder| folder.id))
+    .bind(conversation.updated_at)
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    let _: ConversationSettings = sqlx::query_as(
+        r#"
+INSERT INTO conversation_settings ( id, llm_model, conversation_id, created_at, system_prompt )
+VALUES ( ?1, ?2, ?3, ?4, ?5 )
+RETURNING *
+        "#,
+    )
+    .bind(settings.id)
+    .bind(settings.llm_model)
+    .bind(settings.conversation_id)
+    .bind(settings.created_at)
+    .bind(settings.system_prompt)
+    .fetch_one(&mut *transaction)
+    .await?;
+
+    debug!(
+        conversation_id = new_conversation.id.to_string(),
+        "conversation saved to db"
+    );
+
+    transaction.commit().await?;
+
+    Ok(new_conversation)
+}
+
+pub async fn delete_conversation(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<Conversation>> {
+    debug!(
+        conversation_id = conversation_id.to_string(),
+        "deleting conversation from db"
+    );
+
+    let mut transaction = sqlite.begin().await?;
+
+    // share links and tags cascade, the other tables predate foreign keys
+    let maybe_conversation: Option<Conversation> = sqlx::query_as(
+        r#"
+        DELETE FROM conversations
+        WHERE id = ?1 AND user_id = ?2
+        RETURNING *
+        "#,
+    )
+    .bind(conversation_id)
+    .bind(user_id)
+    .fetch_optional(&mut *transaction)
+    .await?;
+
+    if maybe_conversation.is_some() {
+        for statement in [
+            r#"
+DELETE FROM message_citations
+WHERE message_id IN ( SELECT id FROM messages WHERE conversation_id = ?1 )
+    OR chunk_id IN ( SELECT id FROM document_chunks WHERE conversation_id = ?1 )
+            "#,
+            r#"
+DELETE FROM attachments
+WHERE message_id IN ( SELECT id FROM messages WHERE conversation_id = ?1 )
+            "#,
+            r#"
+DELETE FROM message_feedback
+WHERE message_id IN ( SELECT id FROM messages WHERE conversation_id = ?1 )
+            "#,
+            "DELETE FROM document_chunks WHERE conversation_id = ?1",
+            "DELETE FROM documents WHERE conversation_id = ?1",
+            "DELETE FROM messages WHERE conversation_id = ?1",
+        ] {
+            sqlx::query(statement)
+                .bind(conversation_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+    }
+
+    transaction.commit().await?;
+
+    match maybe_conversation {
+        Some(conversation) => {
+            debug!(
+                conversation_id = conversation_id.to_string(),
+                "conversation deleted from db"
+            );
+            Ok(Some(conversation))
+        }
+        None => {
+            debug!(
+                conversation_id = conversation_id.to_string(),
+                "conversation not found"
+            );
+            Ok(None)
+        }
+    }
+}
+
+pub async fn get_daily_usage(
+    sqlite: SqlitePool,
+    user_id: Uuid,
+    since: DateTime<Utc>,
+) -> Result<Vec<DailyUsage>> {
+    let daily_usage: Vec<DailyUsage> = sqlx::query_as(
+        r#"
+SELECT
+    date(m.created_at) AS day,
+    COUNT(*) AS messages,
+    COALESCE(SUM(m.eval_count), 0) AS tokens_generated
+FROM messages m
+JOIN conversations c ON c.id = m.conversation_id
+WHERE c.user_id = ? AND date(m.created_at) >= date(?)
+GROUP BY day
+ORDER BY day ASC
+        "#,
+    )
+    .bind(user_id)
+    .bind(since)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(daily_usage)
+}
+
+pub async fn get_model_usage(sqlite: SqlitePool, user_id: Uuid) -> Result<Vec<ModelUsage>> {
+    // messages saved before generation stats were captured fall back to the conversation's model
+    let model_usage: Vec<ModelUsage> = sqlx::query_as(
+        r#"
+SELECT
+    COALESCE(m.llm_model, cs.llm_model) AS llm_model,
+    COUNT(*) AS messages,
+    COALESCE(SUM(m.eval_count), 0) AS tokens_generated,
+    AVG(m.eval_count * 1e9 / NULLIF(m.eval_duration, 0)) AS avg_tokens_per_second,
+    AVG((COALESCE(m.load_duration, 0) + m.prompt_eval_duration) / 1e6) AS avg_time_to_first_token_ms,
+    COUNT(m.comparison_id) AS comparisons,
+    COALESCE(SUM(m.winner), 0) AS wins
+FROM messages m
+JOIN conversations c ON c.id = m.conversation_id
+LEFT JOIN conversation_settings cs ON cs.conversation_id = m.conversation_id
+WHERE c.user_id = ?
+    AND m.role = 'assistant'
+    AND COALESCE(m.llm_model, cs.llm_model) IS NOT NULL
+GROUP BY 1
+ORDER BY messages DESC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(model_usage)
+}
+
+pub async fn get_busiest_conversations(
+    sqlite: SqlitePool,
+    user_id: Uuid,
+    limit: i64,
+) -> Result<Vec<ConversationUsage>> {
+    let conversation_usage: Vec<ConversationUsage> = sqlx::query_as(
+        r#"
+SELECT
+    c.id AS conversation_id,
+    c.name AS name,
+    COUNT(*) AS messages,
+    COALESCE(SUM(m.eval_count), 0) AS tokens_generated
+FROM messages m
+JOIN conversations c ON c.id = m.conversation_id
+WHERE c.user_id = ?
+GROUP BY c.id, c.name
+ORDER BY messages DESC, tokens_generated DESC
+LIMIT ?
+        "#,
+    )
+    .bind(user_id)
+    .bind(limit)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(conversation_usage)
+}
+
+pub async fn count_users(sqlite: SqlitePool) -> Result<i64> {
+    let (count,): (i64,) = sqlx::query_as("SELECT COUNT(*) FROM users")
+        .fetch_one(&sqlite)
+        .await?;
+
+    Ok(count)
+}
+
+/// Creates the admin only if there are no users yet, and hands over conversations without an owner.
+pub async fn create_first_admin(sqlite: SqlitePool, admin: User) -> Result<Option<User>> {
+    let mut transaction = sqlite.begin().await?;
+
+    let maybe_admin: Option<User> = sqlx::query_as(
+        r#"
+INSERT INTO users ( id, username, password_hash, is_admin, created_at )
+SELECT ?1, ?2, ?3, TRUE, ?4
+WHERE NOT EXISTS ( SELECT 1 FROM users )
+RETURNING *
+        "#,
+    )
+    .bind(admin.id)
+    .bind(admin.username)
+    .bind(admin.password_hash)
+    .bind(admin.created_at)
+    .fetch_optional(&mut *transaction)
+    .await?;
+
+    if let Some(admin) = &maybe_admin {
+        claim_ownerless_rows(&mut transaction, admin.id).await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(maybe_admin)
+}
+
+/// Gives the first admin the rows created before accounts existed.
+async fn claim_ownerless_rows(connection: &mut SqliteConnection, admin_id: Uuid) -> Result<()> {
+    for table in ["conversations", "attachments"] {
+        sqlx::query(&format!(
+            "UPDATE {table} SET user_id = ? WHERE user_id IS NULL"
+        ))
+        .bind(admin_id)
+        .execute(&mut *connection)
+        .await?;
+    }
+
+    Ok(())
+}
+
+pub async fn create_user(sqlite: SqlitePool, user: User) -> Result<User> {
+    debug!(user_id = user.id.to_string(), "saving user to db");
+
+    let new_user: User = sqlx::query_as(
+        r#"
+INSERT INTO users ( id, username, password_hash, is_admin, created_at )
+VALUES ( ?1, ?2, ?3, ?4, ?5 )
+RETURNING *
+        "#,
+    )
+    .bind(user.id)
+    .bind(user.username)
+    .bind(user.password_hash)
+    .bind(user.is_admin)
+    .bind(user.created_at)
+    .fetch_one(&sqlite)
+    .await?;
+
+    Ok(new_user)
+}
+
+/// Sets a new password and logs the user out everywhere.
+pub async fn reset_password(
+    sqlite: SqlitePool,
+    user_id: Uuid,
+    password_hash: String,
+) -> Result<()> {
+    let mut transaction = sqlite.begin().await?;
+
+    sqlx::query(
+        r#"
+UPDATE users
+SET password_hash = ?1
+WHERE id = ?2
+        "#,
+    )
+    .bind(password_hash)
+    .bind(user_id)
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        r#"
+DELETE FROM sessions
+WHERE user_id = ?
+        "#,
+    )
+    .bind(user_id)
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+pub async fn get_user_by_username(sqlite: SqlitePool, username: &str) -> Result<Option<User>> {
+    let maybe_user: Option<User> = sqlx::query_as(
+        r#"
+SELECT *
+FROM users
+WHERE username = ?
+        "#,
+    )
+    .bind(username)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_user)
+}
+
+/// Finds or creates the user authenticated by the reverse proxy, the very first user becomes the admin.
+/// None when the username belongs to a local account, which is never bound to the proxy.
+pub async fn upsert_proxy_user(
+    sqlite: SqlitePool,
+    subject: String,
+    email: Option<String>,
+) -> Result<Option<User>> {
+    let new_user = User::new(subject.clone(), String::new(), false);
+
+    let mut transaction = sqlite.begin().await?;
+
+    // not every request carries the email header
+    let user: User = match sqlx::query_as(
+        r#"
+INSERT INTO users ( id, username, password_hash, is_admin, created_at, email, proxy_subject )
+SELECT ?1, ?2, '', NOT EXISTS ( SELECT 1 FROM users ), ?3, ?4, ?2
+WHERE TRUE
+ON CONFLICT ( proxy_subject ) DO UPDATE SET email = COALESCE(excluded.email, users.email)
+RETURNING *
+        "#,
+    )
+    .bind(new_user.id)
+    .bind(&subject)
+    .bind(new_user.created_at)
+    .bind(email)
+    .fetch_one(&mut *transaction)
+    .await
+    {
+        Ok(user) => user,
+        // only the username is left to conflict
+        Err(sqlx::Error::Database(err)) if err.is_unique_violation() => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+
+    if user.id == new_user.id && user.is_admin {
+        claim_ownerless_rows(&mut transaction, user.id).await?;
+    }
+
+    transaction.commit().await?;
+
+    Ok(Some(user))
+}
+
+pub async fn get_users(sqlite: SqlitePool) -> Result<Vec<User>> {
+    let users: Vec<User> = sqlx::query_as(
+        r#"
+SELECT *
+FROM users
+ORDER BY created_at ASC
+        "#,
+    )
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(users)
+}
+
+/// Prompt and completion tokens of answers in the user's conversations since the given time.
+pub async fn count_tokens_since(
+    sqlite: SqlitePool,
+    user_id: Uuid,
+    since: DateTime<Utc>,
+) -> Result<i64> {
+    let tokens: i64 = sqlx::query_scalar(
+        r#"
+SELECT COALESCE(SUM(COALESCE(m.prompt_eval_count, 0) + COALESCE(m.eval_count, 0)), 0)
+FROM messages m
+JOIN conversations c ON c.id = m.conversation_id
+WHERE c.user_id = ?1 AND m.created_at >= ?2
+        "#,
+    )
+    .bind(user_id)
+    .bind(since)
+    .fetch_one(&sqlite)
+    .await?;
+
+    Ok(tokens)
+}
+
+/// Tokens used by every user since the given time, users without answers are left out.
+pub async fn get_tokens_used_since(
+    sqlite: SqlitePool,
+    since: DateTime<Utc>,
+) -> Result<HashMap<Uuid, i64>> {
+    let rows: Vec<(Uuid, i64)> = sqlx::query_as(
+        r#"
+SELECT c.user_id, SUM(COALESCE(m.prompt_eval_count, 0) + COALESCE(m.eval_count, 0))
+FROM messages m
+JOIN conversations c ON c.id = m.conversation_id
+WHERE m.created_at >= ?1
+GROUP BY c.user_id
+        "#,
+    )
+    .bind(since)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(rows.into_iter().collect())
+}
+
+pub async fn create_session(sqlite: SqlitePool, session: Session) -> Result<Session> {
+    let new_session: Session = sqlx::query_as(
+        r#"
+INSERT INTO sessions ( token_hash, user_id, expires_at, created_at )
+VALUES ( ?1, ?2, ?3, ?4 )
+RETURNING *
+        "#,
+    )
+    .bind(session.token_hash)
+    .bind(session.user_id)
+    .bind(session.expires_at)
+    .bind(session.created_at)
+    .fetch_one(&sqlite)
+    .await?;
+
+    Ok(new_session)
+}
+
+pub async fn get_session_user(
+    sqlite: SqlitePool,
+    token_hash: Vec<u8>,
+    now: DateTime<Utc>,
+) -> Result<Option<User>> {
+    let maybe_user: Option<User> = sqlx::query_as(
+        r#"
+SELECT u.*
+FROM sessions s
+JOIN users u ON u.id = s.user_id
+WHERE s.token_hash = ? AND s.expires_at > ?
+        "#,
+    )
+    .bind(token_hash)
+    .bind(now)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_user)
+}
+
+pub async fn delete_session(sqlite: SqlitePool, token_hash: Vec<u8>) -> Result<()> {
+    sqlx::query(
+        r#"
+DELETE FROM sessions
+WHERE token_hash = ?
+        "#,
+    )
+    .bind(token_hash)
+    .execute(&sqlite)
+    .await?;
+
+    Ok(())
+}
+
+pub async fn get_user(sqlite: SqlitePool, user_id: Uuid) -> Result<Option<User>> {
+    let maybe_user: Option<User> = sqlx::query_as(
+        r#"
+SELECT *
+FROM users
+WHERE id = ?
+        "#,
+    )
+    .bind(user_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_user)
+}
+
+pub async fn create_api_token(sqlite: SqlitePool, api_token: ApiToken) -> Result<ApiToken> {
+    debug!(
+        api_token_id = api_token.id.to_string(),
+        "saving api token to db"
+    );
+
+    let new_api_token: ApiToken = sqlx::query_as(
+        r#"
+INSERT INTO api_tokens ( id, user_id, name, token_hash, scopes, last_used_at, created_at )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6, ?7 )
+RETURNING *
+        "#,
+    )
+    .bind(api_token.id)
+    .bind(api_token.user_id)
+    .bind(api_token.name)
+    .bind(api_token.token_hash)
+    .bind(api_token.scopes)
+    .bind(api_token.last_used_at)
+    .bind(api_token.created_at)
+    .fetch_one(&sqlite)
+    .await?;
+
+    Ok(new_api_token)
+}
+
+pub async fn get_api_tokens(sqlite: SqlitePool, user_id: Uuid) -> Result<Vec<ApiToken>> {
+    let api_tokens: Vec<ApiToken> = sqlx::query_as(
+        r#"
+SELECT *
+FROM api_tokens
+WHERE user_id = ?
+ORDER BY created_at ASC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(api_tokens)
+}
+
+// every request of a script writing to the database would contend with the answers being saved
+const API_TOKEN_LAST_USED_PRECISION: Duration = Duration::minutes(1);
+
+/// Token with the hash, its `last_used_at` is only updated once a minute.
+pub async fn use_api_token(
+    sqlite: SqlitePool,
+    token_hash: Vec<u8>,
+    now: DateTime<Utc>,
+) -> Result<Option<ApiToken>> {
+    let maybe_api_token: Option<ApiToken> = sqlx::query_as(
+        r#"
+SELECT *
+FROM api_tokens
+WHERE token_hash = ?
+        "#,
+    )
+    .bind(token_hash)
+    .fetch_optional(&sqlite)
+    .await?;
+    let Some(mut api_token) = maybe_api_token else {
+        return Ok(None);
+    };
+    if api_token
+        .last_used_at
+        .is_some_and(|last_used_at| now - last_used_at < API_TOKEN_LAST_USED_PRECISION)
+    {
+        return Ok(Some(api_token));
+    }
+
+    sqlx::query(
+        r#"
+UPDATE api_tokens
+SET last_used_at = ?1
+WHERE id = ?2
+        "#,
+    )
+    .bind(now)
+    .bind(api_token.id)
+    .execute(&sqlite)
+    .await?;
+    api_token.last_used_at = Some(now);
+
+    Ok(Some(api_token))
+}
+
+pub async fn delete_api_token(
+    sqlite: SqlitePool,
+    api_token_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<ApiToken>> {
+    let maybe_api_token: Option<ApiToken> = sqlx::query_as(
+        r#"
+DELETE FROM api_tokens
+WHERE id = ?1 AND user_id = ?2
+RETURNING *
+        "#,
+    )
+    .bind(api_token_id)
+    .bind(user_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_api_token)
+}
+
+pub async fn create_share_link(sqlite: SqlitePool, share_link: ShareLink) -> Result<ShareLink> {
+    debug!(
+        share_link_id = share_link.id.to_string(),
+        "saving share link to db"
+    );
+
+    let new_share_link: ShareLink = sqlx::query_as(
+        r#"
+INSERT INTO share_links ( id, conversation_id, token_hash, expires_at, created_at )
+VALUES ( ?1, ?2, ?3, ?4, ?5 )
+RETURNING *
+        "#,
+    )
+    .bind(share_link.id)
+    .bind(share_link.conversation_id)
+    .bind(share_link.token_hash)
+    .bind(share_link.expires_at)
+    .bind(share_link.created_at)
+    .fetch_one(&sqlite)
+    .await?;
+
+    Ok(new_share_link)
+}
+
+pub async fn get_share_links(sqlite: SqlitePool, conversation_id: Uuid) -> Result<Vec<ShareLink>> {
+    let share_links: Vec<ShareLink> = sqlx::query_as(
+        r#"
+SELECT *
+FROM share_links
+WHERE conversation_id = ?
+ORDER BY created_at ASC
+        "#,
+    )
+    .bind(conversation_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(share_links)
+}
+
+/// Conversation behind a share link which is neither revoked nor expired.
+pub async fn get_shared_conversation(
+    sqlite: SqlitePool,
+    token_hash: Vec<u8>,
+    now: DateTime<Utc>,
+) -> Result<Option<Conversation>> {
+    let maybe_conversation: Option<Conversation> = sqlx::query_as(
+        r#"
+SELECT c.*
+FROM share_links s
+JOIN conversations c ON c.id = s.conversation_id
+WHERE s.token_hash = ?1 AND (s.expires_at IS NULL OR s.expires_at > ?2)
+        "#,
+    )
+    .bind(token_hash)
+    .bind(now)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_conversation)
+}
+
+/// Attachment of a message in the conversation behind an active share link.
+pub async fn get_shared_attachment(
+    sqlite: SqlitePool,
+    token_hash: Vec<u8>,
+    attachment_id: Uuid,
+    now: DateTime<Utc>,
+) -> Result<Option<Attachment>> {
+    let maybe_attachment: Option<Attachment> = sqlx::query_as(
+        r#"
+SELECT a.*
+FROM attachments a
+JOIN messages m ON m.id = a.message_id
+JOIN share_links s ON s.conversation_id = m.conversation_id
+WHERE a.id = ?1 AND s.token_hash = ?2 AND (s.expires_at IS NULL OR s.expires_at > ?3)
+        "#,
+    )
+    .bind(attachment_id)
+    .bind(token_hash)
+    .bind(now)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_attachment)
+}
+
+pub async fn delete_share_link(
+    sqlite: SqlitePool,
+    share_link_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<ShareLink>> {
+    let maybe_share_link: Option<ShareLink> = sqlx::query_as(
+        r#"
+DELETE FROM share_links
+WHERE id = ?1 AND conversation_id IN ( SELECT id FROM conversations WHERE user_id = ?2 )
+RETURNING *
+        "#,
+    )
+    .bind(share_link_id)
+    .bind(user_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_share_link)
+}
+
+pub async fn create_folder(sqlite: SqlitePool, folder: Folder) -> Result<Folder> {
+    debug!(folder_id = folder.id.to_string(), "saving folder to db");
+
+    let new_folder: Folder = sqlx::query_as(
+        r#"
+INSERT INTO folders ( id, user_id, name, llm_model, system_prompt, created_at )
+VALUES ( ?1, ?2, ?3, ?4, ?5, ?6 )
+RETURNING *
+        "#,
+    )
+    .bind(folder.id)
+    .bind(folder.user_id)
+    .bind(folder.name)
+    .bind(folder.llm_model)
+    .bind(folder.system_prompt)
+    .bind(folder.created_at)
+    .fetch_one(&sqlite)
+    .await?;
+
+    Ok(new_folder)
+}
+
+pub async fn get_folder(
+    sqlite: SqlitePool,
+    folder_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<Folder>> {
+    let maybe_folder: Option<Folder> = sqlx::query_as(
+        r#"
+SELECT *
+FROM folders
+WHERE id = ? AND user_id = ?
+        "#,
+    )
+    .bind(folder_id)
+    .bind(user_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_folder)
+}
+
+pub async fn get_folders(sqlite: SqlitePool, user_id: Uuid) -> Result<Vec<Folder>> {
+    let folders: Vec<Folder> = sqlx::query_as(
+        r#"
+SELECT *
+FROM folders
+WHERE user_id = ?
+ORDER BY name ASC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(folders)
+}
+
+pub async fn update_folder(sqlite: SqlitePool, folder: Folder) -> Result<Option<Folder>> {
+    let maybe_folder: Option<Folder> = sqlx::query_as(
+        r#"
+UPDATE folders
+SET name = ?1, llm_model = ?2, system_prompt = ?3
+WHERE id = ?4 AND user_id = ?5
+RETURNING *
+        "#,
+    )
+    .bind(folder.name)
+    .bind(folder.llm_model)
+    .bind(folder.system_prompt)
+    .bind(folder.id)
+    .bind(folder.user_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_folder)
+}
+
+/// Deletes the folder, its conversations stay but move out of it.
+pub async fn delete_folder(
+    sqlite: SqlitePool,
+    folder_id: Uuid,
+    user_id: Uuid,
+) -> Result<Option<Folder>> {
+    let maybe_folder: Option<Folder> = sqlx::query_as(
+        r#"
+DELETE FROM folders
+WHERE id = ? AND user_id = ?
+RETURNING *
+        "#,
+    )
+    .bind(folder_id)
+    .bind(user_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_folder)
+}
+
+/// Moves the conversation into the folder or out of any folder, both have to belong to the user.
+pub async fn move_conversation(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+    folder_id: Option<Uuid>,
+    user_id: Uuid,
+) -> Result<Option<Conversation>> {
+    let maybe_conversation: Option<Conversation> = sqlx::query_as(
+        r#"
+UPDATE conversations
+SET folder_id = ?1
+WHERE id = ?2 AND user_id = ?3
+    AND (?1 IS NULL OR EXISTS ( SELECT 1 FROM folders WHERE id = ?1 AND user_id = ?3 ))
+RETURNING *
+        "#,
+    )
+    .bind(folder_id)
+    .bind(conversation_id)
+    .bind(user_id)
+    .fetch_optional(&sqlite)
+    .await?;
+
+    Ok(maybe_conversation)
+}
+
+pub async fn get_tags(sqlite: SqlitePool, user_id: Uuid) -> Result<Vec<Tag>> {
+    let tags: Vec<Tag> = sqlx::query_as(
+        r#"
+SELECT *
+FROM tags
+WHERE user_id = ?
+ORDER BY name ASC
+        "#,
+    )
+    .bind(user_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(tags)
+}
+
+pub async fn get_conversation_tags(sqlite: SqlitePool, conversation_id: Uuid) -> Result<Vec<Tag>> {
+    let tags: Vec<Tag> = sqlx::query_as(
+        r#"
+SELECT t.*
+FROM tags t
+JOIN conversation_tags ct ON ct.tag_id = t.id
+WHERE ct.conversation_id = ?
+ORDER BY t.name ASC
+        "#,
+    )
+    .bind(conversation_id)
+    .fetch_all(&sqlite)
+    .await?;
+
+    Ok(tags)
+}
+
+/// Tags the conversation, the tag is created the first time its name is used.
+pub async fn tag_conversation(sqlite: SqlitePool, conversation_id: Uuid, tag: Tag) -> Result<Tag> {
+    let mut transaction = sqlite.begin().await?;
+    let tag = insert_tag(&mut transaction, conversation_id, tag).await?;
+    transaction.commit().await?;
+
+    Ok(tag)
+}
+
+async fn insert_tag(
+    connection: &mut SqliteConnection,
+    conversation_id: Uuid,
+    tag: Tag,
+) -> Result<Tag> {
+    let tag: Tag = sqlx::query_as(
+        r#"
+INSERT INTO tags ( id, user_id, name, created_at )
+VALUES ( ?1, ?2, ?3, ?4 )
+ON CONFLICT ( user_id, name ) DO UPDATE SET name = excluded.name
+RETURNING *
+        "#,
+    )
+    .bind(tag.id)
+    .bind(tag.user_id)
+    .bind(tag.name)
+    .bind(tag.created_at)
+    .fetch_one(&mut *connection)
+    .await?;
+
+    sqlx::query(
+        r#"
+INSERT OR IGNORE INTO conversation_tags ( conversation_id, tag_id )
+VALUES ( ?1, ?2 )
+        "#,
+    )
+    .bind(conversation_id)
+    .bind(tag.id)
+    .execute(&mut *connection)
+    .await?;
+
+    Ok(tag)
+}
+
+/// Removes the tag from the conversation, tags which aren't used anymore are deleted.
+pub async fn untag_conversation(
+    sqlite: SqlitePool,
+    conversation_id: Uuid,
+    tag_id: Uuid,
+) -> Result<()> {
+    let mut transaction = sqlite.begin().await?;
+
+    sqlx::query(
+        r#"
+DELETE FROM conversation_tags
+WHERE conversation_id = ?1 AND tag_id = ?2
+        "#,
+    )
+    .bind(conversation_id)
+    .bind(tag_id)
+    .execute(&mut *transaction)
+    .await?;
+
+    sqlx::query(
+        r#"
+DELETE FROM tags
+WHERE id = ? AND NOT EXISTS ( SELECT 1 FROM conversation_tags WHERE tag_id = tags.id )
+        "#,
+    )
+    .bind(tag_id)
+    .execute(&mut *transaction)
+    .await?;
+
+    transaction.commit().await?;
+
+    Ok(())
+}
+
+/// Rebuilds the database file without the free pages, returns its size in bytes before and after.
+pub async fn vacuum(sqlite: SqlitePool) -> Result<(i64, i64)> {
+    let size = || async {
+        let size: i64 = sqlx::query_scalar(
+            r#"
+SELECT page_count * page_size
+FROM pragma_page_count(), pragma_page_size()
+            "#,
+        )
+        .fetch_one(&sqlite)
+        .await?;
+        Result::Ok(size)
+    };
+
+    let before = size().await?;
+    sqlx::query("VACUUM").execute(&sqlite).await?;
+    let after = size().await?;
+
+    Ok((before, after))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::models::{GenerationStats, Role, TokenScope, ToolCall};
+
+    use super::*;
+    use sqlx::Row;
+
+    static LLM_MODEL: &str = "test-model";
+    const USER_ID: Uuid = Uuid::from_u128(1);
+
+    async fn table_count(sqlite: SqlitePool, table_name: &str) -> Result<i64> {
+        let query = format!("SELECT COUNT(*) FROM {table_name}");
+        let count = sqlx::query(&query)
+            .bind(table_name)
+            .fetch_one(&sqlite)
+            .await?;
+
+        Ok(count.get(0))
+    }
+
+    #[sqlx::test]
+    async fn test_create_conversation_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        assert_eq!(table_count(pool.clone(), "conversations").await?, 0);
+        assert_eq!(table_count(pool.clone(), "conversation_settings").await?, 0);
+
+        // when:
+        let new_conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+
+        // then:
+        assert_eq!(table_count(pool.clone(), "conversations").await?, 1);
+        assert_eq!(table_count(pool, "conversation_settings").await?, 1);
+        assert_eq!(new_conversation.name, "name");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_update_conversation_output_format_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+
+        // when:
+        let settings = update_conversation_output_format(
+            pool.clone(),
+            conversation.id,
+            Some("json".to_string()),
+        )
+        .await?;
+        let missing = update_conversation_output_format(pool.clone(), Uuid::new_v4(), None).await?;
+
+        // then:
+        assert_eq!(settings.unwrap().output_format, Some("json".to_string()));
+        assert!(missing.is_none());
+        let settings = get_conversation_settings(pool, conversation.id).await?;
+        assert_eq!(settings.unwrap().output_format, Some("json".to_string()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_create_message_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        assert_eq!(table_count(pool.clone(), "conversations").await?, 1);
+        assert_eq!(table_count(pool.clone(), "conversation_settings").await?, 1);
+        assert_eq!(table_count(pool.clone(), "messages").await?, 0);
+
+        // when:
+        let new_message = create_message(
+            pool.clone(),
+            Message::user("content".to_string(), conversation.id),
+        )
+        .await?;
+
+        // then:
+        assert_eq!(table_count(pool, "messages").await?, 1);
+        assert_eq!(new_message.role, Role::User);
+        assert_eq!(new_message.content, "content");
+        assert_eq!(new_message.conversation_id, conversation.id);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_message_role_check_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+
+        // when:
+        let inserted = sqlx::query(
+            "INSERT INTO messages (id, role, content, conversation_id) VALUES (?, 'moderator', '', ?)",
+        )
+        .bind(Uuid::new_v4())
+        .bind(conversation.id)
+        .execute(&pool)
+        .await;
+
+        // then:
+        assert!(inserted.is_err());
+        assert_eq!(table_count(pool, "messages").await?, 0);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_finish_message_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let mut message = create_message(
+            pool.clone(),
+            Message::assistant("".to_string(), conversation.id),
+        )
+        .await?;
+
+        // when:
+        update_message_content(pool.clone(), message.id, "partial").await?;
+        let partial = get_conversation_messages(pool.clone(), conversation.id).await?;
+        message.content = "partial answer".to_string();
+        message.stats.llm_model = Some(LLM_MODEL.to_string());
+        message.stats.eval_count = Some(2);
+        finish_message(pool.clone(), &message).await?;
+        let finished = get_conversation_messages(pool, conversation.id).await?;
+
+        // then:
+        assert_eq!(partial[0].content, "partial");
+        assert_eq!(finished.len(), 1);
+        assert_eq!(finished[0].content, "partial answer");
+        assert_eq!(finished[0].stats, message.stats);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_count_tokens_since_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let since = Utc::now() - chrono::Duration::hours(1);
+        let mut yesterday = Message::assistant("old".to_string(), conversation.id);
+        yesterday.created_at = Utc::now() - chrono::Duration::days(1);
+        yesterday.stats.eval_count = Some(100);
+        let mut today = Message::assistant("new".to_string(), conversation.id);
+        today.stats.prompt_eval_count = Some(10);
+        today.stats.eval_count = Some(20);
+        create_message(pool.clone(), yesterday).await?;
+        create_message(pool.clone(), today).await?;
+
+        // when:
+        let tokens = count_tokens_since(pool.clone(), USER_ID, since).await?;
+        let tokens_by_user = get_tokens_used_since(pool, since).await?;
+
+        // then:
+        assert_eq!(tokens, 30);
+        assert_eq!(tokens_by_user, HashMap::from([(USER_ID, 30)]));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_create_message_with_stats_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let stats = GenerationStats {
+            llm_model: Some(LLM_MODEL.to_string()),
+            total_duration: Some(5_000_000_000),
+            load_duration: Some(1_000_000),
+            prompt_eval_count: Some(26),
+            prompt_eval_duration: Some(130_000_000),
+            eval_count: Some(290),
+            eval_duration: Some(4_700_000_000),
+        };
+        let mut message = Message::assistant("content".to_string(), conversation.id);
+        message.stats = stats.clone();
+
+        // when:
+        let _ = create_message(pool.clone(), message).await?;
+        let messages = get_conversation_messages(pool, conversation.id).await?;
+
+        // then:
+        assert_eq!(messages.len(), 1);
+        assert_eq!(messages[0].stats, stats);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_get_conversation_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+
+        // when:
+        let maybe_conversation = get_conversation(pool, conversation.id, USER_ID).await?;
+
+        // then:
+        assert!(maybe_conversation.is_some());
+        assert_eq!(maybe_conversation.unwrap(), conversation);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_get_conversation_messages_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+
+        // when:
+        let maybe_messages = get_conversation_messages(pool, Uuid::new_v4()).await?;
+
+        // then:
+        assert_eq!(maybe_messages, Vec::new());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_get_conversation_messages_page_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let mut messages = Vec::new();
+        for content in ["first", "answer", "compare"] {
+            messages.push(
+                create_message(
+                    pool.clone(),
+                    Message::user(content.to_string(), conversation.id),
+                )
+                .await?,
+            );
+        }
+        for llm_model in ["a", "b", "c"] {
+            let answer =
+                Message::comparison(llm_model.to_string(), messages[2].id, conversation.id);
+            messages.push(create_message(pool.clone(), answer).await?);
+        }
+        messages.push(
+            create_message(
+                pool.clone(),
+                Message::user("last".to_string(), conversation.id),
+            )
+            .await?,
+        );
+
+        // when:
+        let latest = get_conversation_messages_page(pool.clone(), conversation.id, None, 3).await?;
+        let older =
+            get_conversation_messages_page(pool.clone(), conversation.id, latest.older, 3).await?;
+        let first =
+            get_conversation_messages_page_after(pool.clone(), conversation.id, None, 4).await?;
+        let newer =
+            get_conversation_messages_page_after(pool, conversation.id, first.newer, 4).await?;
+
+        // then:
+        // the comparison isn't split between pages
+        assert_eq!(latest.messages, messages[3..].to_vec());
+        assert_eq!(latest.older, Some(messages[3].cursor()));
+        assert_eq!(older.messages, messages[..3].to_vec());
+        assert_eq!(older.older, None);
+        assert_eq!(first.messages, messages[..6].to_vec());
+        assert_eq!(first.newer, Some(messages[5].cursor()));
+        assert_eq!(newer.messages, messages[6..].to_vec());
+        assert_eq!(newer.newer, None);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_delete_conversation_which_exist_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = Conversation::new("test".to_string(), USER_ID);
+        let _ =
+            create_conversation(pool.clone(), conversation.clone(), LLM_MODEL.to_string()).await?;
+        assert_eq!(table_count(pool.clone(), "conversations").await?, 1);
+        assert_eq!(table_count(pool.clone(), "conversation_settings").await?, 1);
+
+        // when:
+        let maybe_deleted_conversation =
+            delete_conversation(pool.clone(), conversation.id, USER_ID).await?;
+
+        // then:
+        assert_eq!(table_count(pool.clone(), "conversations").await?, 0);
+        assert_eq!(table_count(pool, "conversation_settings").await?, 1);
+        assert!(maybe_deleted_conversation.is_some());
+        assert_eq!(conversation, maybe_deleted_conversation.unwrap());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_delete_conversation_which_doesnt_exist_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        assert_eq!(table_count(pool.clone(), "conversations").await?, 0);
+        assert_eq!(table_count(pool.clone(), "conversation_settings").await?, 0);
+
+        // when:
+        let maybe_deleted_conversation =
+            delete_conversation(pool.clone(), Uuid::new_v4(), USER_ID).await?;
+
+        // then:
+        assert_eq!(table_count(pool.clone(), "conversations").await?, 0);
+        assert_eq!(table_count(pool, "conversation_settings").await?, 0);
+        assert!(maybe_deleted_conversation.is_none());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_delete_conversation_deletes_its_content(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let other = create_conversation(
+            pool.clone(),
+            Conversation::new("other".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let document = Document::new(
+            "notes.txt".to_string(),
+            "text/plain".to_string(),
+            conversation.id,
+        );
+        let chunk = DocumentChunk::new(document.id, conversation.id, 0, "chunk".to_string(), &[]);
+        let document = create_document(pool.clone(), document, vec![chunk.clone()]).await?;
+        let attachment = create_attachment(
+            pool.clone(),
+            Attachment::new("image/png".to_string(), vec![1, 2, 3], USER_ID),
+        )
+        .await?;
+        let mut question = Message::user("question".to_string(), conversation.id);
+        question.attachment_ids = vec![attachment.id];
+        let _ = create_message(pool.clone(), question).await?;
+        let mut answer = Message::assistant("answer [1]".to_string(), conversation.id);
+        answer.citations = vec![Citation {
+            message_id: answer.id,
+            chunk_id: chunk.id,
+            rank: 1,
+            document_id: document.id,
+            document_name: document.name.clone(),
+            chunk_index: 0,
+        }];
+        let answer = create_message(pool.clone(), answer).await?;
+        let _ = upsert_feedback(pool.clone(), Feedback::new(answer.id, 1, None)).await?;
+        let _ = create_message(pool.clone(), Message::user("kept".to_string(), other.id)).await?;
+
+        // when:
+        let _ = delete_conversation(pool.clone(), conversation.id, USER_ID).await?;
+
+        // then:
+        assert_eq!(table_count(pool.clone(), "messages").await?, 1);
+        assert_eq!(table_count(pool.clone(), "attachments").await?, 0);
+        assert_eq!(table_count(pool.clone(), "documents").await?, 0);
+        assert_eq!(table_count(pool.clone(), "document_chunks").await?, 0);
+        assert_eq!(table_count(pool.clone(), "message_citations").await?, 0);
+        assert_eq!(table_count(pool, "message_feedback").await?, 0);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_usage_stats_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let busy = create_conversation(
+            pool.clone(),
+            Conversation::new("busy".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let quiet = create_conversation(
+            pool.clone(),
+            Conversation::new("quiet".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        for conversation_id in [busy.id, busy.id, quiet.id] {
+            let _ = create_message(
+                pool.clone(),
+                Message::user("question".to_string(), conversation_id),
+            )
+            .await?;
+            let mut answer = Message::assistant("answer".to_string(), conversation_id);
+            answer.stats = GenerationStats {
+                llm_model: Some("other-model".to_string()),
+                load_duration: Some(10_000_000),
+                prompt_eval_duration: Some(90_000_000),
+                eval_count: Some(50),
+                eval_duration: Some(1_000_000_000),
+                ..Default::default()
+            };
+            let _ = create_message(pool.clone(), answer).await?;
+        }
+        // message without stats is attributed to the conversation's model
+        let _ = create_message(
+            pool.clone(),
+            Message::assistant("legacy".to_string(), quiet.id),
+        )
+        .await?;
+
+        // when:
+        let daily = get_daily_usage(
+            pool.clone(),
+            USER_ID,
+            Utc::now() - chrono::Duration::days(1),
+        )
+        .await?;
+        let models = get_model_usage(pool.clone(), USER_ID).await?;
+        let conversations = get_busiest_conversations(pool, USER_ID, 10).await?;
+
+        // then:
+        assert_eq!(daily.len(), 1);
+        assert_eq!(daily[0].messages, 7);
+        assert_eq!(daily[0].tokens_generated, 150);
+
+        assert_eq!(models.len(), 2);
+        assert_eq!(models[0].llm_model, "other-model");
+        assert_eq!(models[0].messages, 3);
+        assert_eq!(models[0].tokens_generated, 150);
+        assert_eq!(models[0].avg_tokens_per_second, Some(50.0));
+        assert_eq!(models[0].avg_time_to_first_token_ms, Some(100.0));
+        assert_eq!(models[1].llm_model, LLM_MODEL);
+        assert_eq!(models[1].avg_tokens_per_second, None);
+
+        assert_eq!(conversations.len(), 2);
+        assert_eq!(conversations[0].conversation_id, busy.id);
+        assert_eq!(conversations[0].messages, 4);
+        assert_eq!(conversations[1].conversation_id, quiet.id);
+        assert_eq!(conversations[1].tokens_generated, 50);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_create_message_with_attachments_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let attachment = create_attachment(
+            pool.clone(),
+            Attachment::new("image/png".to_string(), vec![1, 2, 3], USER_ID),
+        )
+        .await?;
+        let mut message = Message::user("content".to_string(), conversation.id);
+        message.attachment_ids = vec![attachment.id];
+
+        // when:
+        let new_message = create_message(pool.clone(), message).await?;
+        let messages = get_conversation_messages(pool.clone(), conversation.id).await?;
+        let attachments = get_message_attachments(pool.clone(), new_message.id).await?;
+
+        // then:
+        assert_eq!(new_message.attachment_ids, vec![attachment.id]);
+        assert_eq!(messages[0].attachment_ids, vec![attachment.id]);
+        assert_eq!(attachments.len(), 1);
+        assert_eq!(attachments[0].data, vec![1, 2, 3]);
+        assert_eq!(attachments[0].message_id, Some(new_message.id));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_create_message_cannot_claim_linked_attachment(
+        pool: sqlx::SqlitePool,
+    ) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let attachment = create_attachment(
+            pool.clone(),
+            Attachment::new("image/png".to_string(), vec![1, 2, 3], USER_ID),
+        )
+        .await?;
+        let mut first = Message::user("first".to_string(), conversation.id);
+        first.attachment_ids = vec![attachment.id];
+        let first = create_message(pool.clone(), first).await?;
+
+        // when:
+        let mut second = Message::user("second".to_string(), conversation.id);
+        second.attachment_ids = vec![attachment.id];
+        let second = create_message(pool.clone(), second).await?;
+
+        // then:
+        assert_eq!(first.attachment_ids, vec![attachment.id]);
+        assert_eq!(second.attachment_ids, Vec::new());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_create_document_and_cite_chunk_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let document = Document::new(
+            "notes.md".to_string(),
+            "text/markdown".to_string(),
+            conversation.id,
+        );
+        let chunk = DocumentChunk::new(
+            document.id,
+            conversation.id,
+            0,
+            "chunk".to_string(),
+            &[0.1, 0.2],
+        );
+        let document = create_document(pool.clone(), document, vec![chunk.clone()]).await?;
+        let mut answer = Message::assistant("answer [1]".to_string(), conversation.id);
+        answer.citations = vec![Citation {
+            message_id: answer.id,
+            chunk_id: chunk.id,
+            rank: 1,
+            document_id: document.id,
+            document_name: document.name.clone(),
+            chunk_index: 0,
+        }];
+
+        // when:
+        let _ = create_message(pool.clone(), answer.clone()).await?;
+        let messages = get_conversation_messages(pool.clone(), conversation.id).await?;
+        let chunks = get_conversation_chunks(pool.clone(), conversation.id).await?;
+
+        // then:
+        assert_eq!(messages[0].citations, answer.citations);
+        assert_eq!(chunks, vec![chunk]);
+        assert_eq!(
+            get_conversation_documents(pool.clone(), conversation.id).await?,
+            vec![document.clone()]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_delete_document_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let document = Document::new(
+            "notes.txt".to_string(),
+            "text/plain".to_string(),
+            conversation.id,
+        );
+        let chunk = DocumentChunk::new(document.id, conversation.id, 0, "chunk".to_string(), &[]);
+        let document = create_document(pool.clone(), document, vec![chunk.clone()]).await?;
+        let mut answer = Message::assistant("answer [1]".to_string(), conversation.id);
+        answer.citations = vec![Citation {
+            message_id: answer.id,
+            chunk_id: chunk.id,
+            rank: 1,
+            document_id: document.id,
+            document_name: document.name.clone(),
+            chunk_index: 0,
+        }];
+        let _ = create_message(pool.clone(), answer).await?;
+        assert_eq!(table_count(pool.clone(), "document_chunks").await?, 1);
+
+        // when:
+        let maybe_deleted_document = delete_document(pool.clone(), document.id).await?;
+
+        // then:
+        assert_eq!(maybe_deleted_document, Some(document));
+        assert_eq!(table_count(pool.clone(), "documents").await?, 0);
+        assert_eq!(table_count(pool.clone(), "document_chunks").await?, 0);
+        assert_eq!(table_count(pool.clone(), "message_citations").await?, 0);
+        assert_eq!(table_count(pool, "messages").await?, 1);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_create_tool_messages_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let mut tool_call = Message::assistant("".to_string(), conversation.id);
+        tool_call.tool_calls = Some(sqlx::types::Json(vec![ToolCall {
+            name: "calculator".to_string(),
+            arguments: serde_json::json!({ "expression": "1 + 1" }),
+        }]));
+        let tool_result = Message::tool("2".to_string(), "calculator".to_string(), conversation.id);
+
+        // when:
+        let _ = create_message(pool.clone(), tool_call.clone()).await?;
+        let _ = create_message(pool.clone(), tool_result).await?;
+        let messages = get_conversation_messages(pool, conversation.id).await?;
+
+        // then:
+        assert_eq!(messages[0].tool_calls(), tool_call.tool_calls());
+        assert_eq!(messages[1].role, Role::Tool);
+        assert_eq!(messages[1].tool_name, Some("calculator".to_string()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_pick_comparison_winner_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let prompt = create_message(
+            pool.clone(),
+            Message::user("compare".to_string(), conversation.id),
+        )
+        .await?;
+        let mut answers = Vec::new();
+        for llm_model in ["a", "b"] {
+            let mut answer = Message::comparison(llm_model.to_string(), prompt.id, conversation.id);
+            answer.update_content(llm_model);
+            answers.push(create_message(pool.clone(), answer).await?);
+        }
+
+        // when:
+        let _ = pick_comparison_winner(pool.clone(), answers[0].id).await?;
+        let comparison = pick_comparison_winner(pool.clone(), answers[1].id).await?;
+
+        // then:
+        assert_eq!(
+            comparison.iter().map(|m| m.winner).collect::<Vec<_>>(),
+            vec![false, true]
+        );
+        assert!(!get_conversation_messages(pool.clone(), conversation.id).await?[0].winner);
+        let model_usage = get_model_usage(pool, USER_ID).await?;
+        let b = model_usage.iter().find(|m| m.llm_model == "b").unwrap();
+        assert_eq!((b.comparisons, b.wins), (1, 1));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_feedback_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let question = create_message(
+            pool.clone(),
+            Message::user("question".to_string(), conversation.id),
+        )
+        .await?;
+        let answer = create_message(
+            pool.clone(),
+            Message::assistant("answer".to_string(), conversation.id),
+        )
+        .await?;
+        let _ = create_message(
+            pool.clone(),
+            Message::user("thanks".to_string(), conversation.id),
+        )
+        .await?;
+
+        // when:
+        let _ = upsert_feedback(pool.clone(), Feedback::new(answer.id, -1, None)).await?;
+        let feedback = upsert_feedback(
+            pool.clone(),
+            Feedback::new(answer.id, 1, Some("good".to_string())),
+        )
+        .await?;
+        let examples = get_feedback_examples(pool.clone(), USER_ID).await?;
+
+        // then:
+        assert_eq!(table_count(pool.clone(), "message_feedback").await?, 1);
+        assert_eq!(feedback.rating, 1);
+        let messages = get_conversation_messages(pool, conversation.id).await?;
+        assert_eq!(messages[1].feedback, Some(feedback));
+        assert_eq!(examples.len(), 1);
+        assert_eq!(examples[0].history.len(), 1);
+        assert_eq!(examples[0].history[0].content, question.content);
+        assert_eq!(examples[0].answer, "answer");
+        assert_eq!(examples[0].note, Some("good".to_string()));
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_search_messages_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("pets".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        for content in ["I like cats", "100% dogs", "birds"] {
+            let _ = create_message(
+                pool.clone(),
+                Message::user(content.to_string(), conversation.id),
+            )
+            .await?;
+        }
+
+        // when:
+        let cats = search_messages(pool.clone(), USER_ID, "CATS", 10).await?;
+        let percent = search_messages(pool.clone(), USER_ID, "0%", 10).await?;
+        let wildcard = search_messages(pool, USER_ID, "_", 10).await?;
 
-    let settings = ConversationSettings::new(llm_model, conversation.id);
-    let new_conversation: Conversation = sqlx::query_as(
-        r#"
-INSERT INTO conversations ( id, name, created_at )
-VALUES ( ?1, ?2, ?3 )
-RETURNING *
-        "#,
-    )
-    .bind(conversation.id)
-    .bind(conversation.name)
-    .bind(conversation.created_at)
-    .fetch_one(&mut *transaction)
-    .await?;
+        // then:
+        assert_eq!(cats.len(), 1);
+        assert_eq!(cats[0].conversation_name, "pets");
+        assert_eq!(percent.len(), 1);
+        assert_eq!(percent[0].content, "100% dogs");
+        assert!(wildcard.is_empty());
 
-    let _: ConversationSettings = sqlx::query_as(
-        r#"
-INSERT INTO conversation_settings ( id, llm_model, conversation_id, created_at )
-VALUES ( ?1, ?2, ?3, ?4 )
-RETURNING *
-        "#,
-    )
-    .bind(settings.id)
-    .bind(settings.llm_model)
-    .bind(settings.conversation_id)
-    .bind(settings.created_at)
-    .fetch_one(&mut *transaction)
-    .await?;
+        Ok(())
+    }
 
-    debug!(
-        conversation_id = new_conversation.id.to_string(),
-        "conversation saved to db"
-    );
+    #[sqlx::test]
+    async fn test_create_first_admin_claims_orphaned_conversations(
+        pool: sqlx::SqlitePool,
+    ) -> Result<()> {
+        // given:
+        let mut orphan = Conversation::new("before accounts".to_string(), USER_ID);
+        orphan.user_id = None;
+        let _ = create_conversation(pool.clone(), orphan.clone(), LLM_MODEL.to_string()).await?;
 
-    transaction.commit().await?;
+        // when:
+        let admin =
+            create_first_admin(pool.clone(), User::new("admin".into(), "hash".into(), true))
+                .await?
+                .unwrap();
+        let second =
+            create_first_admin(pool.clone(), User::new("other".into(), "hash".into(), true))
+                .await?;
 
-    Ok(new_conversation)
-}
+        // then:
+        assert!(admin.is_admin);
+        assert!(second.is_none());
+        assert_eq!(count_users(pool.clone()).await?, 1);
+        let conversations = get_conversations(pool, admin.id, None, None, None, 10).await?;
+        assert_eq!(conversations[0].id, orphan.id);
 
-pub async fn create_conversation_if_not_exists(
-    sqlite: SqlitePool,
-    conversation: Conversation,
-    llm_model: String,
-) -> Result<Conversation> {
-    if let Some(conversation) = get_conversation(sqlite.clone(), conversation.id).await? {
-        debug!(
-            conversation_id = conversation.id.to_string(),
-            "conversation already exist in db"
-        );
-        return Ok(conversation);
-    } else {
-        return create_conversation(sqlite, conversation, llm_model).await;
+        Ok(())
     }
-}
 
-pub async fn delete_conversation(
-    sqlite: SqlitePool,
-    conversation_id: Uuid,
-) -> Result<Option<Conversation>> {
-    debug!(
-        conversation_id = conversation_id.to_string(),
-        "deleting conversation from db"
-    );
+    #[sqlx::test]
+    async fn test_get_conversations_scoped_to_owner(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let other_user = Uuid::new_v4();
+        let mine = create_conversation(
+            pool.clone(),
+            Conversation::new("mine".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
+        let theirs = create_conversation(
+            pool.clone(),
+            Conversation::new("theirs".to_string(), other_user),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
 
-    let maybe_conversation: Option<Conversation> = sqlx::query_as(
-        r#"
-        DELETE FROM conversations
-        WHERE id = ?1
-        RETURNING *
-        "#,
-    )
-    .bind(conversation_id)
-    .fetch_optional(&sqlite)
-    .await?;
+        // when:
+        let conversations = get_conversations(pool.clone(), USER_ID, None, None, None, 10).await?;
+        let foreign = get_conversation(pool.clone(), theirs.id, USER_ID).await?;
+        let exists = conversation_exists(pool.clone(), mine.id, USER_ID).await?;
+        let foreign_exists = conversation_exists(pool.clone(), theirs.id, USER_ID).await?;
+        let deleted = delete_conversation(pool.clone(), theirs.id, USER_ID).await?;
 
-    match maybe_conversation {
-        Some(conversation) => {
-            debug!(
-                conversation_id = conversation_id.to_string(),
-                "conversation deleted from db"
-            );
-            Ok(Some(conversation))
-        }
-        None => {
-            debug!(
-                conversation_id = conversation_id.to_string(),
-                "conversation not found"
-            );
-            Ok(None)
-        }
-    }
-}
+        // then:
+        assert_eq!(conversations, vec![mine]);
+        assert!(foreign.is_none());
+        assert!(exists);
+        assert!(!foreign_exists);
+        assert!(deleted.is_none());
+        assert_eq!(table_count(pool, "conversations").await?, 2);
 
-#[cfg(test)]
-mod tests {
-    use crate::models::Role;
+        Ok(())
+    }
 
-    use super::*;
-    use sqlx::Row;
+    #[sqlx::test]
+    async fn test_get_conversations_paginated_by_activity(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let mut conversations = Vec::new();
+        for name in ["oldest", "middle", "newest"] {
+            conversations.push(
+                create_conversation(
+                    pool.clone(),
+                    Conversation::new(name.to_string(), USER_ID),
+                    LLM_MODEL.to_string(),
+                )
+                .await?,
+            );
+        }
+        let folder = create_folder(pool.clone(), Folder::new("work".into(), USER_ID)).await?;
+        let mut filed = Conversation::new("filed".to_string(), USER_ID);
+        filed.folder_id = Some(folder.id);
+        let filed = create_conversation(pool.clone(), filed, LLM_MODEL.to_string()).await?;
+        // a new message moves the conversation to the top
+        create_message(
+            pool.clone(),
+            Message::user("bump".to_string(), conversations[0].id),
+        )
+        .await?;
 
-    static LLM_MODEL: &'static str = "test-model";
+        // when:
+        let first_page = get_conversations(pool.clone(), USER_ID, None, None, None, 2).await?;
+        let cursor = first_page.last().map(Conversation::cursor);
+        let second_page = get_conversations(pool.clone(), USER_ID, None, None, cursor, 2).await?;
+        let in_folder =
+            get_conversations(pool.clone(), USER_ID, Some(folder.id), None, None, 2).await?;
 
-    async fn table_count(sqlite: SqlitePool, table_name: &str) -> Result<i64> {
-        let query = format!("SELECT COUNT(*) FROM {table_name}");
-        let count = sqlx::query(&query)
-            .bind(table_name)
-            .fetch_one(&sqlite)
-            .await?;
+        // then:
+        let names = |page: &[Conversation]| page.iter().map(|c| c.name.clone()).collect::<Vec<_>>();
+        assert_eq!(names(&first_page), vec!["oldest", "newest"]);
+        assert_eq!(names(&second_page), vec!["middle"]);
+        assert_eq!(in_folder, vec![filed]);
+        assert!(first_page[0].updated_at > first_page[0].created_at);
 
-        Ok(count.get(0))
+        Ok(())
     }
 
     #[sqlx::test]
-    async fn test_create_conversation_ok(pool: sqlx::SqlitePool) -> Result<()> {
+    async fn test_session_user_ok(pool: sqlx::SqlitePool) -> Result<()> {
         // given:
-        assert_eq!(table_count(pool.clone(), "conversations").await?, 0);
-        assert_eq!(table_count(pool.clone(), "conversation_settings").await?, 0);
+        let user = create_user(pool.clone(), User::new("ann".into(), "hash".into(), false)).await?;
+        let now = Utc::now();
+        let _ = create_session(
+            pool.clone(),
+            Session::new(vec![1], user.id, now + chrono::Duration::days(1)),
+        )
+        .await?;
+        let _ = create_session(
+            pool.clone(),
+            Session::new(vec![2], user.id, now - chrono::Duration::days(1)),
+        )
+        .await?;
 
         // when:
-        let new_conversation = create_conversation(
+        let active = get_session_user(pool.clone(), vec![1], now).await?;
+        let expired = get_session_user(pool.clone(), vec![2], now).await?;
+        delete_session(pool.clone(), vec![1]).await?;
+        let logged_out = get_session_user(pool, vec![1], now).await?;
+
+        // then:
+        assert_eq!(active, Some(user));
+        assert!(expired.is_none());
+        assert!(logged_out.is_none());
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_reset_password_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let user = create_user(pool.clone(), User::new("ann".into(), "hash".into(), false)).await?;
+        let now = Utc::now();
+        let _ = create_session(
             pool.clone(),
-            Conversation::new("name".to_string()),
-            LLM_MODEL.to_string(),
+            Session::new(vec![1], user.id, now + chrono::Duration::days(1)),
         )
         .await?;
 
+        // when:
+        reset_password(pool.clone(), user.id, "new hash".into()).await?;
+
         // then:
-        assert_eq!(table_count(pool.clone(), "conversations").await?, 1);
-        assert_eq!(table_count(pool, "conversation_settings").await?, 1);
-        assert_eq!(new_conversation.name, "name");
+        let user = get_user(pool.clone(), user.id).await?.unwrap();
+        assert_eq!(user.password_hash, "new hash");
+        assert!(get_session_user(pool, vec![1], now).await?.is_none());
 
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_create_message_ok(pool: sqlx::SqlitePool) -> Result<()> {
+    async fn test_import_conversations_ok(pool: sqlx::SqlitePool) -> Result<()> {
         // given:
         let conversation = create_conversation(
             pool.clone(),
-            Conversation::new("name".to_string()),
+            Conversation::new("name".to_string(), USER_ID),
             LLM_MODEL.to_string(),
         )
         .await?;
-        assert_eq!(table_count(pool.clone(), "conversations").await?, 1);
-        assert_eq!(table_count(pool.clone(), "conversation_settings").await?, 1);
-        assert_eq!(table_count(pool.clone(), "messages").await?, 0);
+        create_message(
+            pool.clone(),
+            Message::user("question".to_string(), conversation.id),
+        )
+        .await?;
+        create_message(
+            pool.clone(),
+            Message::assistant("answer".to_string(), conversation.id),
+        )
+        .await?;
+        tag_conversation(
+            pool.clone(),
+            conversation.id,
+            Tag::new("rust".to_string(), USER_ID),
+        )
+        .await?;
+        let export = ConversationExport {
+            owner: Some("ann".to_string()),
+            settings: get_conversation_settings(pool.clone(), conversation.id).await?,
+            messages: get_conversation_messages(pool.clone(), conversation.id).await?,
+            conversation: get_user_conversations(pool.clone(), Some(USER_ID)).await?[0].clone(),
+        };
+        let other_user_id = Uuid::from_u128(2);
 
         // when:
-        let new_message = create_message(
+        // the ids of the second one clash with the exported conversation
+        let failed = import_conversations(
             pool.clone(),
-            Message::user("content".to_string(), conversation.id),
+            vec![export.clone().with_new_ids(other_user_id), export.clone()],
+            LLM_MODEL.to_string(),
         )
-        .await?;
+        .await;
+        let imported = import_conversations(
+            pool.clone(),
+            vec![export.with_new_ids(other_user_id)],
+            LLM_MODEL.to_string(),
+        )
+        .await?
+        .remove(0);
 
         // then:
-        assert_eq!(table_count(pool, "messages").await?, 1);
-        assert_eq!(new_message.role, Role::User.to_string());
-        assert_eq!(new_message.content, "content");
-        assert_eq!(new_message.conversation_id, conversation.id);
+        assert!(failed.is_err());
+        assert_eq!(
+            get_user_conversations(pool.clone(), Some(USER_ID))
+                .await?
+                .len(),
+            1
+        );
+        let conversations = get_user_conversations(pool.clone(), Some(other_user_id)).await?;
+        assert_eq!(
+            conversations
+                .iter()
+                .map(|conversation| conversation.id)
+                .collect::<Vec<_>>(),
+            vec![imported.id]
+        );
+        assert_eq!(
+            conversations[0]
+                .tags
+                .iter()
+                .map(|tag| (tag.name.as_str(), tag.user_id))
+                .collect::<Vec<_>>(),
+            vec![("rust", other_user_id)]
+        );
+        let messages = get_conversation_messages(pool.clone(), imported.id).await?;
+        assert_eq!(
+            messages
+                .iter()
+                .map(|message| (message.role, message.content.as_str()))
+                .collect::<Vec<_>>(),
+            vec![(Role::User, "question"), (Role::Assistant, "answer")]
+        );
+        let settings = get_conversation_settings(pool, imported.id).await?.unwrap();
+        assert_eq!(settings.llm_model, LLM_MODEL);
 
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_get_conversation_ok(pool: sqlx::SqlitePool) -> Result<()> {
+    async fn test_api_token_ok(pool: sqlx::SqlitePool) -> Result<()> {
         // given:
-        let conversation = create_conversation(
+        let user = create_user(pool.clone(), User::new("ann".into(), "hash".into(), false)).await?;
+        let api_token = create_api_token(
             pool.clone(),
-            Conversation::new("name".to_string()),
-            LLM_MODEL.to_string(),
+            ApiToken::new(user.id, "ci".into(), vec![1], vec![TokenScope::Read]),
         )
         .await?;
+        let now = Utc::now();
 
         // when:
-        let maybe_conversation = get_conversation(pool, conversation.id).await?;
+        let used = use_api_token(pool.clone(), vec![1], now).await?.unwrap();
+        let soon = now + Duration::seconds(30);
+        let used_again = use_api_token(pool.clone(), vec![1], soon).await?.unwrap();
+        let later = now + Duration::minutes(2);
+        let used_later = use_api_token(pool.clone(), vec![1], later).await?.unwrap();
+        let stored = get_api_tokens(pool.clone(), user.id).await?.remove(0);
+        let unknown = use_api_token(pool.clone(), vec![2], now).await?;
+        let foreign_delete = delete_api_token(pool.clone(), api_token.id, USER_ID).await?;
+        let deleted = delete_api_token(pool.clone(), api_token.id, user.id).await?;
+        let revoked = use_api_token(pool.clone(), vec![1], now).await?;
 
         // then:
-        assert!(maybe_conversation.is_some());
-        assert_eq!(maybe_conversation.unwrap(), conversation);
+        assert_eq!(used.last_used_at, Some(now));
+        assert_eq!(used_again.last_used_at, Some(now));
+        assert_eq!(used_later.last_used_at, Some(later));
+        assert_eq!(stored.last_used_at, Some(later));
+        assert!(used.has_scope(TokenScope::Read));
+        assert!(!used.has_scope(TokenScope::Write));
+        assert!(unknown.is_none());
+        assert!(foreign_delete.is_none());
+        assert_eq!(deleted.map(|api_token| api_token.id), Some(api_token.id));
+        assert!(revoked.is_none());
+        assert!(get_api_tokens(pool, user.id).await?.is_empty());
 
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_get_conversation_messages_ok(pool: sqlx::SqlitePool) -> Result<()> {
+    async fn test_upsert_proxy_user_ok(pool: sqlx::SqlitePool) -> Result<()> {
         // given:
+        let email = Some("ann@example.com".to_string());
 
         // when:
-        let maybe_messages = get_conversation_messages(pool, Uuid::new_v4()).await?;
+        let ann = upsert_proxy_user(pool.clone(), "ann".into(), None).await?;
+        let ann_again = upsert_proxy_user(pool.clone(), "ann".into(), email.clone()).await?;
+        let ann_without_email = upsert_proxy_user(pool.clone(), "ann".into(), None).await?;
+        let bob = upsert_proxy_user(pool.clone(), "bob".into(), None).await?;
 
         // then:
-        assert_eq!(maybe_messages, Vec::new());
+        let (ann, ann_again, ann_without_email, bob) = (
+            ann.unwrap(),
+            ann_again.unwrap(),
+            ann_without_email.unwrap(),
+            bob.unwrap(),
+        );
+        assert!(ann.is_admin);
+        assert_eq!(ann.proxy_subject.as_deref(), Some("ann"));
+        assert_eq!(ann_again.id, ann.id);
+        assert_eq!(ann_again.email, email);
+        assert_eq!(ann_without_email.email, email);
+        assert!(!bob.is_admin);
+        assert_eq!(count_users(pool).await?, 2);
 
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_create_conversation_if_not_exists_row_already_exists_ok(
-        pool: sqlx::SqlitePool,
-    ) -> Result<()> {
+    async fn test_upsert_proxy_user_ignores_local_account(pool: sqlx::SqlitePool) -> Result<()> {
         // given:
-        let conversation = Conversation::new("test".to_string());
-        let _ =
-            create_conversation(pool.clone(), conversation.clone(), LLM_MODEL.to_string()).await?;
-        assert_eq!(table_count(pool.clone(), "conversations").await?, 1);
-        assert_eq!(table_count(pool.clone(), "conversation_settings").await?, 1);
+        let local = create_user(
+            pool.clone(),
+            User::new("ann".to_string(), "hash".to_string(), false),
+        )
+        .await?;
 
         // when:
-        let already_existing_conversation = create_conversation_if_not_exists(
+        let maybe_user = upsert_proxy_user(
             pool.clone(),
-            conversation.clone(),
-            LLM_MODEL.to_string(),
+            "ann".into(),
+            Some("mallory@example.com".to_string()),
         )
         .await?;
 
         // then:
-        assert_eq!(table_count(pool.clone(), "conversations").await?, 1);
-        assert_eq!(table_count(pool, "conversation_settings").await?, 1);
-        assert_eq!(conversation, already_existing_conversation);
+        assert!(maybe_user.is_none());
+        let stored = get_user_by_username(pool.clone(), "ann").await?.unwrap();
+        assert_eq!(stored, local);
+        assert_eq!(count_users(pool).await?, 1);
 
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_create_conversation_if_not_exists_row_doesnt_exists_ok(
+    async fn test_first_proxy_user_claims_ownerless_conversations(
         pool: sqlx::SqlitePool,
     ) -> Result<()> {
         // given:
-        assert_eq!(table_count(pool.clone(), "conversations").await?, 0);
-        assert_eq!(table_count(pool.clone(), "conversation_settings").await?, 0);
+        let mut conversation = Conversation::new("old".to_string(), USER_ID);
+        conversation.user_id = None;
+        let conversation =
+            create_conversation(pool.clone(), conversation, LLM_MODEL.to_string()).await?;
 
         // when:
-        let _ = create_conversation_if_not_exists(
+        let ann = upsert_proxy_user(pool.clone(), "ann".into(), None)
+            .await?
+            .unwrap();
+
+        // then:
+        let conversations = get_user_conversations(pool, Some(ann.id)).await?;
+        assert_eq!(
+            conversations.iter().map(|c| c.id).collect::<Vec<_>>(),
+            vec![conversation.id]
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_share_link_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let conversation = create_conversation(
             pool.clone(),
-            Conversation::new("test".to_string()),
+            Conversation::new("shared".to_string(), USER_ID),
             LLM_MODEL.to_string(),
         )
         .await?;
+        let now = Utc::now();
+        let active = create_share_link(
+            pool.clone(),
+            ShareLink::new(conversation.id, b"active".to_vec(), None),
+        )
+        .await?;
+        let _ = create_share_link(
+            pool.clone(),
+            ShareLink::new(
+                conversation.id,
+                b"expired".to_vec(),
+                Some(now - chrono::Duration::hours(1)),
+            ),
+        )
+        .await?;
+
+        // when:
+        let shared = get_shared_conversation(pool.clone(), b"active".to_vec(), now).await?;
+        let expired = get_shared_conversation(pool.clone(), b"expired".to_vec(), now).await?;
+        let foreign_delete = delete_share_link(pool.clone(), active.id, Uuid::new_v4()).await?;
+        let deleted = delete_share_link(pool.clone(), active.id, USER_ID).await?;
+        let revoked = get_shared_conversation(pool.clone(), b"active".to_vec(), now).await?;
 
         // then:
-        assert_eq!(table_count(pool.clone(), "conversations").await?, 1);
-        assert_eq!(table_count(pool, "conversation_settings").await?, 1);
+        assert_eq!(shared, Some(conversation.clone()));
+        assert!(expired.is_none());
+        assert!(foreign_delete.is_none());
+        assert_eq!(deleted, Some(active));
+        assert!(revoked.is_none());
+        assert_eq!(get_share_links(pool, conversation.id).await?.len(), 1);
 
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_delete_conversation_which_exist_ok(pool: sqlx::SqlitePool) -> Result<()> {
+    async fn test_create_conversation_in_folder_ok(pool: sqlx::SqlitePool) -> Result<()> {
         // given:
-        let conversation = Conversation::new("test".to_string());
-        let _ =
-            create_conversation(pool.clone(), conversation.clone(), LLM_MODEL.to_string()).await?;
-        assert_eq!(table_count(pool.clone(), "conversations").await?, 1);
-        assert_eq!(table_count(pool.clone(), "conversation_settings").await?, 1);
+        let mut folder = Folder::new("work".to_string(), USER_ID);
+        folder.llm_model = Some("folder-model".to_string());
+        folder.system_prompt = Some("Be brief.".to_string());
+        let folder = create_folder(pool.clone(), folder).await?;
+        let foreign_folder = create_folder(
+            pool.clone(),
+            Folder::new("theirs".to_string(), Uuid::new_v4()),
+        )
+        .await?;
+        let mut in_folder = Conversation::new("in folder".to_string(), USER_ID);
+        in_folder.folder_id = Some(folder.id);
+        let mut in_foreign_folder = Conversation::new("in foreign folder".to_string(), USER_ID);
+        in_foreign_folder.folder_id = Some(foreign_folder.id);
 
         // when:
-        let maybe_deleted_conversation =
-            delete_conversation(pool.clone(), conversation.id.clone()).await?;
+        let in_folder = create_conversation(pool.clone(), in_folder, LLM_MODEL.to_string()).await?;
+        let in_foreign_folder =
+            create_conversation(pool.clone(), in_foreign_folder, LLM_MODEL.to_string()).await?;
 
         // then:
-        assert_eq!(table_count(pool.clone(), "conversations").await?, 0);
-        assert_eq!(table_count(pool, "conversation_settings").await?, 1);
-        assert!(maybe_deleted_conversation.is_some());
-        assert_eq!(conversation, maybe_deleted_conversation.unwrap());
+        let settings = get_conversation_settings(pool.clone(), in_folder.id)
+            .await?
+            .unwrap();
+        assert_eq!(in_folder.folder_id, Some(folder.id));
+        assert_eq!(settings.llm_model, "folder-model");
+        assert_eq!(settings.system_prompt.as_deref(), Some("Be brief."));
+        let settings = get_conversation_settings(pool, in_foreign_folder.id)
+            .await?
+            .unwrap();
+        assert_eq!(in_foreign_folder.folder_id, None);
+        assert_eq!(settings.llm_model, LLM_MODEL);
+        assert!(settings.system_prompt.is_none());
 
         Ok(())
     }
 
     #[sqlx::test]
-    async fn test_delete_conversation_which_doesnt_exist_ok(pool: sqlx::SqlitePool) -> Result<()> {
+    async fn test_move_conversation_ok(pool: sqlx::SqlitePool) -> Result<()> {
         // given:
-        assert_eq!(table_count(pool.clone(), "conversations").await?, 0);
-        assert_eq!(table_count(pool.clone(), "conversation_settings").await?, 0);
+        let folder = create_folder(pool.clone(), Folder::new("work".to_string(), USER_ID)).await?;
+        let foreign_folder = create_folder(
+            pool.clone(),
+            Folder::new("theirs".to_string(), Uuid::new_v4()),
+        )
+        .await?;
+        let conversation = create_conversation(
+            pool.clone(),
+            Conversation::new("chat".to_string(), USER_ID),
+            LLM_MODEL.to_string(),
+        )
+        .await?;
 
         // when:
-        let maybe_deleted_conversation = delete_conversation(pool.clone(), Uuid::new_v4()).await?;
+        let moved =
+            move_conversation(pool.clone(), conversation.id, Some(folder.id), USER_ID).await?;
+        let foreign = move_conversation(
+            pool.clone(),
+            conversation.id,
+            Some(foreign_folder.id),
+            USER_ID,
+        )
+        .await?;
+        let _ = delete_folder(pool.clone(), folder.id, USER_ID).await?;
 
         // then:
-        assert_eq!(table_count(pool.clone(), "conversations").await?, 0);
-        assert_eq!(table_count(pool, "conversation_settings").await?, 0);
-        assert!(maybe_deleted_conversation.is_none());
+        assert_eq!(moved.unwrap().folder_id, Some(folder.id));
+        assert!(foreign.is_none());
+        let conversation = get_conversation(pool, conversation.id, USER_ID)
+            .await?
+            .unwrap();
+        assert_eq!(conversation.folder_id, None);
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_tag_conversations_ok(pool: sqlx::SqlitePool) -> Result<()> {
+        // given:
+        let mut conversations = Vec::new();
+        for name in ["first", "second"] {
+            conversations.push(
+                create_conversation(
+                    pool.clone(),
+                    Conversation::new(name.to_string(), USER_ID),
+                    LLM_MODEL.to_string(),
+                )
+                .await?,
+            );
+        }
+        let (first, second) = (conversations[0].id, conversations[1].id);
+
+        // when:
+        let rust = tag_conversation(pool.clone(), first, Tag::new("rust".into(), USER_ID)).await?;
+        let rust_again =
+            tag_conversation(pool.clone(), second, Tag::new("rust".into(), USER_ID)).await?;
+        let _ = tag_conversation(pool.clone(), second, Tag::new("ai".into(), USER_ID)).await?;
+        let tagged =
+            get_conversations(pool.clone(), USER_ID, None, Some(rust.id), None, 10).await?;
+        untag_conversation(pool.clone(), first, rust.id).await?;
+        untag_conversation(pool.clone(), second, rust.id).await?;
+
+        // then:
+        assert_eq!(rust_again.id, rust.id);
+        assert_eq!(tagged.len(), 2);
+        assert_eq!(
+            tagged[0]
+                .tags
+                .iter()
+                .map(|t| t.name.as_str())
+                .collect::<Vec<_>>(),
+            vec!["ai", "rust"]
+        );
+        assert_eq!(tagged[1].tags, vec![rust.clone()]);
+        assert!(get_conversation_tags(pool.clone(), first).await?.is_empty());
+        // unused tags are gone
+        assert_eq!(
+            get_tags(pool, USER_ID)
+                .await?
+                .into_iter()
+                .map(|t| t.name)
+                .collect::<Vec<_>>(),
+            vec!["ai"]
+        );
 
         Ok(())
     }
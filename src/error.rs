@@ -1,13 +1,278 @@
+use axum::{
+    extract::Request,
+    http::{header, HeaderMap, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+    Json,
+};
 use derive_more::From;
+use serde_json::json;
+use tracing::error;
+
+use crate::frontend::templates::{ErrorFragment, ErrorPage};
+
+// reasons are short sentences, anything longer isn't meant for users
+const MAX_REASON_BYTES: usize = 4 * 1024;
 
 #[derive(Debug, From)]
 pub enum Error {
     #[from]
-    DatabaseError(sqlx::Error),
+    Database(sqlx::Error),
+    /// Ollama or another service LokAI calls
     #[from]
-    ReqwestError(reqwest::Error),
+    Upstream(reqwest::Error),
     #[from]
-    SendError(tokio::sync::mpsc::error::SendError<String>),
+    Send(tokio::sync::mpsc::error::SendError<String>),
+    /// files read and written by the command line
+    #[from]
+    Io(std::io::Error),
+    NotFound(String),
+    Validation(String),
+    Document(String),
+    Tool(String),
+    OutputFormat(String),
+    Auth(String),
+}
+
+impl std::fmt::Display for Error {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Database(err) => write!(f, "database error: {err}"),
+            Error::Upstream(err) => write!(f, "upstream error: {err}"),
+            Error::Send(err) => write!(f, "channel error: {err}"),
+            Error::Io(err) => write!(f, "io error: {err}"),
+            Error::NotFound(what) => write!(f, "{what} not found"),
+            Error::Validation(reason) => write!(f, "{reason}"),
+            Error::Document(reason) => write!(f, "document error: {reason}"),
+            Error::Tool(reason) => write!(f, "tool error: {reason}"),
+            Error::OutputFormat(reason) => write!(f, "output format error: {reason}"),
+            Error::Auth(reason) => write!(f, "authentication error: {reason}"),
+        }
+    }
 }
 
+impl std::error::Error for Error {}
+
 pub type Result<T> = core::result::Result<T, Error>;
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::NotFound(_) => StatusCode::NOT_FOUND,
+            Error::Validation(_) | Error::Document(_) | Error::OutputFormat(_) => {
+                StatusCode::UNPROCESSABLE_ENTITY
+            }
+            Error::Upstream(_) => StatusCode::BAD_GATEWAY,
+            Error::Database(err) if is_busy(err) => StatusCode::SERVICE_UNAVAILABLE,
+            Error::Database(_)
+            | Error::Send(_)
+            | Error::Io(_)
+            | Error::Tool(_)
+            | Error::Auth(_) => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+
+    /// What users are told, details of server errors only go to the logs.
+    pub fn public_message(&self) -> String {
+        match self {
+            Error::NotFound(what) => {
+                let mut what = what.clone();
+                if let Some(first) = what.get_mut(0..1) {
+                    first.make_ascii_uppercase();
+                }
+                format!("{what} not found")
+            }
+            Error::Validation(reason) | Error::Document(reason) | Error::OutputFormat(reason) => {
+                reason.clone()
+            }
+            Error::Upstream(_) => "Ollama cannot be reached, try again later".to_string(),
+            Error::Database(err) if is_busy(err) => {
+                "The database is busy, try again in a moment".to_string()
+            }
+            Error::Database(_)
+            | Error::Send(_)
+            | Error::Io(_)
+            | Error::Tool(_)
+            | Error::Auth(_) => "Something went wrong on our side".to_string(),
+        }
+    }
+}
+
+/// SQLite is locked by another connection or the pool has no free connection.
+fn is_busy(err: &sqlx::Error) -> bool {
+    match err {
+        sqlx::Error::PoolTimedOut => true,
+        sqlx::Error::Database(err) => err
+            .code()
+            .and_then(|code| code.parse::<i32>().ok())
+            // primary result codes SQLITE_BUSY and SQLITE_LOCKED
+            .is_some_and(|code| matches!(code & 0xff, 5 | 6)),
+        _ => false,
+    }
+}
+
+/// Error of a response, rendered by [`render_errors`] in the format the client expects.
+#[derive(Debug, Clone)]
+struct ErrorReport {
+    message: String,
+}
+
+impl IntoResponse for Error {
+    fn into_response(self) -> Response {
+        let status = self.status();
+        if status.is_server_error() {
+            error!(?self, "request failed");
+        }
+        let message = self.public_message();
+        let mut response = (status, Json(json!({ "error": message }))).into_response();
+        response.extensions_mut().insert(ErrorReport { message });
+        response
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ErrorFormat {
+    Page,
+    /// swapped into the error toast of the page
+    Fragment,
+    Json,
+}
+
+impl ErrorFormat {
+    fn of(headers: &HeaderMap) -> Self {
+        if headers.contains_key("HX-Request") {
+            return Self::Fragment;
+        }
+        let accepts_html = headers
+            .get(header::ACCEPT)
+            .and_then(|accept| accept.to_str().ok())
+            .is_some_and(|accept| accept.contains("text/html"));
+        if accepts_html {
+            Self::Page
+        } else {
+            Self::Json
+        }
+    }
+}
+
+/// Renders errors of handlers, and plain text rejections of extractors, as an error page for
+/// browsers, a fragment for HTMX requests and JSON for API clients.
+pub async fn render_errors(request: Request, next: Next) -> Response {
+    let format = ErrorFormat::of(request.headers());
+    let response = next.run(request).await;
+    let status = response.status();
+    if !status.is_client_error() && !status.is_server_error() {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let message = match parts.extensions.remove::<ErrorReport>() {
+        Some(report) => report.message,
+        None => {
+            let is_text = parts
+                .headers
+                .get(header::CONTENT_TYPE)
+                .and_then(|content_type| content_type.to_str().ok())
+                .is_none_or(|content_type| content_type.starts_with("text/plain"));
+            if !is_text {
+                // the handler already rendered the error
+                return Response::from_parts(parts, body);
+            }
+            let reason = axum::body::to_bytes(body, MAX_REASON_BYTES)
+                .await
+                .unwrap_or_default();
+            let reason = String::from_utf8_lossy(&reason).trim().to_string();
+            if reason.is_empty() {
+                status.canonical_reason().unwrap_or("Error").to_string()
+            } else {
+                reason
+            }
+        }
+    };
+
+    let mut headers = parts.headers;
+    headers.remove(header::CONTENT_TYPE);
+    headers.remove(header::CONTENT_LENGTH);
+    let mut response = match format {
+        ErrorFormat::Page => ErrorPage {
+            status: status.as_u16(),
+            title: status.canonical_reason().unwrap_or("Error").to_string(),
+            message,
+        }
+        .into_response(),
+        ErrorFormat::Fragment => {
+            headers.insert("HX-Retarget", HeaderValue::from_static("#error-toast"));
+            headers.insert("HX-Reswap", HeaderValue::from_static("beforeend"));
+            ErrorFragment { message }.into_response()
+        }
+        ErrorFormat::Json => Json(json!({ "error": message })).into_response(),
+    };
+    *response.status_mut() = status;
+    response.headers_mut().extend(headers);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_status_and_public_message() {
+        // given:
+        let errors = [
+            Error::NotFound("conversation".to_string()),
+            Error::Validation("name cannot be empty".to_string()),
+            Error::Database(sqlx::Error::PoolTimedOut),
+            Error::Database(sqlx::Error::RowNotFound),
+        ];
+
+        // when:
+        let responses: Vec<(StatusCode, String)> = errors
+            .iter()
+            .map(|err| (err.status(), err.public_message()))
+            .collect();
+
+        // then:
+        assert_eq!(
+            responses,
+            vec![
+                (StatusCode::NOT_FOUND, "Conversation not found".to_string()),
+                (
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    "name cannot be empty".to_string()
+                ),
+                (
+                    StatusCode::SERVICE_UNAVAILABLE,
+                    "The database is busy, try again in a moment".to_string()
+                ),
+                (
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "Something went wrong on our side".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_error_format_of_request() {
+        // given:
+        let mut htmx = HeaderMap::new();
+        htmx.insert("HX-Request", HeaderValue::from_static("true"));
+        htmx.insert(header::ACCEPT, HeaderValue::from_static("text/html"));
+        let mut browser = HeaderMap::new();
+        browser.insert(
+            header::ACCEPT,
+            HeaderValue::from_static("text/html,application/xhtml+xml"),
+        );
+        let api = HeaderMap::new();
+
+        // when:
+        let formats = [&htmx, &browser, &api].map(ErrorFormat::of);
+
+        // then:
+        assert_eq!(
+            formats,
+            [ErrorFormat::Fragment, ErrorFormat::Page, ErrorFormat::Json]
+        );
+    }
+}
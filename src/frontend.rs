@@ -1,25 +1,137 @@
-// TODO: handle errors by implementing intoresponse for my db error
 pub(crate) mod templates {
     use askama::Template;
+    use uuid::Uuid;
 
-    use crate::models;
+    use crate::{charts::BarChart, models};
 
     #[derive(Template)]
     #[template(path = "index.html")]
     pub(super) struct Index {
-        pub(super) conversations: Vec<models::Conversation>,
+        pub(super) user: models::User,
+        pub(super) tags: Vec<models::Tag>,
+        pub(super) sidebar_conversations: SidebarConversations,
     }
 
     #[derive(Template)]
     #[template(path = "conversation.html")]
     pub(super) struct Conversation {
-        pub(super) conversations: Vec<models::Conversation>,
-        pub(super) messages: Vec<models::Message>,
+        pub(super) user: models::User,
+        pub(super) tags: Vec<models::Tag>,
+        pub(super) sidebar_conversations: SidebarConversations,
+        pub(super) conversation_id: Uuid,
+        pub(super) conversation_tags: Vec<models::Tag>,
+        pub(super) system_prompt: Option<String>,
+        pub(super) documents: Vec<models::Document>,
+        pub(super) output_format: Option<String>,
+        pub(super) comparison_models: Vec<String>,
+        pub(super) share_links: Vec<models::ShareLink>,
+        pub(super) message_groups: Vec<models::MessageGroup>,
+        pub(super) older_url: Option<String>,
+        pub(super) newer_url: Option<String>,
+    }
+
+    impl Index {
+        pub(super) fn ws_path(&self) -> String {
+            "/ws".to_string()
+        }
+
+        pub(super) fn sse_fallback_path(&self) -> String {
+            "/api/prompt/sse".to_string()
+        }
+    }
+
+    impl Conversation {
+        /// Subscribes the page to updates of the conversation made in other tabs.
+        pub(super) fn ws_path(&self) -> String {
+            format!("/ws?conversation_id={}", self.conversation_id)
+        }
+
+        pub(super) fn sse_fallback_path(&self) -> String {
+            format!("/api/prompt/sse?conversation_id={}", self.conversation_id)
+        }
+    }
+
+    #[derive(Template)]
+    #[template(path = "shared.html")]
+    pub(super) struct Shared {
+        pub(super) conversation: models::Conversation,
+        pub(super) attachments_path: String,
+        pub(super) message_groups: Vec<models::MessageGroup>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "document.html")]
+    pub(super) struct Document {
+        pub(super) document: models::Document,
+        pub(super) chunks: Vec<models::DocumentChunk>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "login.html")]
+    pub(super) struct Login {
+        pub(super) username: String,
+        pub(super) error: Option<String>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "setup.html")]
+    pub(super) struct Setup {
+        pub(super) username: String,
+        pub(super) error: Option<String>,
     }
 
     #[derive(Template)]
-    #[template(path = "not_found.html")]
-    pub(super) struct NotFound;
+    #[template(path = "users.html")]
+    pub(super) struct Users {
+        pub(super) users: Vec<models::User>,
+        pub(super) error: Option<String>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "usage.html")]
+    pub(super) struct Usage {
+        pub(super) users: Vec<(models::User, crate::limits::Usage)>,
+        pub(super) requests_per_minute: usize,
+        pub(super) max_streams: usize,
+        pub(super) daily_tokens: i64,
+    }
+
+    #[derive(Template)]
+    #[template(path = "tokens.html")]
+    pub(super) struct Tokens {
+        pub(super) api_tokens: Vec<models::ApiToken>,
+        /// plain token, shown once right after it was created
+        pub(super) new_token: Option<String>,
+        pub(super) error: Option<String>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "error.html")]
+    pub(crate) struct ErrorPage {
+        pub status: u16,
+        pub title: String,
+        pub message: String,
+    }
+
+    #[derive(Template)]
+    #[template(path = "error_fragment.html")]
+    pub(crate) struct ErrorFragment {
+        pub message: String,
+    }
+
+    #[derive(Template)]
+    #[template(path = "folder.html")]
+    pub(super) struct FolderPage {
+        pub(super) folder: models::Folder,
+        pub(super) saved: bool,
+    }
+
+    #[derive(Template)]
+    #[template(path = "stats.html")]
+    pub(super) struct Stats {
+        pub(super) stats: models::UsageStats,
+        pub(super) charts: Vec<BarChart>,
+    }
 
     #[derive(Template)]
     #[template(path = "chat_area/append_message.html")]
@@ -33,9 +145,140 @@ pub(crate) mod templates {
         pub message: models::Message,
     }
 
+    #[derive(Template)]
+    #[template(path = "chat_area/append_comparison.html")]
+    pub(crate) struct ChatAreaAppendComparison {
+        pub group: models::MessageGroup,
+    }
+
+    #[derive(Template)]
+    #[template(path = "chat_area/swap_comparison.html")]
+    pub(crate) struct ChatAreaComparison {
+        pub group: models::MessageGroup,
+    }
+
+    #[derive(Template)]
+    #[template(path = "chat_area/feedback.html")]
+    pub(crate) struct ChatAreaFeedback {
+        pub message_id: Uuid,
+        pub feedback: Option<models::Feedback>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "chat_area/older_messages.html")]
+    pub(crate) struct ChatAreaOlderMessages {
+        pub message_groups: Vec<models::MessageGroup>,
+        /// loads the preceding page once revealed, `None` at the start of the conversation
+        pub older_url: Option<String>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "chat_area/newer_messages.html")]
+    pub(crate) struct ChatAreaNewerMessages {
+        pub message_groups: Vec<models::MessageGroup>,
+        /// loads the following page once revealed, `None` at the end of the conversation
+        pub newer_url: Option<String>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "chat_area/queue_position.html")]
+    pub(crate) struct ChatAreaQueuePosition {
+        pub message_id: Uuid,
+        pub position: usize,
+    }
+
+    #[derive(Template)]
+    #[template(path = "chat_area/prompt_error.html")]
+    pub(crate) struct ChatAreaPromptError {
+        pub error: String,
+    }
+
+    /// Prompt sending through a POST request and streaming answers as server-sent events.
+    #[derive(Template)]
+    #[template(path = "chat_area/sse_prompt.html")]
+    pub(crate) struct ChatAreaSsePrompt {
+        pub sse_path: String,
+    }
+
+    #[derive(Template)]
+    #[template(path = "chat_area/attachment_previews.html")]
+    pub(crate) struct ChatAreaAttachmentPreviews {
+        pub attachment_ids: Vec<Uuid>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "documents/documents.html")]
+    pub(crate) struct DocumentsList {
+        pub documents: Vec<models::Document>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "share/links.html")]
+    pub(crate) struct ShareLinksList {
+        pub share_links: Vec<models::ShareLink>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "output_format/panel.html")]
+    pub(crate) struct OutputFormatPanel {
+        pub conversation_id: Uuid,
+        pub output_format: Option<String>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "comparison/panel.html")]
+    pub(crate) struct ComparisonPanel {
+        pub conversation_id: Uuid,
+        pub comparison_models: Vec<String>,
+    }
+
     #[derive(Template)]
     #[template(path = "sidebar/new_conversation_form.html")]
-    pub(crate) struct SidebarNewConversationForm;
+    pub(crate) struct SidebarNewConversationForm {
+        pub folder_id: Option<Uuid>,
+        pub conversation_name: String,
+        pub error: Option<String>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "sidebar/new_folder_form.html")]
+    pub(crate) struct SidebarNewFolderForm;
+
+    #[derive(Template)]
+    #[template(path = "sidebar/conversations.html")]
+    pub(crate) struct SidebarConversations {
+        pub folders: Vec<models::Folder>,
+        pub tag_id: Option<Uuid>,
+        /// first page of the conversations outside of folders
+        pub page: SidebarPage,
+    }
+
+    /// Sent to every tab of the user when the sidebar changes, makes them reload their conversations.
+    #[derive(Template)]
+    #[template(path = "sidebar/refresh.html")]
+    pub(crate) struct SidebarRefresh;
+
+    #[derive(Template)]
+    #[template(path = "sidebar/page.html")]
+    pub(crate) struct SidebarPage {
+        pub recency_groups: Vec<models::RecencyGroup>,
+        /// loads the following page once revealed, `None` on the last one
+        pub next_url: Option<String>,
+    }
+
+    #[derive(Template)]
+    #[template(path = "folder/form.html")]
+    pub(crate) struct FolderForm {
+        pub folder: models::Folder,
+        pub saved: bool,
+    }
+
+    #[derive(Template)]
+    #[template(path = "tags/panel.html")]
+    pub(crate) struct TagsPanel {
+        pub conversation_id: Uuid,
+        pub conversation_tags: Vec<models::Tag>,
+    }
 
     #[derive(Template)]
     #[template(path = "sidebar/conversation.html")]
@@ -49,109 +292,1275 @@ pub mod handlers {
     use askama_axum::IntoResponse;
     use axum::{
         body::Body,
-        extract::{Path, State},
+        extract::{Multipart, Path, Query, State},
         response::{Redirect, Response},
-        Form,
+        Extension, Form, Json,
     };
-    use http::{HeaderMap, HeaderValue, StatusCode};
+    use axum_extra::extract::cookie::CookieJar;
+    use chrono::{Duration, TimeDelta, Utc};
+    use http::{header, HeaderMap, HeaderValue, StatusCode};
     use serde::Deserialize;
     use sqlx::SqlitePool;
-    use tracing::error;
     use uuid::Uuid;
 
-    use crate::{config::CONFIG, db, models, state::AppState};
+    use std::sync::Arc;
+
+    use crate::{
+        auth,
+        charts::BarChart,
+        config::CONFIG,
+        db,
+        error::Error,
+        hub::{Hub, Topic},
+        json_mode::OutputFormat,
+        limits, models, rag,
+        state::AppState,
+        validation,
+    };
+
+    pub const MAX_ATTACHMENTS_UPLOAD_BYTES: usize = 20 * 1024 * 1024;
+    pub const MAX_DOCUMENTS_UPLOAD_BYTES: usize = 50 * 1024 * 1024;
+    const STATS_DAYS: i64 = 30;
+    const STATS_BUSIEST_CONVERSATIONS: i64 = 10;
+
+    pub async fn index(
+        state: State<AppState>,
+        Extension(user): Extension<models::User>,
+    ) -> crate::error::Result<Response> {
+        let tags = db::get_tags(state.sqlite.clone(), user.id).await?;
+        let sidebar_conversations = sidebar(state.sqlite.clone(), user.id, None).await?;
+        Ok(Index {
+            user,
+            tags,
+            sidebar_conversations,
+        }
+        .into_response())
+    }
+
+    const SIDEBAR_PAGE_SIZE: usize = 30;
+
+    /// Folders and the first page of conversations outside of them, optionally only the tagged ones.
+    async fn sidebar(
+        sqlite: SqlitePool,
+        user_id: Uuid,
+        tag_id: Option<Uuid>,
+    ) -> crate::error::Result<SidebarConversations> {
+        let folders = db::get_folders(sqlite.clone(), user_id).await?;
+        let page = sidebar_page_after(sqlite, user_id, None, tag_id, None).await?;
+        Ok(SidebarConversations {
+            folders,
+            tag_id,
+            page,
+        })
+    }
+
+    async fn sidebar_page_after(
+        sqlite: SqlitePool,
+        user_id: Uuid,
+        folder_id: Option<Uuid>,
+        tag_id: Option<Uuid>,
+        after: Option<models::Cursor>,
+    ) -> crate::error::Result<SidebarPage> {
+        // one extra row tells whether there is a next page
+        let mut conversations = db::get_conversations(
+            sqlite,
+            user_id,
+            folder_id,
+            tag_id,
+            after,
+            SIDEBAR_PAGE_SIZE as i64 + 1,
+        )
+        .await?;
+        let next = if conversations.len() > SIDEBAR_PAGE_SIZE {
+            conversations.truncate(SIDEBAR_PAGE_SIZE);
+            conversations.last().map(models::Conversation::cursor)
+        } else {
+            None
+        };
+        let next_url = next.map(|cursor| {
+            let mut url = format!("/api/sidebar/page?after={cursor}");
+            if let Some(folder_id) = folder_id {
+                url.push_str(&format!("&folder_id={folder_id}"));
+            }
+            if let Some(tag_id) = tag_id {
+                url.push_str(&format!("&tag={tag_id}"));
+            }
+            url
+        });
+
+        let now = Utc::now();
+        let previous = after.map(|cursor| models::Recency::of(cursor.at, now));
+        Ok(SidebarPage {
+            recency_groups: models::group_by_recency(conversations, now, previous),
+            next_url,
+        })
+    }
 
-    pub async fn index(state: State<AppState>) -> impl IntoResponse {
-        let conversations = db::get_conversations(state.sqlite.clone()).await.unwrap();
-        Index { conversations }
+    #[derive(Deserialize)]
+    pub struct ConversationQuery {
+        /// deep link to a message of the conversation
+        message: Option<Uuid>,
     }
 
     pub async fn conversation(
         State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
         Path(conversation_id): Path<String>,
-    ) -> Response {
-        let conversation_id = match Uuid::parse_str(&conversation_id) {
-            Ok(id) => id,
-            Err(_) => return Redirect::permanent("/not_found").into_response(),
+        Query(query): Query<ConversationQuery>,
+    ) -> crate::error::Result<Response> {
+        let Ok(conversation_id) = Uuid::parse_str(&conversation_id) else {
+            return Err(Error::NotFound("conversation".to_string()));
         };
+        owns_conversation(sqlite.clone(), conversation_id, &user).await?;
+        let tags = db::get_tags(sqlite.clone(), user.id).await?;
+        let sidebar_conversations = sidebar(sqlite.clone(), user.id, None).await?;
+        let conversation_tags = db::get_conversation_tags(sqlite.clone(), conversation_id).await?;
 
-        let conversations = db::get_conversations(sqlite.clone()).await.unwrap();
-        if !conversations
-            .iter()
-            .map(|c| c.id)
-            .collect::<Vec<Uuid>>()
-            .contains(&conversation_id)
-        {
-            return Redirect::permanent("/not_found").into_response();
+        let documents = db::get_conversation_documents(sqlite.clone(), conversation_id).await?;
+        let settings = db::get_conversation_settings(sqlite.clone(), conversation_id).await?;
+        let output_format = settings
+            .as_ref()
+            .and_then(|settings| settings.output_format.clone());
+        let system_prompt = settings
+            .as_ref()
+            .and_then(|settings| settings.system_prompt.clone());
+        let comparison_models = settings
+            .and_then(|settings| settings.comparison_models)
+            .map(|models| models.0)
+            .unwrap_or_default();
+        let share_links = db::get_share_links(sqlite.clone(), conversation_id).await?;
+        let target = match query.message {
+            Some(message_id) => db::get_message(sqlite.clone(), message_id, user.id)
+                .await?
+                .filter(|message| message.conversation_id == conversation_id),
+            None => None,
+        };
+        let page = match target {
+            // a deep link loads the page around the message, both directions load on scrolling
+            Some(target) => {
+                let older = db::get_conversation_messages_page(
+                    sqlite.clone(),
+                    conversation_id,
+                    Some(target.cursor()),
+                    MESSAGES_PAGE_SIZE / 2,
+                )
+                .await?;
+                let mut newer = db::get_conversation_messages_page_after(
+                    sqlite,
+                    conversation_id,
+                    older.messages.last().map(models::Message::cursor),
+                    MESSAGES_PAGE_SIZE / 2,
+                )
+                .await?;
+                let mut messages = older.messages;
+                messages.append(&mut newer.messages);
+                models::MessagePage {
+                    messages,
+                    older: older.older,
+                    newer: newer.newer,
+                }
+            }
+            None => {
+                db::get_conversation_messages_page(
+                    sqlite,
+                    conversation_id,
+                    None,
+                    MESSAGES_PAGE_SIZE,
+                )
+                .await?
+            }
+        };
+
+        Ok(Conversation {
+            user,
+            tags,
+            sidebar_conversations,
+            conversation_id,
+            conversation_tags,
+            system_prompt,
+            documents,
+            output_format,
+            comparison_models,
+            share_links,
+            message_groups: models::group_messages(page.messages),
+            older_url: messages_url(conversation_id, "before", page.older),
+            newer_url: messages_url(conversation_id, "after", page.newer),
         }
+        .into_response())
+    }
 
-        let messages = db::get_conversation_messages(sqlite, conversation_id)
-            .await
-            .unwrap();
+    const MESSAGES_PAGE_SIZE: i64 = 50;
 
-        Conversation {
-            conversations,
-            messages,
+    fn messages_url(
+        conversation_id: Uuid,
+        direction: &str,
+        cursor: Option<models::Cursor>,
+    ) -> Option<String> {
+        cursor.map(|cursor| {
+            format!("/api/conversations/{conversation_id}/messages?{direction}={cursor}")
+        })
+    }
+
+    /// Exactly one of the cursors is given.
+    #[derive(Deserialize)]
+    pub struct MessagesQuery {
+        before: Option<String>,
+        after: Option<String>,
+    }
+
+    /// Page of the conversation history preceding or following the one shown.
+    pub async fn messages_page(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(conversation_id): Path<Uuid>,
+        Query(query): Query<MessagesQuery>,
+    ) -> crate::error::Result<Response> {
+        owns_conversation(sqlite.clone(), conversation_id, &user).await?;
+        let parse = |cursor: &str| cursor.parse::<models::Cursor>().map_err(Error::Validation);
+
+        match (query.before, query.after) {
+            (Some(before), None) => {
+                let page = db::get_conversation_messages_page(
+                    sqlite,
+                    conversation_id,
+                    Some(parse(&before)?),
+                    MESSAGES_PAGE_SIZE,
+                )
+                .await?;
+                Ok(ChatAreaOlderMessages {
+                    message_groups: models::group_messages(page.messages),
+                    older_url: messages_url(conversation_id, "before", page.older),
+                }
+                .into_response())
+            }
+            (None, Some(after)) => {
+                let page = db::get_conversation_messages_page_after(
+                    sqlite,
+                    conversation_id,
+                    Some(parse(&after)?),
+                    MESSAGES_PAGE_SIZE,
+                )
+                .await?;
+                Ok(ChatAreaNewerMessages {
+                    message_groups: models::group_messages(page.messages),
+                    newer_url: messages_url(conversation_id, "after", page.newer),
+                }
+                .into_response())
+            }
+            _ => Err(Error::Validation(
+                "either before or after must be given".to_string(),
+            )),
         }
-        .into_response()
     }
 
-    pub async fn not_found() -> impl IntoResponse {
-        NotFound
+    #[derive(Deserialize)]
+    pub struct SsePromptQuery {
+        conversation_id: Option<Uuid>,
     }
 
-    pub async fn sidebar_new_conversation_form() -> impl IntoResponse {
-        SidebarNewConversationForm
+    /// Prompt for clients which cannot open a websocket.
+    pub async fn sse_prompt(Query(query): Query<SsePromptQuery>) -> Response {
+        let sse_path = match query.conversation_id {
+            Some(conversation_id) => format!("/sse?conversation_id={conversation_id}"),
+            None => "/sse".to_string(),
+        };
+        ChatAreaSsePrompt { sse_path }.into_response()
+    }
+
+    /// Read-only view of a shared conversation, without the owner's sidebar and the prompt.
+    pub async fn shared_conversation(
+        State(sqlite): State<SqlitePool>,
+        Path(token): Path<String>,
+    ) -> crate::error::Result<Response> {
+        let conversation =
+            db::get_shared_conversation(sqlite.clone(), auth::hash_token(&token), Utc::now())
+                .await?
+                .ok_or_else(|| Error::NotFound("shared conversation".to_string()))?;
+        let messages = db::get_conversation_messages(sqlite, conversation.id).await?;
+
+        Ok((
+            [
+                (header::REFERRER_POLICY, "no-referrer"),
+                (header::HeaderName::from_static("x-robots-tag"), "noindex"),
+            ],
+            Shared {
+                conversation,
+                attachments_path: format!("/s/{token}/attachments"),
+                message_groups: models::group_messages(messages),
+            },
+        )
+            .into_response())
+    }
+
+    pub async fn document(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(document_id): Path<Uuid>,
+    ) -> crate::error::Result<Response> {
+        let document = db::get_document(sqlite.clone(), document_id, user.id)
+            .await?
+            .ok_or_else(|| Error::NotFound("document".to_string()))?;
+        let chunks = db::get_document_chunks(sqlite, document_id).await?;
+
+        Ok(Document { document, chunks }.into_response())
+    }
+
+    async fn usage_stats(
+        sqlite: SqlitePool,
+        user_id: Uuid,
+    ) -> crate::error::Result<models::UsageStats> {
+        let since = Utc::now() - Duration::days(STATS_DAYS);
+        Ok(models::UsageStats {
+            daily: db::get_daily_usage(sqlite.clone(), user_id, since).await?,
+            models: db::get_model_usage(sqlite.clone(), user_id).await?,
+            busiest_conversations: db::get_busiest_conversations(
+                sqlite,
+                user_id,
+                STATS_BUSIEST_CONVERSATIONS,
+            )
+            .await?,
+        })
+    }
+
+    pub async fn stats(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+    ) -> crate::error::Result<Response> {
+        let stats = usage_stats(sqlite, user.id).await?;
+
+        // show MM-DD to keep 30 labels readable
+        let daily_label = |day: &str| day.get(5..).unwrap_or(day).to_string();
+        let charts = vec![
+            BarChart::new(
+                "Messages per day",
+                "messages",
+                stats
+                    .daily
+                    .iter()
+                    .map(|d| (daily_label(&d.day), d.messages as f64))
+                    .collect(),
+            ),
+            BarChart::new(
+                "Tokens generated per day",
+                "tokens",
+                stats
+                    .daily
+                    .iter()
+                    .map(|d| (daily_label(&d.day), d.tokens_generated as f64))
+                    .collect(),
+            ),
+            BarChart::new(
+                "Average generation speed per model",
+                "tokens/s",
+                stats
+                    .models
+                    .iter()
+                    .filter_map(|m| Some((m.llm_model.clone(), m.avg_tokens_per_second?)))
+                    .collect(),
+            ),
+            BarChart::new(
+                "Average time to first token per model",
+                "ms",
+                stats
+                    .models
+                    .iter()
+                    .filter_map(|m| Some((m.llm_model.clone(), m.avg_time_to_first_token_ms?)))
+                    .collect(),
+            ),
+            BarChart::new(
+                "Comparison wins per model",
+                "wins",
+                stats
+                    .models
+                    .iter()
+                    .filter(|m| m.comparisons > 0)
+                    .map(|m| (m.llm_model.clone(), m.wins as f64))
+                    .collect(),
+            ),
+        ];
+
+        Ok(Stats { stats, charts }.into_response())
+    }
+
+    pub async fn api_stats(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+    ) -> crate::error::Result<Response> {
+        Ok(Json(usage_stats(sqlite, user.id).await?).into_response())
+    }
+
+    pub async fn not_found() -> Error {
+        Error::NotFound("page".to_string())
+    }
+
+    #[derive(Deserialize)]
+    pub struct SidebarQuery {
+        /// empty when the filter is cleared
+        #[serde(default)]
+        tag: String,
+    }
+
+    pub async fn sidebar_conversations(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Query(query): Query<SidebarQuery>,
+    ) -> crate::error::Result<Response> {
+        let tag_id = Uuid::parse_str(&query.tag).ok();
+        Ok(sidebar(sqlite, user.id, tag_id).await?.into_response())
+    }
+
+    /// Lets other tabs and devices of the user know their sidebar is out of date.
+    fn refresh_sidebar(hub: &Hub, user_id: Uuid) {
+        hub.publish(Topic::Sidebar(user_id), SidebarRefresh.to_string());
+    }
+
+    #[derive(Deserialize)]
+    pub struct SidebarPageQuery {
+        /// conversations outside of folders when not set
+        folder_id: Option<Uuid>,
+        #[serde(default)]
+        tag: String,
+        after: Option<String>,
+    }
+
+    pub async fn sidebar_page(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Query(query): Query<SidebarPageQuery>,
+    ) -> crate::error::Result<Response> {
+        let after = query
+            .after
+            .as_deref()
+            .map(str::parse)
+            .transpose()
+            .map_err(Error::Validation)?;
+        let tag_id = Uuid::parse_str(&query.tag).ok();
+        let page = sidebar_page_after(sqlite, user.id, query.folder_id, tag_id, after).await?;
+        Ok(page.into_response())
+    }
+
+    #[derive(Deserialize)]
+    pub struct NewConversationFormQuery {
+        folder_id: Option<Uuid>,
+    }
+
+    pub async fn sidebar_new_conversation_form(
+        Query(query): Query<NewConversationFormQuery>,
+    ) -> impl IntoResponse {
+        SidebarNewConversationForm {
+            folder_id: query.folder_id,
+            conversation_name: String::new(),
+            error: None,
+        }
     }
 
-    // TODO: add validation, e.g. cannot be empty string
     #[derive(Deserialize, Debug)]
     pub struct NewConversationForm {
         pub conversation_name: String,
+        #[serde(default)]
+        pub folder_id: Option<Uuid>,
     }
 
     pub async fn create_conversation(
         State(sqlite): State<SqlitePool>,
+        State(hub): State<Arc<Hub>>,
+        Extension(user): Extension<models::User>,
         Form(new_conversation_form): Form<NewConversationForm>,
-    ) -> impl IntoResponse {
-        let new_conversation = models::Conversation::new(new_conversation_form.conversation_name);
-        // TODO: implement into response for my error
+    ) -> crate::error::Result<Response> {
+        let name = match validation::conversation_name(
+            &new_conversation_form.conversation_name,
+            CONFIG.lokai_max_conversation_name_length,
+        ) {
+            Ok(name) => name,
+            // the form is swapped back with the error next to the name
+            Err(err) => {
+                return Ok((
+                    StatusCode::UNPROCESSABLE_ENTITY,
+                    SidebarNewConversationForm {
+                        folder_id: new_conversation_form.folder_id,
+                        conversation_name: new_conversation_form.conversation_name,
+                        error: Some(err.to_string()),
+                    },
+                )
+                    .into_response())
+            }
+        };
+        let mut new_conversation = models::Conversation::new(name, user.id);
+        new_conversation.folder_id = new_conversation_form.folder_id;
         // TODO: read global default LLM model from db
         let new_conversation = db::create_conversation(
             sqlite,
             new_conversation,
             CONFIG.lokai_default_llm_model.clone(),
         )
-        .await
-        .unwrap();
+        .await?;
+        refresh_sidebar(&hub, user.id);
 
-        let mut headers = HeaderMap::new();
-        headers.insert(
-            "HX-Redirect",
-            HeaderValue::from_str(&format!("/c/{:?}", &new_conversation.id)).unwrap(),
-        );
-
-        (
-            headers,
+        Ok((
+            [("HX-Redirect", format!("/c/{}", new_conversation.id))],
             SidebarConversation {
                 conversation: new_conversation,
             },
         )
+            .into_response())
+    }
+
+    pub async fn upload_attachments(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        mut multipart: Multipart,
+    ) -> crate::error::Result<Response> {
+        let mut attachment_ids = Vec::new();
+        loop {
+            let field = match multipart.next_field().await {
+                Ok(Some(field)) => field,
+                Ok(None) => break,
+                Err(err) => return Ok((StatusCode::BAD_REQUEST, err.body_text()).into_response()),
+            };
+            if field.name() != Some("images") {
+                continue;
+            }
+            let mime_type = match field.content_type() {
+                Some(mime_type) if mime_type.starts_with("image/") => mime_type.to_string(),
+                _ => return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response()),
+            };
+            let data = match field.bytes().await {
+                Ok(data) => data.to_vec(),
+                Err(err) => return Ok((StatusCode::BAD_REQUEST, err.body_text()).into_response()),
+            };
+            let attachment = models::Attachment::new(mime_type, data, user.id);
+            let attachment = db::create_attachment(sqlite.clone(), attachment).await?;
+            attachment_ids.push(attachment.id);
+        }
+
+        Ok(ChatAreaAttachmentPreviews { attachment_ids }.into_response())
+    }
+
+    pub async fn attachment(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(attachment_id): Path<Uuid>,
+    ) -> crate::error::Result<Response> {
+        attachment_response(db::get_attachment(sqlite, attachment_id, user.id).await?)
+    }
+
+    pub async fn shared_attachment(
+        State(sqlite): State<SqlitePool>,
+        Path((token, attachment_id)): Path<(String, Uuid)>,
+    ) -> crate::error::Result<Response> {
+        attachment_response(
+            db::get_shared_attachment(sqlite, auth::hash_token(&token), attachment_id, Utc::now())
+                .await?,
+        )
+    }
+
+    fn attachment_response(
+        attachment: Option<models::Attachment>,
+    ) -> crate::error::Result<Response> {
+        let attachment = attachment.ok_or_else(|| Error::NotFound("attachment".to_string()))?;
+        Ok((
+            [
+                (header::CONTENT_TYPE, attachment.mime_type),
+                (
+                    header::CACHE_CONTROL,
+                    "private, max-age=31536000, immutable".to_string(),
+                ),
+            ],
+            attachment.data,
+        )
+            .into_response())
+    }
+
+    /// Checks that the conversation exists and belongs to the user.
+    async fn owns_conversation(
+        sqlite: SqlitePool,
+        conversation_id: Uuid,
+        user: &models::User,
+    ) -> crate::error::Result<()> {
+        if db::conversation_exists(sqlite, conversation_id, user.id).await? {
+            Ok(())
+        } else {
+            Err(Error::NotFound("conversation".to_string()))
+        }
+    }
+
+    pub async fn upload_documents(
+        State(state): State<AppState>,
+        Extension(user): Extension<models::User>,
+        Path(conversation_id): Path<Uuid>,
+        mut multipart: Multipart,
+    ) -> crate::error::Result<Response> {
+        owns_conversation(state.sqlite.clone(), conversation_id, &user).await?;
+
+        let mut documents = Vec::new();
+        loop {
+            let field = match multipart.next_field().await {
+                Ok(Some(field)) => field,
+                Ok(None) => break,
+                Err(err) => return Ok((StatusCode::BAD_REQUEST, err.body_text()).into_response()),
+            };
+            if field.name() != Some("documents") {
+                continue;
+            }
+            let name = field.file_name().unwrap_or("document").to_string();
+            let mime_type = field
+                .content_type()
+                .unwrap_or("application/octet-stream")
+                .to_string();
+            let Some(kind) = rag::DocumentKind::detect(&name, &mime_type) else {
+                return Ok(StatusCode::UNSUPPORTED_MEDIA_TYPE.into_response());
+            };
+            let data = match field.bytes().await {
+                Ok(data) => data.to_vec(),
+                Err(err) => return Ok((StatusCode::BAD_REQUEST, err.body_text()).into_response()),
+            };
+
+            let document = models::Document::new(name, mime_type, conversation_id);
+            let text = rag::extract_text(kind, data).await?;
+            let chunks = rag::embed_document(&state.reqwest_client, &document, &text).await?;
+            documents.push(db::create_document(state.sqlite.clone(), document, chunks).await?);
+        }
+
+        Ok(DocumentsList { documents }.into_response())
+    }
+
+    pub async fn delete_document(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(document_id): Path<Uuid>,
+    ) -> crate::error::Result<Response> {
+        let not_found = || Error::NotFound("document".to_string());
+        db::get_document(sqlite.clone(), document_id, user.id)
+            .await?
+            .ok_or_else(not_found)?;
+        db::delete_document(sqlite, document_id)
+            .await?
+            .ok_or_else(not_found)?;
+        Ok(Body::empty().into_response())
+    }
+
+    #[derive(Deserialize)]
+    pub struct OutputFormatForm {
+        mode: String,
+        #[serde(default)]
+        schema: String,
+    }
+
+    pub async fn update_output_format(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(conversation_id): Path<Uuid>,
+        Form(form): Form<OutputFormatForm>,
+    ) -> crate::error::Result<Response> {
+        owns_conversation(sqlite.clone(), conversation_id, &user).await?;
+        let output_format = match form.mode.as_str() {
+            "text" => None,
+            "json" => Some("json".to_string()),
+            "schema" => {
+                OutputFormat::parse(&form.schema)?;
+                Some(form.schema.trim().to_string())
+            }
+            _ => return Err(Error::Validation("unknown mode".to_string())),
+        };
+
+        let settings =
+            db::update_conversation_output_format(sqlite, conversation_id, output_format)
+                .await?
+                .ok_or_else(|| Error::NotFound("conversation settings".to_string()))?;
+        Ok(OutputFormatPanel {
+            conversation_id,
+            output_format: settings.output_format,
+        }
+        .into_response())
+    }
+
+    #[derive(Deserialize)]
+    pub struct ComparisonForm {
+        #[serde(default)]
+        models: String,
+    }
+
+    pub async fn update_comparison_models(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(conversation_id): Path<Uuid>,
+        Form(form): Form<ComparisonForm>,
+    ) -> crate::error::Result<Response> {
+        owns_conversation(sqlite.clone(), conversation_id, &user).await?;
+        let comparison_models =
+            models::parse_comparison_models(&form.models).map_err(Error::Validation)?;
+
+        let settings =
+            db::update_conversation_comparison_models(sqlite, conversation_id, comparison_models)
+                .await?
+                .ok_or_else(|| Error::NotFound("conversation settings".to_string()))?;
+        Ok(ComparisonPanel {
+            conversation_id,
+            comparison_models: settings
+                .comparison_models
+                .map(|models| models.0)
+                .unwrap_or_default(),
+        }
+        .into_response())
+    }
+
+    #[derive(Deserialize)]
+    pub struct ShareLinkForm {
+        /// empty for links which never expire
+        #[serde(default)]
+        expires_in_hours: String,
+    }
+
+    pub async fn create_share_link(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(conversation_id): Path<Uuid>,
+        Form(form): Form<ShareLinkForm>,
+    ) -> crate::error::Result<Response> {
+        owns_conversation(sqlite.clone(), conversation_id, &user).await?;
+        let expires_at = match form.expires_in_hours.trim() {
+            "" => None,
+            hours => Some(
+                hours
+                    .parse::<i64>()
+                    .ok()
+                    .filter(|hours| *hours > 0)
+                    .and_then(TimeDelta::try_hours)
+                    .and_then(|expires_in| Utc::now().checked_add_signed(expires_in))
+                    .ok_or_else(|| {
+                        Error::Validation("expiry must be a positive number of hours".to_string())
+                    })?,
+            ),
+        };
+
+        let token = auth::generate_token();
+        let share_link =
+            models::ShareLink::new(conversation_id, auth::hash_token(&token), expires_at);
+        let share_link = db::create_share_link(sqlite, share_link).await?;
+        Ok(ShareLinksList {
+            share_links: vec![models::ShareLink {
+                token: Some(token),
+                ..share_link
+            }],
+        }
+        .into_response())
+    }
+
+    pub async fn revoke_share_link(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(share_link_id): Path<Uuid>,
+    ) -> crate::error::Result<Response> {
+        db::delete_share_link(sqlite, share_link_id, user.id)
+            .await?
+            .ok_or_else(|| Error::NotFound("share link".to_string()))?;
+        Ok(Body::empty().into_response())
+    }
+
+    pub async fn pick_comparison_winner(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(message_id): Path<Uuid>,
+    ) -> crate::error::Result<Response> {
+        let not_found = || Error::NotFound("message".to_string());
+        db::get_message(sqlite.clone(), message_id, user.id)
+            .await?
+            .ok_or_else(not_found)?;
+        let messages = db::pick_comparison_winner(sqlite, message_id).await?;
+        let comparison_id = messages.first().ok_or_else(not_found)?.comparison_id;
+        Ok(ChatAreaComparison {
+            group: models::MessageGroup {
+                comparison_id,
+                messages,
+            },
+        }
+        .into_response())
+    }
+
+    #[derive(Deserialize)]
+    pub struct FeedbackForm {
+        rating: i64,
+        note: Option<String>,
+    }
+
+    pub async fn message_feedback(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(message_id): Path<Uuid>,
+        Form(form): Form<FeedbackForm>,
+    ) -> crate::error::Result<Response> {
+        if ![-1, 1].contains(&form.rating) {
+            return Err(Error::Validation("rating must be 1 or -1".to_string()));
+        }
+        let note = form
+            .note
+            .map(|note| note.trim().to_string())
+            .filter(|note| !note.is_empty());
+
+        // only answers of the models can be rated
+        db::get_message(sqlite.clone(), message_id, user.id)
+            .await?
+            .filter(|message| message.role == models::Role::Assistant)
+            .ok_or_else(|| Error::NotFound("message".to_string()))?;
+        let feedback = models::Feedback::new(message_id, form.rating, note);
+        let feedback = db::upsert_feedback(sqlite, feedback).await?;
+        Ok(ChatAreaFeedback {
+            message_id,
+            feedback: Some(feedback),
+        }
+        .into_response())
+    }
+
+    /// Rated answers as JSON Lines, one `{history, answer, rating}` example per line.
+    pub async fn export_feedback(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+    ) -> crate::error::Result<Response> {
+        let examples = db::get_feedback_examples(sqlite, user.id).await?;
+
+        let mut body = String::new();
+        for example in examples {
+            // SAFETY: examples consist of strings and numbers only
+            body.push_str(&serde_json::to_string(&example).unwrap());
+            body.push('\n');
+        }
+        Ok((
+            [
+                (header::CONTENT_TYPE, "application/x-ndjson"),
+                (
+                    header::CONTENT_DISPOSITION,
+                    "attachment; filename=\"feedback.jsonl\"",
+                ),
+            ],
+            body,
+        )
+            .into_response())
+    }
+
+    pub async fn sidebar_new_folder_form() -> impl IntoResponse {
+        SidebarNewFolderForm
+    }
+
+    #[derive(Deserialize)]
+    pub struct NewFolderForm {
+        folder_name: String,
+    }
+
+    pub async fn create_folder(
+        State(sqlite): State<SqlitePool>,
+        State(hub): State<Arc<Hub>>,
+        Extension(user): Extension<models::User>,
+        Form(form): Form<NewFolderForm>,
+    ) -> crate::error::Result<Response> {
+        let name = form.folder_name.trim();
+        if name.is_empty() {
+            return Err(Error::Validation("name cannot be empty".to_string()));
+        }
+        db::create_folder(
+            sqlite.clone(),
+            models::Folder::new(name.to_string(), user.id),
+        )
+        .await?;
+        let sidebar_conversations = sidebar(sqlite, user.id, None).await?;
+        refresh_sidebar(&hub, user.id);
+        Ok(sidebar_conversations.into_response())
+    }
+
+    pub async fn folder_page(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(folder_id): Path<Uuid>,
+    ) -> crate::error::Result<Response> {
+        let folder = db::get_folder(sqlite, folder_id, user.id)
+            .await?
+            .ok_or_else(|| Error::NotFound("folder".to_string()))?;
+        Ok(FolderPage {
+            folder,
+            saved: false,
+        }
+        .into_response())
+    }
+
+    #[derive(Deserialize)]
+    pub struct FolderSettingsForm {
+        name: String,
+        /// empty to use the global default model
+        #[serde(default)]
+        llm_model: String,
+        #[serde(default)]
+        system_prompt: String,
+    }
+
+    pub async fn update_folder(
+        State(sqlite): State<SqlitePool>,
+        State(hub): State<Arc<Hub>>,
+        Extension(user): Extension<models::User>,
+        Path(folder_id): Path<Uuid>,
+        Form(form): Form<FolderSettingsForm>,
+    ) -> crate::error::Result<Response> {
+        let name = form.name.trim();
+        if name.is_empty() {
+            return Err(Error::Validation("name cannot be empty".to_string()));
+        }
+        let non_empty = |value: &str| Some(value.trim().to_string()).filter(|v| !v.is_empty());
+        let mut folder = models::Folder::new(name.to_string(), user.id);
+        folder.id = folder_id;
+        folder.llm_model = non_empty(&form.llm_model);
+        folder.system_prompt = non_empty(&form.system_prompt);
+
+        let folder = db::update_folder(sqlite, folder)
+            .await?
+            .ok_or_else(|| Error::NotFound("folder".to_string()))?;
+        refresh_sidebar(&hub, user.id);
+        Ok(FolderForm {
+            folder,
+            saved: true,
+        }
+        .into_response())
+    }
+
+    pub async fn delete_folder(
+        State(sqlite): State<SqlitePool>,
+        State(hub): State<Arc<Hub>>,
+        Extension(user): Extension<models::User>,
+        Path(folder_id): Path<Uuid>,
+    ) -> crate::error::Result<Response> {
+        db::delete_folder(sqlite, folder_id, user.id)
+            .await?
+            .ok_or_else(|| Error::NotFound("folder".to_string()))?;
+        refresh_sidebar(&hub, user.id);
+        let mut headers = HeaderMap::new();
+        headers.insert("HX-Redirect", HeaderValue::from_static("/"));
+        Ok((headers, Body::empty()).into_response())
+    }
+
+    #[derive(Deserialize)]
+    pub struct MoveConversationForm {
+        /// empty moves the conversation out of its folder
+        #[serde(default)]
+        folder_id: String,
+        /// tag filter of the sidebar being re-rendered
+        #[serde(default)]
+        tag: String,
+    }
+
+    pub async fn move_conversation(
+        State(sqlite): State<SqlitePool>,
+        State(hub): State<Arc<Hub>>,
+        Extension(user): Extension<models::User>,
+        Path(conversation_id): Path<Uuid>,
+        Form(form): Form<MoveConversationForm>,
+    ) -> crate::error::Result<Response> {
+        let folder_id = match form.folder_id.as_str() {
+            "" => None,
+            folder_id => Some(
+                Uuid::parse_str(folder_id)
+                    .map_err(|_| Error::Validation("invalid folder".to_string()))?,
+            ),
+        };
+        let tag_id = Uuid::parse_str(&form.tag).ok();
+        db::move_conversation(sqlite.clone(), conversation_id, folder_id, user.id)
+            .await?
+            .ok_or_else(|| Error::NotFound("conversation".to_string()))?;
+        let sidebar_conversations = sidebar(sqlite, user.id, tag_id).await?;
+        refresh_sidebar(&hub, user.id);
+        Ok(sidebar_conversations.into_response())
+    }
+
+    const MAX_TAG_LENGTH: usize = 32;
+
+    #[derive(Deserialize)]
+    pub struct TagForm {
+        name: String,
+    }
+
+    pub async fn tag_conversation(
+        State(sqlite): State<SqlitePool>,
+        State(hub): State<Arc<Hub>>,
+        Extension(user): Extension<models::User>,
+        Path(conversation_id): Path<Uuid>,
+        Form(form): Form<TagForm>,
+    ) -> crate::error::Result<Response> {
+        owns_conversation(sqlite.clone(), conversation_id, &user).await?;
+        let name = form.name.trim().trim_start_matches('#');
+        if name.is_empty() || name.chars().count() > MAX_TAG_LENGTH {
+            return Err(Error::Validation(format!(
+                "tag must have between 1 and {MAX_TAG_LENGTH} characters"
+            )));
+        }
+
+        let tag = models::Tag::new(name.to_string(), user.id);
+        db::tag_conversation(sqlite.clone(), conversation_id, tag).await?;
+        tags_panel(sqlite, &hub, &user, conversation_id).await
+    }
+
+    pub async fn untag_conversation(
+        State(sqlite): State<SqlitePool>,
+        State(hub): State<Arc<Hub>>,
+        Extension(user): Extension<models::User>,
+        Path((conversation_id, tag_id)): Path<(Uuid, Uuid)>,
+    ) -> crate::error::Result<Response> {
+        owns_conversation(sqlite.clone(), conversation_id, &user).await?;
+        db::untag_conversation(sqlite.clone(), conversation_id, tag_id).await?;
+        tags_panel(sqlite, &hub, &user, conversation_id).await
+    }
+
+    async fn tags_panel(
+        sqlite: SqlitePool,
+        hub: &Hub,
+        user: &models::User,
+        conversation_id: Uuid,
+    ) -> crate::error::Result<Response> {
+        let conversation_tags = db::get_conversation_tags(sqlite, conversation_id).await?;
+        // tags are shown in the sidebar too
+        refresh_sidebar(hub, user.id);
+        Ok(TagsPanel {
+            conversation_id,
+            conversation_tags,
+        }
+        .into_response())
     }
 
     pub async fn delete_conversation(
         State(sqlite): State<SqlitePool>,
+        State(hub): State<Arc<Hub>>,
+        Extension(user): Extension<models::User>,
         Path(conversation_id): Path<Uuid>,
-    ) -> Response {
-        match db::delete_conversation(sqlite, conversation_id).await {
-            Ok(_) => Body::empty().into_response(),
-            Err(err) => {
-                error!(
-                    conversation_id = conversation_id.to_string(),
-                    "Error when deleting conversation: {:?}", err
-                );
-                StatusCode::INTERNAL_SERVER_ERROR.into_response()
+    ) -> crate::error::Result<Response> {
+        db::delete_conversation(sqlite, conversation_id, user.id)
+            .await?
+            .ok_or_else(|| Error::NotFound("conversation".to_string()))?;
+        refresh_sidebar(&hub, user.id);
+        Ok(Body::empty().into_response())
+    }
+
+    #[derive(Deserialize)]
+    pub struct CredentialsForm {
+        username: String,
+        password: String,
+    }
+
+    fn validate_credentials(form: &CredentialsForm) -> Option<String> {
+        validation::credentials(&form.username, &form.password)
+            .err()
+            .map(|err| err.to_string())
+    }
+
+    pub async fn login_page(State(sqlite): State<SqlitePool>) -> crate::error::Result<Response> {
+        if db::count_users(sqlite).await? == 0 {
+            return Ok(Redirect::to("/setup").into_response());
+        }
+        Ok(Login {
+            username: String::new(),
+            error: None,
+        }
+        .into_response())
+    }
+
+    pub async fn login(
+        State(state): State<AppState>,
+        jar: CookieJar,
+        Form(form): Form<CredentialsForm>,
+    ) -> crate::error::Result<Response> {
+        let username = form.username.trim().to_string();
+        let user = db::get_user_by_username(state.sqlite.clone(), &username).await?;
+        let user = match auth::check_credentials(user, &form.password) {
+            Some(user) => user,
+            None => {
+                return Ok((
+                    StatusCode::UNAUTHORIZED,
+                    Login {
+                        username,
+                        error: Some("Invalid username or password".to_string()),
+                    },
+                )
+                    .into_response())
             }
+        };
+
+        let jar = auth::login(&state, jar, &user).await?;
+        Ok((jar, Redirect::to("/")).into_response())
+    }
+
+    pub async fn logout(
+        State(state): State<AppState>,
+        jar: CookieJar,
+    ) -> crate::error::Result<Response> {
+        let jar = auth::logout(&state, jar).await?;
+        Ok((jar, Redirect::to("/login")).into_response())
+    }
+
+    pub async fn setup_page(State(sqlite): State<SqlitePool>) -> crate::error::Result<Response> {
+        if db::count_users(sqlite).await? > 0 {
+            return Ok(Redirect::to("/login").into_response());
+        }
+        Ok(Setup {
+            username: String::new(),
+            error: None,
+        }
+        .into_response())
+    }
+
+    /// First run, creates the admin account and logs it in.
+    pub async fn setup(
+        State(state): State<AppState>,
+        jar: CookieJar,
+        Form(form): Form<CredentialsForm>,
+    ) -> crate::error::Result<Response> {
+        let username = form.username.trim().to_string();
+        if let Some(error) = validate_credentials(&form) {
+            return Ok((
+                StatusCode::UNPROCESSABLE_ENTITY,
+                Setup {
+                    username,
+                    error: Some(error),
+                },
+            )
+                .into_response());
         }
+
+        let password_hash = auth::hash_password(&form.password)?;
+        let admin = models::User::new(username, password_hash, true);
+        let Some(admin) = db::create_first_admin(state.sqlite.clone(), admin).await? else {
+            // someone else finished the setup in the meantime
+            return Ok(Redirect::to("/login").into_response());
+        };
+
+        let jar = auth::login(&state, jar, &admin).await?;
+        Ok((jar, Redirect::to("/")).into_response())
+    }
+
+    async fn users_page(
+        sqlite: SqlitePool,
+        error: Option<String>,
+    ) -> crate::error::Result<Response> {
+        let users = db::get_users(sqlite).await?;
+        Ok(Users { users, error }.into_response())
+    }
+
+    pub async fn users(State(sqlite): State<SqlitePool>) -> crate::error::Result<Response> {
+        users_page(sqlite, None).await
+    }
+
+    /// Usage of every user against the limits, tokens are counted since midnight UTC.
+    pub async fn usage(State(state): State<AppState>) -> crate::error::Result<Response> {
+        let users = db::get_users(state.sqlite.clone()).await?;
+        let since = limits::start_of_day(Utc::now());
+        let tokens = db::get_tokens_used_since(state.sqlite.clone(), since).await?;
+
+        let now = tokio::time::Instant::now();
+        let limits = &state.limits;
+        Ok(Usage {
+            users: users
+                .into_iter()
+                .map(|user| {
+                    let tokens = tokens.get(&user.id).copied().unwrap_or_default();
+                    let usage = limits.usage(user.id, tokens, now);
+                    (user, usage)
+                })
+                .collect(),
+            requests_per_minute: limits.requests_per_minute,
+            max_streams: limits.max_streams,
+            daily_tokens: limits.daily_tokens,
+        }
+        .into_response())
+    }
+
+    #[derive(Deserialize)]
+    pub struct NewUserForm {
+        #[serde(flatten)]
+        credentials: CredentialsForm,
+        #[serde(default)]
+        is_admin: Option<String>,
+    }
+
+    pub async fn create_user(
+        State(sqlite): State<SqlitePool>,
+        Form(form): Form<NewUserForm>,
+    ) -> crate::error::Result<Response> {
+        if let Some(error) = validate_credentials(&form.credentials) {
+            return users_page(sqlite, Some(error)).await;
+        }
+        let username = form.credentials.username.trim().to_string();
+
+        if db::get_user_by_username(sqlite.clone(), &username)
+            .await?
+            .is_some()
+        {
+            let error = Some(format!("User {username} already exists"));
+            return users_page(sqlite, error).await;
+        }
+        let password_hash = auth::hash_password(&form.credentials.password)?;
+        let user = models::User::new(username, password_hash, form.is_admin.is_some());
+        db::create_user(sqlite.clone(), user).await?;
+        users_page(sqlite, None).await
+    }
+
+    async fn tokens_page(
+        sqlite: SqlitePool,
+        user_id: Uuid,
+        new_token: Option<String>,
+        error: Option<String>,
+    ) -> crate::error::Result<Response> {
+        let api_tokens = db::get_api_tokens(sqlite, user_id).await?;
+        Ok(Tokens {
+            api_tokens,
+            new_token,
+            error,
+        }
+        .into_response())
+    }
+
+    pub async fn tokens(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+    ) -> crate::error::Result<Response> {
+        tokens_page(sqlite, user.id, None, None).await
+    }
+
+    #[derive(Deserialize)]
+    pub struct NewTokenForm {
+        name: String,
+        read: Option<String>,
+        write: Option<String>,
+    }
+
+    pub async fn create_token(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Form(form): Form<NewTokenForm>,
+    ) -> crate::error::Result<Response> {
+        let name = form.name.trim().to_string();
+        let scopes: Vec<models::TokenScope> = [
+            (form.read, models::TokenScope::Read),
+            (form.write, models::TokenScope::Write),
+        ]
+        .into_iter()
+        .filter_map(|(checked, scope)| checked.map(|_| scope))
+        .collect();
+        if name.is_empty() {
+            let error = Some("Token name cannot be empty".to_string());
+            return tokens_page(sqlite, user.id, None, error).await;
+        }
+        if scopes.is_empty() {
+            let error = Some("Pick at least one scope".to_string());
+            return tokens_page(sqlite, user.id, None, error).await;
+        }
+
+        let token = auth::generate_token();
+        let api_token = models::ApiToken::new(user.id, name, auth::hash_token(&token), scopes);
+        db::create_api_token(sqlite.clone(), api_token).await?;
+        tokens_page(sqlite, user.id, Some(token), None).await
+    }
+
+    pub async fn revoke_token(
+        State(sqlite): State<SqlitePool>,
+        Extension(user): Extension<models::User>,
+        Path(api_token_id): Path<Uuid>,
+    ) -> crate::error::Result<Response> {
+        db::delete_api_token(sqlite, api_token_id, user.id)
+            .await?
+            .ok_or_else(|| Error::NotFound("api token".to_string()))?;
+        Ok(Body::empty().into_response())
     }
 }
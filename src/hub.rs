@@ -0,0 +1,123 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::broadcast;
+use uuid::Uuid;
+
+use crate::jobs::Progress;
+
+// fragments are full snapshots of a message, lagging clients can skip some of them
+const CHANNEL_CAPACITY: usize = 256;
+
+/// What a websocket client is watching.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Topic {
+    Conversation(Uuid),
+    /// sidebar of the user with the given id
+    Sidebar(Uuid),
+}
+
+/// Fans out HTML fragments to every client watching the same topic, e.g. the same conversation
+/// opened in several tabs or on several devices.
+#[derive(Default)]
+pub struct Hub {
+    channels: Mutex<HashMap<Topic, broadcast::Sender<String>>>,
+}
+
+impl Hub {
+    pub fn subscribe(&self, topic: Topic) -> broadcast::Receiver<String> {
+        let mut channels = self.channels.lock().unwrap();
+        // channels of disconnected clients
+        channels.retain(|_, sender| sender.receiver_count() > 0);
+        channels
+            .entry(topic)
+            .or_insert_with(|| broadcast::channel(CHANNEL_CAPACITY).0)
+            .subscribe()
+    }
+
+    /// Sends the fragment to current subscribers, it's dropped when nobody watches the topic.
+    pub fn publish(&self, topic: Topic, html: String) {
+        let mut channels = self.channels.lock().unwrap();
+        if let Some(sender) = channels.get(&topic) {
+            if sender.send(html).is_err() {
+                channels.remove(&topic);
+            }
+        }
+    }
+}
+
+/// Publishes to a single topic.
+#[derive(Clone)]
+pub struct Publisher {
+    hub: Arc<Hub>,
+    topic: Topic,
+    progress: Progress,
+}
+
+impl Publisher {
+    pub fn new(hub: Arc<Hub>, topic: Topic) -> Self {
+        Self {
+            hub,
+            topic,
+            progress: Progress::default(),
+        }
+    }
+
+    /// Keeps the latest fragment of unfinished messages in `progress`.
+    pub fn with_progress(self, progress: Progress) -> Self {
+        Self { progress, ..self }
+    }
+
+    pub fn send(&self, html: String) {
+        self.hub.publish(self.topic, html);
+    }
+
+    /// Sends a snapshot of a message which is still being generated.
+    pub fn send_progress(&self, message_id: Uuid, html: String) {
+        self.progress.update(message_id, html.clone());
+        self.send(html);
+    }
+
+    /// Sends the final snapshot of the message.
+    pub fn send_finished(&self, message_id: Uuid, html: String) {
+        self.progress.finish(message_id);
+        self.send(html);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_publish_reaches_subscribers_of_topic() {
+        // given:
+        let hub = Hub::default();
+        let conversation = Topic::Conversation(Uuid::new_v4());
+        let (mut first_tab, mut second_tab) =
+            (hub.subscribe(conversation), hub.subscribe(conversation));
+        let mut other = hub.subscribe(Topic::Conversation(Uuid::new_v4()));
+
+        // when:
+        hub.publish(conversation, "<div>token</div>".to_string());
+
+        // then:
+        assert_eq!(first_tab.try_recv().unwrap(), "<div>token</div>");
+        assert_eq!(second_tab.try_recv().unwrap(), "<div>token</div>");
+        assert!(other.try_recv().is_err());
+    }
+
+    #[test]
+    fn test_channels_without_subscribers_are_dropped() {
+        // given:
+        let hub = Hub::default();
+        let sidebar = Topic::Sidebar(Uuid::new_v4());
+        drop(hub.subscribe(sidebar));
+
+        // when:
+        hub.publish(sidebar, "<div></div>".to_string());
+
+        // then:
+        assert!(hub.channels.lock().unwrap().is_empty());
+    }
+}
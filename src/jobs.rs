@@ -0,0 +1,142 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::mpsc;
+use tracing::{debug, error};
+use uuid::Uuid;
+
+use crate::limits::Stream;
+use crate::models::{Message, User};
+use crate::state::AppState;
+use crate::ws;
+
+/// Latest fragment of every message being generated, replayed to clients which connect mid-answer.
+#[derive(Clone, Default)]
+pub struct Progress(Arc<Mutex<HashMap<Uuid, String>>>);
+
+impl Progress {
+    pub fn update(&self, message_id: Uuid, html: String) {
+        self.0.lock().unwrap().insert(message_id, html);
+    }
+
+    pub fn finish(&self, message_id: Uuid) {
+        self.0.lock().unwrap().remove(&message_id);
+    }
+
+    /// Drops the fragments of every message, none of them is being generated anymore.
+    pub fn clear(&self) {
+        self.0.lock().unwrap().clear();
+    }
+
+    pub fn fragments(&self) -> Vec<String> {
+        self.0.lock().unwrap().values().cloned().collect()
+    }
+}
+
+struct Job {
+    prompts: mpsc::UnboundedSender<(Message, User, Stream)>,
+    progress: Progress,
+}
+
+/// Generations running in the background, independent of the websocket which sent the prompt.
+/// Prompts of a conversation are answered one after another, in the order they came in.
+#[derive(Default)]
+pub struct Jobs {
+    running: Mutex<HashMap<Uuid, Job>>,
+}
+
+impl Jobs {
+    /// Answers the prompt, `stream` counts it against the user's limit until it's answered.
+    pub fn submit(&self, state: AppState, user: User, user_prompt: Message, stream: Stream) {
+        let conversation_id = user_prompt.conversation_id;
+        let mut running = self.running.lock().unwrap();
+        let prompt = (user_prompt, user, stream);
+        let prompt = match running.get(&conversation_id) {
+            Some(job) => match job.prompts.send(prompt) {
+                Ok(()) => {
+                    debug!(
+                        conversation_id = conversation_id.to_string(),
+                        "prompt queued behind a running generation"
+                    );
+                    return;
+                }
+                Err(mpsc::error::SendError(prompt)) => prompt,
+            },
+            None => prompt,
+        };
+
+        let (prompts, mut prompts_rx) = mpsc::unbounded_channel();
+        let progress = Progress::default();
+        let _ = prompts.send(prompt);
+        running.insert(
+            conversation_id,
+            Job {
+                prompts,
+                progress: progress.clone(),
+            },
+        );
+
+        tokio::spawn(async move {
+            loop {
+                let next = {
+                    // checked under the lock, so `submit` never queues to a finished job
+                    let mut running = state.jobs.running.lock().unwrap();
+                    let next = prompts_rx.try_recv().ok();
+                    if next.is_none() {
+                        running.remove(&conversation_id);
+                    }
+                    next
+                };
+                // the stream is held until the prompt is answered
+                let Some((user_prompt, user, _stream)) = next else {
+                    break;
+                };
+                if let Err(err) = ws::inference(user_prompt, state.clone(), &user, &progress).await
+                {
+                    error!(
+                        conversation_id = conversation_id.to_string(),
+                        ?err,
+                        "error while processing inference request"
+                    );
+                    // reconnecting clients mustn't be shown answers which stopped
+                    progress.clear();
+                }
+            }
+            debug!(
+                conversation_id = conversation_id.to_string(),
+                "generation job finished"
+            );
+        });
+    }
+
+    /// Fragments of the answers being generated in the conversation right now.
+    pub fn progress(&self, conversation_id: Uuid) -> Vec<String> {
+        self.running
+            .lock()
+            .unwrap()
+            .get(&conversation_id)
+            .map(|job| job.progress.fragments())
+            .unwrap_or_default()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_progress_keeps_latest_fragment_of_unfinished_messages() {
+        // given:
+        let progress = Progress::default();
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+
+        // when:
+        progress.update(first, "He".to_string());
+        progress.update(first, "Hello".to_string());
+        progress.update(second, "Hi".to_string());
+        progress.finish(second);
+
+        // then:
+        assert_eq!(progress.fragments(), vec!["Hello".to_string()]);
+    }
+}
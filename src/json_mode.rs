@@ -0,0 +1,113 @@
+use jsonschema::JSONSchema;
+use serde_json::Value;
+
+use crate::error::{Error, Result};
+
+/// Structured output requested from the model, stored as `conversation_settings.output_format`.
+pub enum OutputFormat {
+    /// any valid JSON
+    Json,
+    Schema(Value),
+}
+
+impl OutputFormat {
+    /// Parses the stored format, either the literal `json` or a JSON Schema.
+    pub fn parse(raw: &str) -> Result<Self> {
+        let raw = raw.trim();
+        if raw == "json" {
+            return Ok(Self::Json);
+        }
+        let schema: Value = serde_json::from_str(raw)
+            .map_err(|err| Error::OutputFormat(format!("schema is not valid JSON: {err}")))?;
+        if !schema.is_object() {
+            return Err(Error::OutputFormat("schema must be a JSON object".into()));
+        }
+        JSONSchema::compile(&schema)
+            .map_err(|err| Error::OutputFormat(format!("invalid schema: {err}")))?;
+        Ok(Self::Schema(schema))
+    }
+
+    /// Value of the `format` field sent to Ollama.
+    pub fn to_ollama(&self) -> Value {
+        match self {
+            Self::Json => Value::String("json".to_string()),
+            Self::Schema(schema) => schema.clone(),
+        }
+    }
+
+    /// Checks the model response, returns human readable validation errors.
+    pub fn validate(&self, content: &str) -> std::result::Result<(), Vec<String>> {
+        let instance: Value = serde_json::from_str(content.trim())
+            .map_err(|err| vec![format!("response is not valid JSON: {err}")])?;
+        let Self::Schema(schema) = self else {
+            return Ok(());
+        };
+        let compiled = JSONSchema::compile(schema).map_err(|err| vec![err.to_string()])?;
+        let result = compiled.validate(&instance).map_err(|errors| {
+            errors
+                .map(|err| {
+                    let path = err.instance_path.to_string();
+                    if path.is_empty() {
+                        err.to_string()
+                    } else {
+                        format!("{path}: {err}")
+                    }
+                })
+                .collect()
+        });
+        result
+    }
+}
+
+/// Follow-up prompt asking the model to fix its previous response.
+pub fn repair_prompt(errors: &[String]) -> String {
+    format!(
+        "Your previous response failed validation:\n- {}\n\
+        Respond again with the corrected JSON only.",
+        errors.join("\n- ")
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_output_format() {
+        assert!(matches!(
+            OutputFormat::parse("json"),
+            Ok(OutputFormat::Json)
+        ));
+        assert!(matches!(
+            OutputFormat::parse(r#"{"type": "object"}"#),
+            Ok(OutputFormat::Schema(_))
+        ));
+        assert!(OutputFormat::parse("[1, 2]").is_err());
+        assert!(OutputFormat::parse(r#"{"type": 12}"#).is_err());
+        assert!(OutputFormat::parse("{").is_err());
+    }
+
+    #[test]
+    fn test_validate_against_schema() {
+        // given:
+        let format = OutputFormat::parse(
+            r#"{
+                "type": "object",
+                "properties": { "name": { "type": "string" }, "age": { "type": "integer" } },
+                "required": ["name"]
+            }"#,
+        )
+        .unwrap();
+
+        // when:
+        let valid = format.validate(r#"{"name": "Ann", "age": 3}"#);
+        let invalid = format.validate(r#"{"age": "three"}"#).unwrap_err();
+        let not_json = OutputFormat::Json.validate("name: Ann").unwrap_err();
+
+        // then:
+        assert!(valid.is_ok());
+        assert_eq!(invalid.len(), 2);
+        assert!(invalid.iter().any(|err| err.starts_with("/age:")));
+        assert!(not_json[0].starts_with("response is not valid JSON"));
+    }
+}
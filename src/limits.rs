@@ -0,0 +1,351 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use axum::{
+    extract::{Request, State},
+    http::{header, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use chrono::{DateTime, Days, Utc};
+use sqlx::SqlitePool;
+use tokio::time::{Duration, Instant};
+use tracing::{debug, error};
+use uuid::Uuid;
+
+use crate::{
+    db,
+    error::Result,
+    models::{ApiToken, User},
+    state::AppState,
+};
+
+const WINDOW: Duration = Duration::from_secs(60);
+// streams have no known end, clients retry after a typical generation
+const STREAM_RETRY_AFTER: Duration = Duration::from_secs(10);
+
+/// Why a request of a user has been rejected.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejection {
+    Requests { retry_after: Duration },
+    Streams,
+    DailyTokens { retry_after: Duration },
+}
+
+impl Rejection {
+    pub fn retry_after(&self) -> Duration {
+        match self {
+            Self::Requests { retry_after } | Self::DailyTokens { retry_after } => *retry_after,
+            Self::Streams => STREAM_RETRY_AFTER,
+        }
+    }
+}
+
+impl fmt::Display for Rejection {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let retry_after = self.retry_after().as_secs().max(1);
+        match self {
+            Self::Requests { .. } => {
+                write!(f, "Too many requests, try again in {retry_after} seconds")
+            }
+            Self::Streams => write!(
+                f,
+                "Too many answers are being generated for you, wait until one of them finishes"
+            ),
+            Self::DailyTokens { .. } => write!(
+                f,
+                "Daily token quota used up, it resets in {} hours",
+                retry_after.div_ceil(3600)
+            ),
+        }
+    }
+}
+
+impl IntoResponse for Rejection {
+    fn into_response(self) -> Response {
+        let retry_after = self.retry_after().as_secs().max(1).to_string();
+        (
+            StatusCode::TOO_MANY_REQUESTS,
+            [(header::RETRY_AFTER, retry_after)],
+            self.to_string(),
+        )
+            .into_response()
+    }
+}
+
+/// Who a request is counted against, every API token has a window of its own.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Requester {
+    pub user_id: Uuid,
+    pub api_token_id: Option<Uuid>,
+}
+
+impl Requester {
+    pub fn new(user: &User, api_token: Option<&ApiToken>) -> Self {
+        Self {
+            user_id: user.id,
+            api_token_id: api_token.map(|api_token| api_token.id),
+        }
+    }
+}
+
+/// Current usage of a user, shown next to the limits.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Usage {
+    pub requests: usize,
+    pub streams: usize,
+    pub tokens: i64,
+}
+
+/// Per-user limits, 0 turns a limit off.
+pub struct Limits {
+    pub requests_per_minute: usize,
+    pub max_streams: usize,
+    pub daily_tokens: i64,
+    requests: Mutex<HashMap<Requester, VecDeque<Instant>>>,
+    streams: Mutex<HashMap<Uuid, usize>>,
+}
+
+impl Limits {
+    pub fn new(requests_per_minute: usize, max_streams: usize, daily_tokens: i64) -> Self {
+        Self {
+            requests_per_minute,
+            max_streams,
+            daily_tokens,
+            requests: Mutex::new(HashMap::new()),
+            streams: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Counts the request against the sliding window of the last minute.
+    pub fn check_request(
+        &self,
+        requester: Requester,
+        now: Instant,
+    ) -> std::result::Result<(), Rejection> {
+        if self.requests_per_minute == 0 {
+            return Ok(());
+        }
+        let mut requests = self.requests.lock().unwrap();
+        // windows of requesters who stopped sending requests
+        requests.retain(|_, times| {
+            times
+                .back()
+                .is_some_and(|last| now.duration_since(*last) < WINDOW)
+        });
+        let times = requests.entry(requester).or_default();
+        while times
+            .front()
+            .is_some_and(|first| now.duration_since(*first) >= WINDOW)
+        {
+            times.pop_front();
+        }
+        if times.len() >= self.requests_per_minute {
+            let retry_after = WINDOW - now.duration_since(times[0]);
+            return Err(Rejection::Requests { retry_after });
+        }
+        times.push_back(now);
+        Ok(())
+    }
+
+    /// Starts a stream of the user, it ends when the guard is dropped.
+    pub fn start_stream(self: &Arc<Self>, user_id: Uuid) -> std::result::Result<Stream, Rejection> {
+        let mut streams = self.streams.lock().unwrap();
+        let running = streams.entry(user_id).or_default();
+        if self.max_streams > 0 && *running >= self.max_streams {
+            return Err(Rejection::Streams);
+        }
+        *running += 1;
+        Ok(Stream {
+            limits: self.clone(),
+            user_id,
+        })
+    }
+
+    /// Checks every limit of a new prompt, the stream of its answer starts when it's accepted.
+    pub async fn check_prompt(
+        self: &Arc<Self>,
+        sqlite: SqlitePool,
+        user_id: Uuid,
+    ) -> Result<std::result::Result<Stream, Rejection>> {
+        if self.daily_tokens > 0 {
+            let now = Utc::now();
+            let tokens = db::count_tokens_since(sqlite, user_id, start_of_day(now)).await?;
+            if tokens >= self.daily_tokens {
+                let retry_after = (next_day(now) - now).to_std().unwrap_or_default();
+                return Ok(Err(Rejection::DailyTokens { retry_after }));
+            }
+        }
+        Ok(self.start_stream(user_id))
+    }
+
+    /// Usage of the user, tokens are counted since the start of the day.
+    /// Requests are the ones of the busiest window, the session's or one of an API token.
+    pub fn usage(&self, user_id: Uuid, tokens: i64, now: Instant) -> Usage {
+        let requests = self
+            .requests
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|(requester, _)| requester.user_id == user_id)
+            .map(|(_, times)| {
+                times
+                    .iter()
+                    .filter(|time| now.duration_since(**time) < WINDOW)
+                    .count()
+            })
+            .max()
+            .unwrap_or_default();
+        let streams = self
+            .streams
+            .lock()
+            .unwrap()
+            .get(&user_id)
+            .copied()
+            .unwrap_or_default();
+        Usage {
+            requests,
+            streams,
+            tokens,
+        }
+    }
+}
+
+/// Answer being generated for a user, counted against their concurrent streams.
+pub struct Stream {
+    limits: Arc<Limits>,
+    user_id: Uuid,
+}
+
+impl Drop for Stream {
+    fn drop(&mut self) {
+        let mut streams = self.limits.streams.lock().unwrap();
+        if let Some(running) = streams.get_mut(&self.user_id) {
+            *running -= 1;
+            if *running == 0 {
+                streams.remove(&self.user_id);
+            }
+        }
+    }
+}
+
+pub fn start_of_day(now: DateTime<Utc>) -> DateTime<Utc> {
+    now.date_naive().and_hms_opt(0, 0, 0).unwrap().and_utc()
+}
+
+fn next_day(now: DateTime<Utc>) -> DateTime<Utc> {
+    start_of_day(now) + Days::new(1)
+}
+
+/// Rejects requests over the per-minute limit of the user, must be layered after authentication.
+pub async fn limit_requests(
+    State(state): State<AppState>,
+    request: Request,
+    next: Next,
+) -> Response {
+    let Some(user) = request.extensions().get::<User>() else {
+        error!("rate limit applied before authentication");
+        return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+    };
+    let requester = Requester::new(user, request.extensions().get::<ApiToken>());
+    if let Err(rejection) = state.limits.check_request(requester, Instant::now()) {
+        debug!(
+            user_id = user.id.to_string(),
+            ?rejection,
+            "request rejected"
+        );
+        return rejection.into_response();
+    }
+    next.run(request).await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_request_rejects_over_limit_until_window_passes() {
+        // given:
+        let limits = Limits::new(2, 0, 0);
+        let user = Requester {
+            user_id: Uuid::new_v4(),
+            api_token_id: None,
+        };
+        let other_user = Requester {
+            user_id: Uuid::new_v4(),
+            api_token_id: None,
+        };
+        let start = Instant::now();
+
+        // when:
+        let first = limits.check_request(user, start);
+        let second = limits.check_request(user, start + Duration::from_secs(20));
+        let third = limits.check_request(user, start + Duration::from_secs(30));
+        let other_user = limits.check_request(other_user, start + Duration::from_secs(30));
+        let after_window = limits.check_request(user, start + Duration::from_secs(60));
+
+        // then:
+        assert_eq!(first, Ok(()));
+        assert_eq!(second, Ok(()));
+        assert_eq!(
+            third,
+            Err(Rejection::Requests {
+                retry_after: Duration::from_secs(30)
+            })
+        );
+        assert_eq!(other_user, Ok(()));
+        assert_eq!(after_window, Ok(()));
+    }
+
+    #[test]
+    fn test_check_request_counts_api_tokens_separately() {
+        // given:
+        let limits = Limits::new(1, 0, 0);
+        let user_id = Uuid::new_v4();
+        let session = Requester {
+            user_id,
+            api_token_id: None,
+        };
+        let token = Requester {
+            user_id,
+            api_token_id: Some(Uuid::new_v4()),
+        };
+        let other_token = Requester {
+            user_id,
+            api_token_id: Some(Uuid::new_v4()),
+        };
+        let now = Instant::now();
+
+        // when:
+        let token_first = limits.check_request(token, now);
+        let token_second = limits.check_request(token, now);
+        let other_token = limits.check_request(other_token, now);
+        let session = limits.check_request(session, now);
+
+        // then:
+        assert_eq!(token_first, Ok(()));
+        assert!(token_second.is_err());
+        assert_eq!(other_token, Ok(()));
+        assert_eq!(session, Ok(()));
+        assert_eq!(limits.usage(user_id, 0, now).requests, 1);
+    }
+
+    #[test]
+    fn test_streams_are_released_when_dropped() {
+        // given:
+        let limits = Arc::new(Limits::new(0, 1, 0));
+        let user_id = Uuid::new_v4();
+        let stream = limits.start_stream(user_id).unwrap();
+
+        // when:
+        let rejected = limits.start_stream(user_id).err();
+        drop(stream);
+        let accepted = limits.start_stream(user_id);
+
+        // then:
+        assert_eq!(rejected, Some(Rejection::Streams));
+        assert!(accepted.is_ok());
+        assert_eq!(limits.usage(user_id, 0, Instant::now()).streams, 1);
+    }
+}
@@ -1,55 +1,127 @@
 #![forbid(unsafe_code)]
+mod auth;
+mod charts;
+mod cli;
 mod config;
 mod db;
 mod error;
 mod frontend;
+mod hub;
+mod jobs;
+mod json_mode;
+mod limits;
 mod models;
 mod ollama;
+mod rag;
+mod scheduler;
+mod sse;
 mod state;
+mod tools;
+mod validation;
 mod ws;
 
+use crate::cli::{Cli, Command, ConfigCommand};
 use crate::error::Result;
 use crate::frontend::handlers;
+use crate::hub::Hub;
+use crate::jobs::Jobs;
+use crate::limits::Limits;
+use crate::scheduler::Scheduler;
 use crate::state::AppState;
+use crate::tools::ToolRegistry;
 use crate::ws::websocket;
 
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use axum::extract::DefaultBodyLimit;
 use axum::handler::Handler;
-use axum::routing::{delete, get, post};
+use axum::middleware;
+use axum::routing::{delete, get, post, put};
 use axum::Router;
+use clap::Parser;
 use config::CONFIG;
 use sqlx::migrate::{MigrateDatabase, Migrator};
 use sqlx::sqlite::SqlitePoolOptions;
 use sqlx::SqlitePool;
 use tower_http::services::{ServeDir, ServeFile};
-use tracing::info;
+use tracing::{info, warn};
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    let cli = Cli::parse();
+
     tracing_subscriber::fmt()
         .with_env_filter("lokai=info")
         .with_target(false)
         .with_level(true)
         .init();
 
-    let state = {
-        create_db(&CONFIG.database_url).await;
-        let migrator = Migrator::new(std::path::Path::new("./migrations"))
-            .await
-            .expect("Cannot create database migrator");
-        let sqlite: SqlitePool = SqlitePoolOptions::new()
-            .connect(&CONFIG.database_url)
-            .await
-            .expect("Could not make pool");
-        migrator
-            .run(&sqlite)
-            .await
-            .expect("Cannot run database migrations");
-
-        AppState {
-            sqlite: sqlite,
-            reqwest_client: reqwest::Client::new(),
+    let result = match cli.command {
+        Some(Command::Config(ConfigCommand::Show)) => {
+            cli::show_config(&cli.flags);
+            Ok(())
+        }
+        None => serve(&cli.flags, false).await,
+        Some(Command::Serve { skip_migrations }) => serve(&cli.flags, skip_migrations).await,
+        Some(Command::Migrate(command)) => {
+            let (sqlite, migrator) = connect(&cli.flags).await;
+            cli::migrate(command, sqlite, &migrator).await
         }
+        Some(Command::Admin(command)) => {
+            let (sqlite, migrator) = connect(&cli.flags).await;
+            cli::run(command, sqlite, &migrator).await
+        }
+    };
+    if let Err(err) = result {
+        eprintln!("{err}");
+        std::process::exit(1);
+    }
+
+    Ok(())
+}
+
+/// Loads the configuration and opens the database, creating it when it doesn't exist yet.
+async fn connect(flags: &config::Flags) -> (SqlitePool, Migrator) {
+    match config::Config::load(flags) {
+        Ok(config) => config::init(config),
+        Err(errors) => cli::exit_with_errors(&errors),
+    }
+
+    create_db(&CONFIG.database_url).await;
+    let migrator = Migrator::new(std::path::Path::new("./migrations"))
+        .await
+        .expect("Cannot create database migrator");
+    let sqlite: SqlitePool = SqlitePoolOptions::new()
+        .connect(&CONFIG.database_url)
+        .await
+        .expect("Could not make pool");
+
+    (sqlite, migrator)
+}
+
+async fn serve(flags: &config::Flags, skip_migrations: bool) -> Result<()> {
+    let (sqlite, migrator) = connect(flags).await;
+    if skip_migrations {
+        cli::check_migrations(&sqlite, &migrator).await?;
+    } else {
+        migrator.run(&sqlite).await.map_err(sqlx::Error::from)?;
+    }
+
+    let state = AppState {
+        sqlite,
+        reqwest_client: reqwest::Client::new(),
+        tools: Arc::new(ToolRegistry::builtin()),
+        hub: Arc::new(Hub::default()),
+        jobs: Arc::new(Jobs::default()),
+        scheduler: Arc::new(Scheduler::new(CONFIG.lokai_max_concurrent_generations)),
+        limits: Arc::new(Limits::new(
+            CONFIG.lokai_requests_per_minute,
+            CONFIG.lokai_max_streams_per_user,
+            CONFIG.lokai_daily_token_quota,
+        )),
     };
+    check_models(&state.reqwest_client).await;
 
     let api_router = Router::new()
         .route(
@@ -57,13 +129,108 @@ async fn main() -> Result<()> {
             get(handlers::sidebar_new_conversation_form),
         )
         .route("/conversations", post(handlers::create_conversation))
-        .route("/conversations/:id", delete(handlers::delete_conversation));
+        .route("/conversations/:id", delete(handlers::delete_conversation))
+        .route(
+            "/attachments",
+            post(handlers::upload_attachments).layer(DefaultBodyLimit::max(
+                handlers::MAX_ATTACHMENTS_UPLOAD_BYTES,
+            )),
+        )
+        .route("/attachments/:id", get(handlers::attachment))
+        .route(
+            "/conversations/:id/documents",
+            post(handlers::upload_documents)
+                .layer(DefaultBodyLimit::max(handlers::MAX_DOCUMENTS_UPLOAD_BYTES)),
+        )
+        .route("/documents/:id", delete(handlers::delete_document))
+        .route(
+            "/conversations/:id/output-format",
+            put(handlers::update_output_format),
+        )
+        .route(
+            "/conversations/:id/comparison",
+            put(handlers::update_comparison_models),
+        )
+        .route(
+            "/messages/:id/winner",
+            post(handlers::pick_comparison_winner),
+        )
+        .route("/messages/:id/feedback", post(handlers::message_feedback))
+        .route(
+            "/conversations/:id/share-links",
+            post(handlers::create_share_link),
+        )
+        .route("/share-links/:id", delete(handlers::revoke_share_link))
+        .route("/sidebar", get(handlers::sidebar_conversations))
+        .route("/sidebar/page", get(handlers::sidebar_page))
+        .route("/conversations/:id/messages", get(handlers::messages_page))
+        .route("/prompt", post(sse::prompt))
+        .route("/prompt/sse", get(handlers::sse_prompt))
+        .route("/folders/form", get(handlers::sidebar_new_folder_form))
+        .route("/folders", post(handlers::create_folder))
+        .route(
+            "/folders/:id",
+            put(handlers::update_folder).delete(handlers::delete_folder),
+        )
+        .route(
+            "/conversations/:id/folder",
+            put(handlers::move_conversation),
+        )
+        .route("/conversations/:id/tags", post(handlers::tag_conversation))
+        .route(
+            "/conversations/:id/tags/:tag_id",
+            delete(handlers::untag_conversation),
+        )
+        .route("/feedback/export", get(handlers::export_feedback))
+        .route("/stats", get(handlers::api_stats));
 
-    let app = Router::new()
+    let admin_router = Router::new()
+        .route("/users", get(handlers::users).post(handlers::create_user))
+        .route("/usage", get(handlers::usage))
+        .route_layer(middleware::from_fn(auth::require_admin));
+
+    // pages are for browsers only, API tokens are accepted by `/api` and `/ws`
+    let pages_router = Router::new()
         .route("/", get(handlers::index))
         .route("/c/:id", get(handlers::conversation))
+        .route("/d/:id", get(handlers::document))
+        .route("/f/:id", get(handlers::folder_page))
+        .route("/stats", get(handlers::stats))
+        .route(
+            "/tokens",
+            get(handlers::tokens).post(handlers::create_token),
+        )
+        .route("/tokens/:id", delete(handlers::revoke_token))
+        .merge(admin_router)
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_user,
+        ));
+
+    let api_ws_router = Router::new()
         .route("/ws", get(websocket))
+        .route("/sse", get(sse::events))
         .nest("/api", api_router)
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            limits::limit_requests,
+        ))
+        .route_layer(middleware::from_fn_with_state(
+            state.clone(),
+            auth::require_api_user,
+        ));
+
+    let app = Router::new()
+        .route("/login", get(handlers::login_page).post(handlers::login))
+        .route("/logout", post(handlers::logout))
+        .route("/setup", get(handlers::setup_page).post(handlers::setup))
+        .route("/s/:token", get(handlers::shared_conversation))
+        .route(
+            "/s/:token/attachments/:id",
+            get(handlers::shared_attachment),
+        )
+        .merge(pages_router)
+        .merge(api_ws_router)
         .nest_service("/robots.txt", ServeFile::new("static/robots.txt"))
         .nest_service(
             "/static",
@@ -71,21 +238,25 @@ async fn main() -> Result<()> {
                 .not_found_service(handlers::not_found.with_state(state.clone())),
         )
         .fallback(handlers::not_found)
+        .layer(middleware::from_fn(error::render_errors))
         .with_state(state);
 
     let addr = CONFIG.lokai_url();
     let listener = tokio::net::TcpListener::bind(&addr)
         .await
-        .expect(&format!("Cannot bind TcpListener to {:?}", addr));
+        .unwrap_or_else(|_| panic!("Cannot bind TcpListener to {:?}", addr));
     info!("listening on http://{}", &addr);
-    axum::serve(listener, app.into_make_service())
-        .await
-        .expect("Cannot start server");
+    axum::serve(
+        listener,
+        app.into_make_service_with_connect_info::<SocketAddr>(),
+    )
+    .await
+    .expect("Cannot start server");
 
     Ok(())
 }
 
-async fn create_db<'a>(db_url: &'a str) {
+async fn create_db(db_url: &str) {
     if !sqlx::Sqlite::database_exists(db_url)
         .await
         .expect("Cannot check if database exists")
@@ -95,3 +266,34 @@ async fn create_db<'a>(db_url: &'a str) {
             .expect("Cannot create database");
     }
 }
+
+/// A typo in a model name shouldn't wait for the first prompt to show up.
+async fn check_models(reqwest_client: &reqwest::Client) {
+    let models = [
+        (&CONFIG.lokai_default_llm_model, "default_llm_model", true),
+        (&CONFIG.lokai_embedding_model, "embedding_model", false),
+    ];
+    for (model, key, required) in models {
+        match ollama::model_capabilities(reqwest_client, &CONFIG.ollama_url, model).await {
+            Ok(_) => {}
+            Err(error::Error::Upstream(err))
+                if err.status() == Some(http::StatusCode::NOT_FOUND) =>
+            {
+                let reason = format!(
+                    "{key} = {model:?} isn't available in Ollama at {}, pull it with `ollama pull {model}`",
+                    CONFIG.ollama_url
+                );
+                if required {
+                    cli::exit_with_errors(&[reason]);
+                }
+                warn!("{reason}");
+            }
+            Err(err) => {
+                warn!(
+                    "Cannot check {key} = {model:?} in Ollama at {}: {err}",
+                    CONFIG.ollama_url
+                );
+            }
+        }
+    }
+}
@@ -1,75 +1,139 @@
 use std::{collections::HashMap, str::FromStr};
 
 use chrono::{DateTime, Utc};
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Deserializer, Serialize};
+use sqlx::types::Json;
 use sqlx::FromRow;
 use url::Url;
 use uuid::Uuid;
 
-#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+/// Stored lowercase in `messages.role`, a CHECK constraint keeps out anything else.
+#[derive(Deserialize, Serialize, sqlx::Type, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+#[sqlx(rename_all = "lowercase")]
 pub enum Role {
-    #[serde(rename = "system")]
     System,
-    #[serde(rename = "user")]
     User,
-    #[serde(rename = "assistant")]
     Assistant,
+    /// result of a tool the assistant called
+    Tool,
 }
 
-impl From<&str> for Role {
-    fn from(value: &str) -> Self {
-        let value = value.to_lowercase();
-        match value.as_ref() {
-            "system" => Role::System,
-            "user" => Role::User,
-            "assistant" => Role::Assistant,
-            _ => panic!("Unknown Role!"),
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::System => "system",
+            Role::User => "user",
+            Role::Assistant => "assistant",
+            Role::Tool => "tool",
         }
     }
 }
 
-impl From<String> for Role {
-    fn from(value: String) -> Self {
-        let value = value.to_lowercase();
-        match value.as_ref() {
-            "system" => Role::System,
-            "user" => Role::User,
-            "assistant" => Role::Assistant,
-            _ => panic!("Unknown Role!"),
+impl TryFrom<&str> for Role {
+    type Error = String;
+
+    fn try_from(value: &str) -> Result<Self, Self::Error> {
+        match value.trim().to_lowercase().as_ref() {
+            "system" => Ok(Role::System),
+            "user" => Ok(Role::User),
+            "assistant" => Ok(Role::Assistant),
+            "tool" => Ok(Role::Tool),
+            _ => Err(format!("unknown role: {value}")),
         }
     }
 }
 
 impl std::fmt::Display for Role {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        match self {
-            Role::System => write!(f, "system"),
-            Role::User => write!(f, "user"),
-            Role::Assistant => write!(f, "assistant"),
+        write!(f, "{}", self.as_str())
+    }
+}
+
+/// Generation statistics reported by Ollama in the final chunk of a streamed response.
+/// All durations are in nanoseconds.
+#[derive(FromRow, Deserialize, Serialize, Debug, Clone, Default, PartialEq)]
+pub struct GenerationStats {
+    pub llm_model: Option<String>,
+    pub total_duration: Option<i64>,
+    pub load_duration: Option<i64>,
+    pub prompt_eval_count: Option<i64>,
+    pub prompt_eval_duration: Option<i64>,
+    pub eval_count: Option<i64>,
+    pub eval_duration: Option<i64>,
+}
+
+impl GenerationStats {
+    pub fn tokens_per_second(&self) -> Option<f64> {
+        match (self.eval_count, self.eval_duration) {
+            (Some(count), Some(duration)) if duration > 0 => {
+                Some(count as f64 / duration as f64 * 1_000_000_000.0)
+            }
+            _ => None,
         }
     }
 }
 
+#[derive(Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
 #[derive(FromRow, Deserialize, Serialize, Debug, Clone, PartialEq)]
 pub struct Message {
     pub id: Uuid,
-    pub role: String,
+    pub role: Role,
     pub content: String,
     pub conversation_id: Uuid,
     pub created_at: DateTime<Utc>,
+    #[sqlx(flatten)]
+    pub stats: GenerationStats,
+    /// tools requested by the model, only set on assistant messages
+    pub tool_calls: Option<Json<Vec<ToolCall>>>,
+    /// name of the tool which produced the result, only set on tool messages
+    pub tool_name: Option<String>,
+    /// why the response doesn't match the conversation output format, if it doesn't
+    pub validation_errors: Option<Json<Vec<String>>>,
+    /// why the answer is incomplete, set when generating it failed
+    pub generation_error: Option<String>,
+    /// id of the user prompt answered side by side by several models
+    pub comparison_id: Option<Uuid>,
+    /// picked by the user as the best answer of a comparison
+    pub winner: bool,
+    #[sqlx(skip)]
+    pub attachment_ids: Vec<Uuid>,
+    #[sqlx(skip)]
+    pub citations: Vec<Citation>,
+    #[sqlx(skip)]
+    pub feedback: Option<Feedback>,
 }
 
 impl Message {
     fn new(role: Role, content: String, conversation_id: Uuid) -> Self {
         Self {
             id: Uuid::new_v4(),
-            role: role.to_string(),
+            role,
             content,
             conversation_id,
             created_at: Utc::now(),
+            stats: GenerationStats::default(),
+            tool_calls: None,
+            tool_name: None,
+            validation_errors: None,
+            generation_error: None,
+            comparison_id: None,
+            winner: false,
+            attachment_ids: Vec::new(),
+            citations: Vec::new(),
+            feedback: None,
         }
     }
 
+    pub fn system(content: String, conversation_id: Uuid) -> Self {
+        Self::new(Role::System, content, conversation_id)
+    }
+
     pub fn user(content: String, conversation_id: Uuid) -> Self {
         Self::new(Role::User, content, conversation_id)
     }
@@ -78,15 +142,227 @@ impl Message {
         Self::new(Role::Assistant, content, conversation_id)
     }
 
+    pub fn tool(content: String, tool_name: String, conversation_id: Uuid) -> Self {
+        let mut message = Self::new(Role::Tool, content, conversation_id);
+        message.tool_name = Some(tool_name);
+        message
+    }
+
+    pub fn comparison(llm_model: String, comparison_id: Uuid, conversation_id: Uuid) -> Self {
+        let mut message = Self::new(Role::Assistant, "".to_string(), conversation_id);
+        message.comparison_id = Some(comparison_id);
+        message.stats.llm_model = Some(llm_model);
+        message
+    }
+
+    /// Position in the conversation, ordered by creation time.
+    pub fn cursor(&self) -> Cursor {
+        Cursor {
+            at: self.created_at,
+            id: self.id,
+        }
+    }
+
     pub fn update_content(&mut self, update: &str) {
         self.content.push_str(update);
     }
+
+    pub fn tool_calls(&self) -> &[ToolCall] {
+        self.tool_calls
+            .as_ref()
+            .map(|tool_calls| tool_calls.0.as_slice())
+            .unwrap_or_default()
+    }
+
+    pub fn validation_errors(&self) -> &[String] {
+        self.validation_errors
+            .as_ref()
+            .map(|errors| errors.0.as_slice())
+            .unwrap_or_default()
+    }
+
+    /// Pretty-printed content when it's a JSON object or array.
+    pub fn json_content(&self) -> Option<String> {
+        if self.role != Role::Assistant {
+            return None;
+        }
+        match serde_json::from_str(self.content.trim()) {
+            Ok(value @ (serde_json::Value::Object(_) | serde_json::Value::Array(_))) => {
+                serde_json::to_string_pretty(&value).ok()
+            }
+            _ => None,
+        }
+    }
+}
+
+#[derive(FromRow, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Feedback {
+    pub message_id: Uuid,
+    /// 1 for thumbs up, -1 for thumbs down
+    pub rating: i64,
+    pub note: Option<String>,
+    pub created_at: DateTime<Utc>,
+    pub updated_at: DateTime<Utc>,
+}
+
+impl Feedback {
+    pub fn new(message_id: Uuid, rating: i64, note: Option<String>) -> Self {
+        let now = Utc::now();
+        Self {
+            message_id,
+            rating,
+            note,
+            created_at: now,
+            updated_at: now,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct ExampleMessage {
+    pub role: Role,
+    pub content: String,
+}
+
+/// Single line of the feedback dataset export.
+#[derive(Serialize, Debug, Clone, PartialEq)]
+pub struct FeedbackExample {
+    pub message_id: Uuid,
+    pub llm_model: Option<String>,
+    pub history: Vec<ExampleMessage>,
+    pub answer: String,
+    pub rating: i64,
+    pub note: Option<String>,
+}
+
+impl FeedbackExample {
+    /// `history` are the conversation messages which came before the answer.
+    pub fn new(answer: Message, history: Vec<Message>, feedback: Feedback) -> Self {
+        let history = prune_comparisons(history)
+            .into_iter()
+            // answers of the same comparison are alternatives, not part of the history
+            .filter(|m| m.comparison_id.is_none() || m.comparison_id != answer.comparison_id)
+            .map(|m| ExampleMessage {
+                role: m.role,
+                content: m.content,
+            })
+            .collect();
+        Self {
+            message_id: answer.id,
+            llm_model: answer.stats.llm_model,
+            history,
+            answer: answer.content,
+            rating: feedback.rating,
+            note: feedback.note,
+        }
+    }
+}
+
+/// Messages as shown in the chat area, answers of a comparison are rendered side by side.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessageGroup {
+    pub comparison_id: Option<Uuid>,
+    pub messages: Vec<Message>,
+}
+
+/// Consecutive messages of a conversation in chronological order.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MessagePage {
+    pub messages: Vec<Message>,
+    /// loads the messages preceding the page, `None` when it starts the conversation
+    pub older: Option<Cursor>,
+    /// loads the messages following the page, `None` when it ends the conversation
+    pub newer: Option<Cursor>,
+}
+
+pub fn group_messages(messages: Vec<Message>) -> Vec<MessageGroup> {
+    let mut groups: Vec<MessageGroup> = Vec::new();
+    for message in messages {
+        match groups.last_mut() {
+            Some(group)
+                if message.comparison_id.is_some()
+                    && group.comparison_id == message.comparison_id =>
+            {
+                group.messages.push(message)
+            }
+            _ => groups.push(MessageGroup {
+                comparison_id: message.comparison_id,
+                messages: vec![message],
+            }),
+        }
+    }
+    groups
+}
+
+pub const MIN_COMPARISON_MODELS: usize = 2;
+pub const MAX_COMPARISON_MODELS: usize = 4;
+
+/// Parses comma separated model names, an empty list turns the comparison off.
+pub fn parse_comparison_models(raw: &str) -> Result<Option<Vec<String>>, String> {
+    let mut models: Vec<String> = Vec::new();
+    for model in raw.split(',').map(str::trim).filter(|m| !m.is_empty()) {
+        if !models.iter().any(|m| m == model) {
+            models.push(model.to_string());
+        }
+    }
+    match models.len() {
+        0 => Ok(None),
+        MIN_COMPARISON_MODELS..=MAX_COMPARISON_MODELS => Ok(Some(models)),
+        _ => Err(format!(
+            "compare between {MIN_COMPARISON_MODELS} and {MAX_COMPARISON_MODELS} different models"
+        )),
+    }
+}
+
+/// Keeps a single answer of every comparison in the history sent to the model, the winner or the first one.
+pub fn prune_comparisons(messages: Vec<Message>) -> Vec<Message> {
+    let winners: Vec<Uuid> = messages
+        .iter()
+        .filter(|message| message.winner)
+        .filter_map(|message| message.comparison_id)
+        .collect();
+    let mut seen: Vec<Uuid> = Vec::new();
+    messages
+        .into_iter()
+        .filter(|message| {
+            let Some(comparison_id) = message.comparison_id else {
+                return true;
+            };
+            let keep = if winners.contains(&comparison_id) {
+                message.winner
+            } else {
+                !seen.contains(&comparison_id)
+            };
+            seen.push(comparison_id);
+            keep
+        })
+        .collect()
+}
+
+// HTMX sends a single value for a field as a string and multiple values as an array
+fn one_or_many<'de, D>(deserializer: D) -> Result<Vec<Uuid>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum OneOrMany {
+        One(Uuid),
+        Many(Vec<Uuid>),
+    }
+
+    Ok(match OneOrMany::deserialize(deserializer)? {
+        OneOrMany::One(value) => vec![value],
+        OneOrMany::Many(values) => values,
+    })
 }
 
 #[allow(non_snake_case)]
 #[derive(Deserialize, Serialize, Debug)]
 pub struct UserPromptFormMessage {
     pub user_prompt: String,
+    #[serde(default, deserialize_with = "one_or_many")]
+    pub attachment_ids: Vec<Uuid>,
     pub HEADERS: HashMap<String, serde_json::Value>,
 }
 
@@ -104,16 +380,46 @@ impl UserPromptFormMessage {
 
     pub fn conversation_id(&self) -> Uuid {
         // SAFETY: We can unwrap as we know the value set by HTMX is correct
-        let url = Url::parse(self.hx_current_url()).unwrap();
-        let path = url.path().strip_prefix("/c/").unwrap();
-        // SAFETY: We can unwrap because router doesn't allow invalid UUIDs
-        Uuid::from_str(path).unwrap()
+        // and the router doesn't allow invalid UUIDs
+        conversation_id_from_url(self.hx_current_url()).unwrap()
     }
 }
 
+/// Id of the conversation open at the given page URL, e.g. `HX-Current-URL` of a prompt.
+pub fn conversation_id_from_url(url: &str) -> Option<Uuid> {
+    let url = Url::parse(url).ok()?;
+    let path = url.path().strip_prefix("/c/")?;
+    Uuid::from_str(path).ok()
+}
+
 impl From<UserPromptFormMessage> for Message {
     fn from(value: UserPromptFormMessage) -> Self {
-        Message::user(value.user_prompt.clone(), value.conversation_id())
+        let mut message = Message::user(value.user_prompt.clone(), value.conversation_id());
+        message.attachment_ids = value.attachment_ids;
+        message
+    }
+}
+
+#[derive(FromRow, Debug, Clone, PartialEq)]
+pub struct Attachment {
+    pub id: Uuid,
+    pub message_id: Option<Uuid>,
+    pub mime_type: String,
+    pub data: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+    pub user_id: Option<Uuid>,
+}
+
+impl Attachment {
+    pub fn new(mime_type: String, data: Vec<u8>, user_id: Uuid) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            message_id: None,
+            mime_type,
+            data,
+            created_at: Utc::now(),
+            user_id: Some(user_id),
+        }
     }
 }
 
@@ -122,24 +428,332 @@ pub struct Conversation {
     pub id: Uuid,
     pub name: String,
     pub created_at: DateTime<Utc>,
+    /// owner, conversations created before user accounts existed have none until the first admin is set up
+    pub user_id: Option<Uuid>,
+    pub folder_id: Option<Uuid>,
+    /// time of the latest message, kept up to date by a trigger on `messages`
+    pub updated_at: DateTime<Utc>,
+    #[sqlx(skip)]
+    #[serde(default)]
+    pub tags: Vec<Tag>,
 }
 
 impl Conversation {
-    pub fn new(name: String) -> Self {
+    pub fn new(name: String, user_id: Uuid) -> Self {
+        let created_at = Utc::now();
         Self {
             id: Uuid::new_v4(),
             name,
+            created_at,
+            user_id: Some(user_id),
+            folder_id: None,
+            updated_at: created_at,
+            tags: Vec::new(),
+        }
+    }
+
+    /// Position in the sidebar, ordered by last activity.
+    pub fn cursor(&self) -> Cursor {
+        Cursor {
+            at: self.updated_at,
+            id: self.id,
+        }
+    }
+}
+
+/// Position in a list ordered by time and id, pages start right next to it.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Cursor {
+    pub at: DateTime<Utc>,
+    pub id: Uuid,
+}
+
+impl std::fmt::Display for Cursor {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        // nanoseconds survive the round trip, the keyset comparison needs the exact timestamp
+        let nanos = self.at.timestamp_nanos_opt().unwrap_or_default();
+        write!(f, "{}_{}", nanos, self.id)
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (nanos, id) = s.split_once('_').ok_or("cursor must be <nanos>_<id>")?;
+        let nanos: i64 = nanos.parse().map_err(|_| "invalid cursor timestamp")?;
+        let id = Uuid::from_str(id).map_err(|_| "invalid cursor id")?;
+        Ok(Self {
+            at: DateTime::from_timestamp_nanos(nanos),
+            id,
+        })
+    }
+}
+
+/// Sidebar section of a conversation, by the day of its last activity.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Recency {
+    Today,
+    Yesterday,
+    PreviousWeek,
+    Older,
+}
+
+impl Recency {
+    pub fn of(updated_at: DateTime<Utc>, now: DateTime<Utc>) -> Self {
+        match (now.date_naive() - updated_at.date_naive()).num_days() {
+            ..=0 => Self::Today,
+            1 => Self::Yesterday,
+            2..=7 => Self::PreviousWeek,
+            _ => Self::Older,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            Self::Today => "Today",
+            Self::Yesterday => "Yesterday",
+            Self::PreviousWeek => "Previous 7 days",
+            Self::Older => "Older",
+        }
+    }
+}
+
+/// Conversations of a single sidebar section.
+#[derive(Debug, Clone, PartialEq)]
+pub struct RecencyGroup {
+    pub recency: Recency,
+    /// the section began on a previous page, its heading is already rendered
+    pub continued: bool,
+    pub conversations: Vec<Conversation>,
+}
+
+/// Splits conversations ordered by last activity into sections,
+/// `previous` is the section of the last conversation on the previous page.
+pub fn group_by_recency(
+    conversations: Vec<Conversation>,
+    now: DateTime<Utc>,
+    previous: Option<Recency>,
+) -> Vec<RecencyGroup> {
+    let mut groups: Vec<RecencyGroup> = Vec::new();
+    for conversation in conversations {
+        let recency = Recency::of(conversation.updated_at, now);
+        match groups.last_mut() {
+            Some(group) if group.recency == recency => group.conversations.push(conversation),
+            _ => groups.push(RecencyGroup {
+                recency,
+                continued: groups.is_empty() && previous == Some(recency),
+                conversations: vec![conversation],
+            }),
+        }
+    }
+    groups
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow, PartialEq)]
+pub struct Folder {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    /// model of conversations created inside the folder, the global default when not set
+    pub llm_model: Option<String>,
+    /// system prompt of conversations created inside the folder
+    pub system_prompt: Option<String>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Folder {
+    pub fn new(name: String, user_id: Uuid) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            name,
+            llm_model: None,
+            system_prompt: None,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, FromRow, PartialEq)]
+pub struct Tag {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Tag {
+    pub fn new(name: String, user_id: Uuid) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            name,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(FromRow, Serialize, Debug, Clone, PartialEq)]
+pub struct User {
+    pub id: Uuid,
+    pub username: String,
+    /// empty for users provisioned by the reverse proxy, they cannot log in with a password
+    #[serde(skip)]
+    pub password_hash: String,
+    pub is_admin: bool,
+    pub created_at: DateTime<Utc>,
+    pub email: Option<String>,
+    /// identity sent by the reverse proxy, set for the users it provisioned only
+    pub proxy_subject: Option<String>,
+}
+
+impl User {
+    pub fn new(username: String, password_hash: String, is_admin: bool) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            username,
+            password_hash,
+            is_admin,
+            created_at: Utc::now(),
+            email: None,
+            proxy_subject: None,
+        }
+    }
+}
+
+#[derive(FromRow, Debug, Clone, PartialEq)]
+pub struct Session {
+    /// SHA-256 of the cookie value
+    pub token_hash: Vec<u8>,
+    pub user_id: Uuid,
+    pub expires_at: DateTime<Utc>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Session {
+    pub fn new(token_hash: Vec<u8>, user_id: Uuid, expires_at: DateTime<Utc>) -> Self {
+        Self {
+            token_hash,
+            user_id,
+            expires_at,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TokenScope {
+    /// `GET` requests to the API
+    #[serde(rename = "read")]
+    Read,
+    /// everything else, including prompts sent over the websocket
+    #[serde(rename = "write")]
+    Write,
+}
+
+impl std::fmt::Display for TokenScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TokenScope::Read => write!(f, "read"),
+            TokenScope::Write => write!(f, "write"),
+        }
+    }
+}
+
+/// Personal access token sent as `Authorization: Bearer <token>`.
+#[derive(FromRow, Serialize, Debug, Clone, PartialEq)]
+pub struct ApiToken {
+    pub id: Uuid,
+    pub user_id: Uuid,
+    pub name: String,
+    /// SHA-256 of the token, the token itself is shown only once
+    #[serde(skip)]
+    pub token_hash: Vec<u8>,
+    pub scopes: Json<Vec<TokenScope>>,
+    pub last_used_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ApiToken {
+    pub fn new(user_id: Uuid, name: String, token_hash: Vec<u8>, scopes: Vec<TokenScope>) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            user_id,
+            name,
+            token_hash,
+            scopes: Json(scopes),
+            last_used_at: None,
+            created_at: Utc::now(),
+        }
+    }
+
+    pub fn has_scope(&self, scope: TokenScope) -> bool {
+        self.scopes.0.contains(&scope)
+    }
+
+    pub fn scopes(&self) -> String {
+        self.scopes
+            .0
+            .iter()
+            .map(|scope| scope.to_string())
+            .collect::<Vec<_>>()
+            .join(", ")
+    }
+}
+
+/// Read-only link to a conversation, anyone knowing the token can read it until it expires.
+#[derive(FromRow, Debug, Clone, PartialEq)]
+pub struct ShareLink {
+    pub id: Uuid,
+    pub conversation_id: Uuid,
+    /// SHA-256 of the token
+    pub token_hash: Vec<u8>,
+    /// only known right after the link is created, it's shown once
+    #[sqlx(skip)]
+    pub token: Option<String>,
+    pub expires_at: Option<DateTime<Utc>>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl ShareLink {
+    pub fn new(
+        conversation_id: Uuid,
+        token_hash: Vec<u8>,
+        expires_at: Option<DateTime<Utc>>,
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            conversation_id,
+            token_hash,
+            token: None,
+            expires_at,
             created_at: Utc::now(),
         }
     }
+
+    pub fn path(&self) -> Option<String> {
+        self.token.as_ref().map(|token| format!("/s/{token}"))
+    }
+
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= Utc::now())
+    }
 }
 
-#[derive(FromRow, Deserialize, Debug, Clone)]
+#[derive(FromRow, Deserialize, Serialize, Debug, Clone)]
 pub struct ConversationSettings {
     pub id: Uuid,
     pub llm_model: String,
     pub conversation_id: Uuid,
     pub created_at: DateTime<Utc>,
+    /// `json` or a JSON Schema, plain text responses when not set
+    pub output_format: Option<String>,
+    /// models answering every prompt side by side, a single model when not set
+    pub comparison_models: Option<Json<Vec<String>>>,
+    /// sent before the conversation, inherited from the folder the conversation was created in
+    pub system_prompt: Option<String>,
 }
 
 impl ConversationSettings {
@@ -149,10 +763,182 @@ impl ConversationSettings {
             llm_model,
             conversation_id,
             created_at: Utc::now(),
+            output_format: None,
+            comparison_models: None,
+            system_prompt: None,
         }
     }
 }
 
+/// Backup written by `lokai export`, attachments and documents aren't included.
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct Export {
+    pub exported_at: DateTime<Utc>,
+    pub conversations: Vec<ConversationExport>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct ConversationExport {
+    /// username, users don't have the same ids in another LokAI instance.
+    /// `None` for conversations from before user accounts.
+    pub owner: Option<String>,
+    pub conversation: Conversation,
+    pub settings: Option<ConversationSettings>,
+    pub messages: Vec<Message>,
+}
+
+impl ConversationExport {
+    /// Fresh ids, so importing never clashes with the conversation it was exported from.
+    pub fn with_new_ids(self, user_id: Uuid) -> Self {
+        let mut ids: HashMap<Uuid, Uuid> = HashMap::new();
+        let mut new_id = |id: Uuid| *ids.entry(id).or_insert_with(Uuid::new_v4);
+
+        let conversation = Conversation {
+            id: new_id(self.conversation.id),
+            user_id: Some(user_id),
+            folder_id: None,
+            tags: self
+                .conversation
+                .tags
+                .into_iter()
+                .map(|tag| Tag::new(tag.name, user_id))
+                .collect(),
+            ..self.conversation
+        };
+        let settings = self.settings.map(|settings| ConversationSettings {
+            id: Uuid::new_v4(),
+            conversation_id: conversation.id,
+            ..settings
+        });
+        let messages = self
+            .messages
+            .into_iter()
+            .map(|message| Message {
+                id: new_id(message.id),
+                conversation_id: conversation.id,
+                comparison_id: message.comparison_id.map(&mut new_id),
+                attachment_ids: Vec::new(),
+                citations: Vec::new(),
+                feedback: None,
+                ..message
+            })
+            .collect();
+
+        Self {
+            owner: self.owner,
+            conversation,
+            settings,
+            messages,
+        }
+    }
+}
+
+#[derive(FromRow, Serialize, Debug, Clone, PartialEq)]
+pub struct Document {
+    pub id: Uuid,
+    pub conversation_id: Uuid,
+    pub name: String,
+    pub mime_type: String,
+    pub created_at: DateTime<Utc>,
+}
+
+impl Document {
+    pub fn new(name: String, mime_type: String, conversation_id: Uuid) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            conversation_id,
+            name,
+            mime_type,
+            created_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(FromRow, Debug, Clone, PartialEq)]
+pub struct DocumentChunk {
+    pub id: Uuid,
+    pub document_id: Uuid,
+    pub conversation_id: Uuid,
+    pub chunk_index: i64,
+    pub content: String,
+    /// little-endian f32 vector returned by the embedding model
+    pub embedding: Vec<u8>,
+    pub created_at: DateTime<Utc>,
+}
+
+impl DocumentChunk {
+    pub fn new(
+        document_id: Uuid,
+        conversation_id: Uuid,
+        chunk_index: i64,
+        content: String,
+        embedding: &[f32],
+    ) -> Self {
+        Self {
+            id: Uuid::new_v4(),
+            document_id,
+            conversation_id,
+            chunk_index,
+            content,
+            embedding: crate::rag::embedding_to_bytes(embedding),
+            created_at: Utc::now(),
+        }
+    }
+}
+
+#[derive(FromRow, Deserialize, Serialize, Debug, Clone, PartialEq)]
+pub struct Citation {
+    pub message_id: Uuid,
+    pub chunk_id: Uuid,
+    pub rank: i64,
+    pub document_id: Uuid,
+    pub document_name: String,
+    pub chunk_index: i64,
+}
+
+#[derive(FromRow, Debug, Clone, PartialEq)]
+pub struct MessageSearchResult {
+    pub conversation_id: Uuid,
+    pub conversation_name: String,
+    pub role: Role,
+    pub content: String,
+    pub created_at: DateTime<Utc>,
+}
+
+#[derive(FromRow, Serialize, Debug, Clone, PartialEq)]
+pub struct DailyUsage {
+    pub day: String,
+    pub messages: i64,
+    pub tokens_generated: i64,
+}
+
+#[derive(FromRow, Serialize, Debug, Clone, PartialEq)]
+pub struct ModelUsage {
+    pub llm_model: String,
+    pub messages: i64,
+    pub tokens_generated: i64,
+    pub avg_tokens_per_second: Option<f64>,
+    pub avg_time_to_first_token_ms: Option<f64>,
+    /// answers given in comparisons and how many of them were picked as the best one
+    pub comparisons: i64,
+    pub wins: i64,
+}
+
+#[derive(FromRow, Serialize, Debug, Clone, PartialEq)]
+pub struct ConversationUsage {
+    pub conversation_id: Uuid,
+    pub name: String,
+    pub messages: i64,
+    pub tokens_generated: i64,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct UsageStats {
+    pub daily: Vec<DailyUsage>,
+    pub models: Vec<ModelUsage>,
+    pub busiest_conversations: Vec<ConversationUsage>,
+}
+
 #[cfg(test)]
 mod tests {
     use serde_json::json;
@@ -169,6 +955,7 @@ mod tests {
         );
         let user_prompt_form_message = UserPromptFormMessage {
             user_prompt: "".to_string(),
+            attachment_ids: Vec::new(),
             HEADERS: htmx_headers,
         };
 
@@ -181,4 +968,288 @@ mod tests {
             Uuid::from_str("a310afea-981e-4054-924a-37090ac227e2").unwrap()
         );
     }
+
+    #[test]
+    fn test_conversation_id_from_url() {
+        // given:
+        let conversation = "http://localhost:3000/c/a310afea-981e-4054-924a-37090ac227e2";
+        let index = "http://localhost:3000/";
+        let invalid = "http://localhost:3000/c/not-a-uuid";
+
+        // when:
+        let ids = [conversation, index, invalid].map(conversation_id_from_url);
+
+        // then:
+        assert_eq!(
+            ids,
+            [
+                Some(Uuid::from_str("a310afea-981e-4054-924a-37090ac227e2").unwrap()),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn test_deserialize_attachment_ids() {
+        // given:
+        let id = "a310afea-981e-4054-924a-37090ac227e2";
+        let none = r#"{"user_prompt": "", "HEADERS": {}}"#;
+        let one = format!(r#"{{"user_prompt": "", "attachment_ids": "{id}", "HEADERS": {{}}}}"#);
+        let many = format!(
+            r#"{{"user_prompt": "", "attachment_ids": ["{id}", "{id}"], "HEADERS": {{}}}}"#
+        );
+
+        // when:
+        let none = serde_json::from_str::<UserPromptFormMessage>(none).unwrap();
+        let one = serde_json::from_str::<UserPromptFormMessage>(&one).unwrap();
+        let many = serde_json::from_str::<UserPromptFormMessage>(&many).unwrap();
+
+        // then:
+        let id = Uuid::from_str(id).unwrap();
+        assert_eq!(none.attachment_ids, Vec::new());
+        assert_eq!(one.attachment_ids, vec![id]);
+        assert_eq!(many.attachment_ids, vec![id, id]);
+    }
+
+    #[test]
+    fn test_tokens_per_second() {
+        // given:
+        let stats = GenerationStats {
+            eval_count: Some(100),
+            eval_duration: Some(2_000_000_000),
+            ..Default::default()
+        };
+
+        // when:
+        let tokens_per_second = stats.tokens_per_second();
+
+        // then:
+        assert_eq!(tokens_per_second, Some(50.0));
+        assert_eq!(GenerationStats::default().tokens_per_second(), None);
+    }
+
+    fn comparison_message(comparison_id: Uuid, winner: bool) -> Message {
+        let mut message = Message::comparison("model".to_string(), comparison_id, Uuid::nil());
+        message.winner = winner;
+        message
+    }
+
+    #[test]
+    fn test_group_messages() {
+        // given:
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let messages = vec![
+            Message::user("1".to_string(), Uuid::nil()),
+            comparison_message(first, false),
+            comparison_message(first, false),
+            Message::user("2".to_string(), Uuid::nil()),
+            comparison_message(second, false),
+            comparison_message(second, false),
+            comparison_message(second, false),
+        ];
+
+        // when:
+        let groups = group_messages(messages);
+
+        // then:
+        assert_eq!(
+            groups
+                .iter()
+                .map(|g| (g.comparison_id, g.messages.len()))
+                .collect::<Vec<_>>(),
+            vec![(None, 1), (Some(first), 2), (None, 1), (Some(second), 3)]
+        );
+    }
+
+    #[test]
+    fn test_cursor_roundtrip() {
+        // given:
+        let conversation = Conversation::new("name".to_string(), Uuid::new_v4());
+
+        // when:
+        let cursor = conversation.cursor().to_string().parse::<Cursor>();
+
+        // then:
+        assert_eq!(cursor, Ok(conversation.cursor()));
+        assert!("123".parse::<Cursor>().is_err());
+        assert!("abc_def".parse::<Cursor>().is_err());
+    }
+
+    #[test]
+    fn test_group_by_recency() {
+        // given:
+        let now = DateTime::parse_from_rfc3339("2026-10-19T08:00:00Z")
+            .unwrap()
+            .to_utc();
+        let updated = |name: &str, at: &str| {
+            let mut conversation = Conversation::new(name.to_string(), Uuid::new_v4());
+            conversation.updated_at = DateTime::parse_from_rfc3339(at).unwrap().to_utc();
+            conversation
+        };
+        let conversations = vec![
+            updated("a", "2026-10-19T07:00:00Z"),
+            updated("b", "2026-10-18T23:00:00Z"),
+            updated("c", "2026-10-12T01:00:00Z"),
+            updated("d", "2026-10-11T23:00:00Z"),
+        ];
+
+        // when:
+        let groups = group_by_recency(conversations.clone(), now, None);
+        let next_page =
+            group_by_recency(conversations[1..].to_vec(), now, Some(Recency::Yesterday));
+
+        // then:
+        fn summary(groups: &[RecencyGroup]) -> Vec<(&'static str, bool, Vec<&str>)> {
+            groups
+                .iter()
+                .map(|g| {
+                    let names = g.conversations.iter().map(|c| c.name.as_str()).collect();
+                    (g.recency.label(), g.continued, names)
+                })
+                .collect()
+        }
+        assert_eq!(
+            summary(&groups),
+            vec![
+                ("Today", false, vec!["a"]),
+                ("Yesterday", false, vec!["b"]),
+                ("Previous 7 days", false, vec!["c"]),
+                ("Older", false, vec!["d"]),
+            ]
+        );
+        assert_eq!(summary(&next_page)[0], ("Yesterday", true, vec!["b"]));
+        assert!(!next_page[1].continued);
+    }
+
+    #[test]
+    fn test_parse_comparison_models() {
+        assert_eq!(parse_comparison_models(" "), Ok(None));
+        assert_eq!(
+            parse_comparison_models("phi3, llama3 ,phi3"),
+            Ok(Some(vec!["phi3".to_string(), "llama3".to_string()]))
+        );
+        assert!(parse_comparison_models("phi3").is_err());
+        assert!(parse_comparison_models("a,b,c,d,e").is_err());
+    }
+
+    #[test]
+    fn test_prune_comparisons_keeps_winner_or_first() {
+        // given:
+        let (first, second) = (Uuid::new_v4(), Uuid::new_v4());
+        let messages = vec![
+            comparison_message(first, false),
+            comparison_message(first, true),
+            comparison_message(second, false),
+            comparison_message(second, false),
+        ];
+        let (winner, first_answer) = (messages[1].id, messages[2].id);
+
+        // when:
+        let pruned = prune_comparisons(messages);
+
+        // then:
+        assert_eq!(
+            pruned.iter().map(|m| m.id).collect::<Vec<_>>(),
+            vec![winner, first_answer]
+        );
+    }
+
+    #[test]
+    fn test_feedback_example_skips_comparison_siblings() {
+        // given:
+        let comparison_id = Uuid::new_v4();
+        let question = Message::user("question".to_string(), Uuid::nil());
+        let sibling = comparison_message(comparison_id, false);
+        let mut answer = comparison_message(comparison_id, false);
+        answer.update_content("answer");
+        let feedback = Feedback::new(answer.id, 1, Some("good".to_string()));
+
+        // when:
+        let example = FeedbackExample::new(answer, vec![question, sibling], feedback);
+
+        // then:
+        assert_eq!(
+            example.history,
+            vec![ExampleMessage {
+                role: Role::User,
+                content: "question".to_string()
+            }]
+        );
+        assert_eq!(example.answer, "answer");
+        assert_eq!(example.llm_model, Some("model".to_string()));
+        assert_eq!(example.rating, 1);
+    }
+
+    #[test]
+    fn test_role_try_from() {
+        // given:
+        let values = ["system", "User", " assistant ", "tool", "moderator", ""];
+
+        // when:
+        let roles: Vec<_> = values.iter().map(|value| Role::try_from(*value)).collect();
+
+        // then:
+        assert_eq!(
+            roles,
+            vec![
+                Ok(Role::System),
+                Ok(Role::User),
+                Ok(Role::Assistant),
+                Ok(Role::Tool),
+                Err("unknown role: moderator".to_string()),
+                Err("unknown role: ".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_conversation_export_with_new_ids() {
+        // given:
+        let user_id = Uuid::new_v4();
+        let mut conversation = Conversation::new("trip".to_string(), Uuid::new_v4());
+        conversation.folder_id = Some(Uuid::new_v4());
+        let comparison_id = Uuid::new_v4();
+        let mut question = Message::user("question".to_string(), conversation.id);
+        question.attachment_ids = vec![Uuid::new_v4()];
+        let messages = vec![
+            question,
+            Message::comparison("a".to_string(), comparison_id, conversation.id),
+            Message::comparison("b".to_string(), comparison_id, conversation.id),
+        ];
+        let export = ConversationExport {
+            owner: Some("admin".to_string()),
+            conversation: conversation.clone(),
+            settings: Some(ConversationSettings::new(
+                "model".to_string(),
+                conversation.id,
+            )),
+            messages: messages.clone(),
+        };
+
+        // when:
+        let imported = export.with_new_ids(user_id);
+
+        // then:
+        assert_ne!(imported.conversation.id, conversation.id);
+        assert_eq!(imported.conversation.name, "trip");
+        assert_eq!(imported.conversation.user_id, Some(user_id));
+        assert_eq!(imported.conversation.folder_id, None);
+        assert_eq!(
+            imported.settings.unwrap().conversation_id,
+            imported.conversation.id
+        );
+        assert_ne!(imported.messages[0].id, messages[0].id);
+        assert!(imported.messages[0].attachment_ids.is_empty());
+        assert!(imported
+            .messages
+            .iter()
+            .all(|message| message.conversation_id == imported.conversation.id));
+        let comparison_ids: Vec<_> = imported.messages[1..]
+            .iter()
+            .map(|message| message.comparison_id.unwrap())
+            .collect();
+        assert_eq!(comparison_ids[0], comparison_ids[1]);
+        assert_ne!(comparison_ids[0], comparison_id);
+    }
 }
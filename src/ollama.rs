@@ -1,31 +1,125 @@
 use serde::{Deserialize, Serialize};
 
-use crate::models::{Message, Role};
+use crate::error::Result;
+use crate::models::{GenerationStats, Message, Role, ToolCall};
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct OllamaMessage {
     pub role: Role,
     pub content: String,
+    /// base64 encoded images, only understood by vision models
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub images: Option<Vec<String>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_calls: Option<Vec<OllamaToolCall>>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tool_name: Option<String>,
+}
+
+impl OllamaMessage {
+    fn new(role: Role, content: String) -> Self {
+        Self {
+            role,
+            content,
+            images: None,
+            tool_calls: None,
+            tool_name: None,
+        }
+    }
+
+    pub fn system(content: String) -> Self {
+        Self::new(Role::System, content)
+    }
+
+    pub fn user(content: String) -> Self {
+        Self::new(Role::User, content)
+    }
 }
 
 impl From<Message> for OllamaMessage {
     fn from(value: Message) -> Self {
+        let tool_calls = value.tool_calls.map(|tool_calls| {
+            tool_calls
+                .0
+                .into_iter()
+                .map(|tool_call| OllamaToolCall {
+                    function: OllamaToolCallFunction {
+                        name: tool_call.name,
+                        arguments: tool_call.arguments,
+                    },
+                })
+                .collect()
+        });
         Self {
-            role: Role::from(value.role.as_ref()),
+            role: value.role,
             content: value.content,
+            images: None,
+            tool_calls,
+            tool_name: value.tool_name,
         }
     }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
-pub struct OllamaChatResponse {
-    pub message: OllamaMessage,
+pub struct OllamaToolCallFunction {
+    pub name: String,
+    pub arguments: serde_json::Value,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct OllamaToolCall {
+    pub function: OllamaToolCallFunction,
+}
+
+impl From<OllamaToolCall> for ToolCall {
+    fn from(value: OllamaToolCall) -> Self {
+        Self {
+            name: value.function.name,
+            arguments: value.function.arguments,
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OllamaToolFunction {
+    pub name: String,
+    pub description: String,
+    /// JSON Schema of the function arguments
+    pub parameters: serde_json::Value,
+}
+
+#[derive(Serialize, Debug, Clone)]
+pub struct OllamaTool {
+    pub r#type: String,
+    pub function: OllamaToolFunction,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct OllamaChatResponseStream {
+    pub model: String,
     pub message: OllamaMessage,
     pub done: bool,
+    // generation statistics are only present in the final chunk
+    pub total_duration: Option<i64>,
+    pub load_duration: Option<i64>,
+    pub prompt_eval_count: Option<i64>,
+    pub prompt_eval_duration: Option<i64>,
+    pub eval_count: Option<i64>,
+    pub eval_duration: Option<i64>,
+}
+
+impl From<&OllamaChatResponseStream> for GenerationStats {
+    fn from(value: &OllamaChatResponseStream) -> Self {
+        Self {
+            llm_model: Some(value.model.clone()),
+            total_duration: value.total_duration,
+            load_duration: value.load_duration,
+            prompt_eval_count: value.prompt_eval_count,
+            prompt_eval_duration: value.prompt_eval_duration,
+            eval_count: value.eval_count,
+            eval_duration: value.eval_duration,
+        }
+    }
 }
 
 #[derive(Serialize, Debug)]
@@ -33,4 +127,224 @@ pub struct OllamaChatParams {
     pub model: String,
     pub messages: Vec<OllamaMessage>,
     pub stream: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tools: Option<Vec<OllamaTool>>,
+    /// `"json"` or a JSON Schema constraining the response
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub format: Option<serde_json::Value>,
+}
+
+#[derive(Serialize, Debug)]
+struct OllamaShowParams<'a> {
+    model: &'a str,
+}
+
+#[derive(Deserialize, Debug, Default)]
+struct OllamaModelDetails {
+    #[serde(default)]
+    families: Vec<String>,
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaShowResponse {
+    #[serde(default)]
+    capabilities: Vec<String>,
+    #[serde(default)]
+    details: OllamaModelDetails,
+}
+
+/// Features of a model relevant for building chat requests.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ModelCapabilities {
+    pub vision: bool,
+    pub tools: bool,
+}
+
+impl OllamaShowResponse {
+    fn supports_tools(&self) -> bool {
+        self.capabilities.iter().any(|c| c == "tools")
+    }
+
+    fn supports_vision(&self) -> bool {
+        // older Ollama versions don't report capabilities, but vision models ship with a projector
+        self.capabilities.iter().any(|c| c == "vision")
+            || self
+                .details
+                .families
+                .iter()
+                .any(|f| f == "clip" || f == "mllama")
+    }
+}
+
+pub async fn model_capabilities(
+    reqwest_client: &reqwest::Client,
+    ollama_url: &str,
+    model: &str,
+) -> Result<ModelCapabilities> {
+    let response: OllamaShowResponse = reqwest_client
+        .post(format!("{}/api/show", ollama_url))
+        .json(&OllamaShowParams { model })
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(ModelCapabilities {
+        vision: response.supports_vision(),
+        tools: response.supports_tools(),
+    })
+}
+
+#[derive(Serialize, Debug)]
+struct OllamaEmbedParams<'a> {
+    model: &'a str,
+    input: &'a [String],
+}
+
+#[derive(Deserialize, Debug)]
+struct OllamaEmbedResponse {
+    embeddings: Vec<Vec<f32>>,
+}
+
+pub async fn embed(
+    reqwest_client: &reqwest::Client,
+    ollama_url: &str,
+    model: &str,
+    input: &[String],
+) -> Result<Vec<Vec<f32>>> {
+    let response: OllamaEmbedResponse = reqwest_client
+        .post(format!("{}/api/embed", ollama_url))
+        .json(&OllamaEmbedParams { model, input })
+        .send()
+        .await?
+        .error_for_status()?
+        .json()
+        .await?;
+
+    Ok(response.embeddings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_deserialize_final_chunk_stats() {
+        // given:
+        let chunk = r#"{
+            "model": "phi3:3.8b",
+            "created_at": "2024-08-04T19:22:45.499127Z",
+            "message": { "role": "assistant", "content": "" },
+            "done": true,
+            "total_duration": 4883583458,
+            "load_duration": 1334875,
+            "prompt_eval_count": 26,
+            "prompt_eval_duration": 342546000,
+            "eval_count": 282,
+            "eval_duration": 4535599000
+        }"#;
+
+        // when:
+        let chunk = serde_json::from_str::<OllamaChatResponseStream>(chunk).unwrap();
+        let stats = GenerationStats::from(&chunk);
+
+        // then:
+        assert!(chunk.done);
+        assert_eq!(stats.llm_model, Some("phi3:3.8b".to_string()));
+        assert_eq!(stats.prompt_eval_count, Some(26));
+        assert_eq!(stats.eval_count, Some(282));
+        assert_eq!(stats.eval_duration, Some(4535599000));
+    }
+
+    #[test]
+    fn test_deserialize_intermediate_chunk_without_stats() {
+        // given:
+        let chunk = r#"{
+            "model": "phi3:3.8b",
+            "created_at": "2024-08-04T19:22:45.499127Z",
+            "message": { "role": "assistant", "content": "The" },
+            "done": false
+        }"#;
+
+        // when:
+        let chunk = serde_json::from_str::<OllamaChatResponseStream>(chunk).unwrap();
+
+        // then:
+        assert!(!chunk.done);
+        assert_eq!(GenerationStats::from(&chunk).eval_count, None);
+    }
+
+    #[test]
+    fn test_show_response_supports_vision() {
+        // given:
+        let with_capabilities = r#"{"capabilities": ["completion", "vision"]}"#;
+        let with_projector = r#"{"details": {"families": ["llama", "clip"]}}"#;
+        let text_only = r#"{"capabilities": ["completion"], "details": {"families": ["phi3"]}}"#;
+
+        // when:
+        let parse = |s| serde_json::from_str::<OllamaShowResponse>(s).unwrap();
+
+        // then:
+        assert!(parse(with_capabilities).supports_vision());
+        assert!(parse(with_projector).supports_vision());
+        assert!(!parse(text_only).supports_vision());
+    }
+
+    #[test]
+    fn test_show_response_supports_tools() {
+        // given:
+        let with_tools = r#"{"capabilities": ["completion", "tools"]}"#;
+        let without_tools = r#"{"capabilities": ["completion"]}"#;
+
+        // when:
+        let parse = |s| serde_json::from_str::<OllamaShowResponse>(s).unwrap();
+
+        // then:
+        assert!(parse(with_tools).supports_tools());
+        assert!(!parse(without_tools).supports_tools());
+    }
+
+    #[test]
+    fn test_deserialize_tool_calls_chunk() {
+        // given:
+        let chunk = r#"{
+            "model": "llama3.1",
+            "message": {
+                "role": "assistant",
+                "content": "",
+                "tool_calls": [
+                    { "function": { "name": "calculator", "arguments": { "expression": "1 + 1" } } }
+                ]
+            },
+            "done": false
+        }"#;
+
+        // when:
+        let chunk = serde_json::from_str::<OllamaChatResponseStream>(chunk).unwrap();
+        let tool_calls: Vec<ToolCall> = chunk
+            .message
+            .tool_calls
+            .unwrap()
+            .into_iter()
+            .map(ToolCall::from)
+            .collect();
+
+        // then:
+        assert_eq!(tool_calls.len(), 1);
+        assert_eq!(tool_calls[0].name, "calculator");
+        assert_eq!(tool_calls[0].arguments["expression"], "1 + 1");
+    }
+
+    #[test]
+    fn test_serialize_message_without_images() {
+        // given:
+        let message = OllamaMessage::from(Message::user("hi".to_string(), uuid::Uuid::new_v4()));
+
+        // when:
+        let value = serde_json::to_value(message).unwrap();
+
+        // then:
+        assert_eq!(value, serde_json::json!({"role": "user", "content": "hi"}));
+    }
 }
@@ -0,0 +1,247 @@
+use crate::config::CONFIG;
+use crate::error::{Error, Result};
+use crate::models::{Document, DocumentChunk};
+use crate::ollama;
+
+const CHUNK_MAX_CHARS: usize = 1000;
+const EMBED_BATCH_SIZE: usize = 32;
+
+pub enum DocumentKind {
+    Text,
+    Pdf,
+}
+
+impl DocumentKind {
+    // browsers are inconsistent with mime types of markdown files, so the extension wins
+    pub fn detect(file_name: &str, mime_type: &str) -> Option<Self> {
+        let extension = file_name
+            .rsplit_once('.')
+            .map(|(_, extension)| extension.to_lowercase());
+        match (extension.as_deref(), mime_type) {
+            (Some("pdf"), _) | (_, "application/pdf") => Some(Self::Pdf),
+            (Some("txt" | "md" | "markdown"), _) | (_, "text/plain" | "text/markdown") => {
+                Some(Self::Text)
+            }
+            _ => None,
+        }
+    }
+}
+
+pub async fn extract_text(kind: DocumentKind, data: Vec<u8>) -> Result<String> {
+    match kind {
+        DocumentKind::Text => {
+            String::from_utf8(data).map_err(|_| Error::Document("file is not valid UTF-8".into()))
+        }
+        // pdf-extract is CPU bound and may panic on malformed files
+        DocumentKind::Pdf => tokio::task::spawn_blocking(move || {
+            pdf_extract::extract_text_from_mem(&data)
+                .map_err(|err| Error::Document(err.to_string()))
+        })
+        .await
+        .map_err(|_| Error::Document("cannot extract text from PDF".into()))?,
+    }
+}
+
+/// Splits text into chunks of at most `CHUNK_MAX_CHARS` characters, keeping paragraphs together when possible.
+pub fn chunk_text(text: &str) -> Vec<String> {
+    let mut chunks = Vec::new();
+    let mut current = String::new();
+
+    for paragraph in text.split("\n\n").map(str::trim).filter(|p| !p.is_empty()) {
+        if !current.is_empty()
+            && current.chars().count() + paragraph.chars().count() + 2 > CHUNK_MAX_CHARS
+        {
+            chunks.push(std::mem::take(&mut current));
+        }
+        if paragraph.chars().count() > CHUNK_MAX_CHARS {
+            let mut window = String::new();
+            for word in paragraph.split_whitespace() {
+                if !window.is_empty()
+                    && window.chars().count() + word.chars().count() + 1 > CHUNK_MAX_CHARS
+                {
+                    chunks.push(std::mem::take(&mut window));
+                }
+                if !window.is_empty() {
+                    window.push(' ');
+                }
+                window.push_str(word);
+            }
+            current = window;
+            continue;
+        }
+        if !current.is_empty() {
+            current.push_str("\n\n");
+        }
+        current.push_str(paragraph);
+    }
+    if !current.is_empty() {
+        chunks.push(current);
+    }
+
+    chunks
+}
+
+pub async fn embed_document(
+    reqwest_client: &reqwest::Client,
+    document: &Document,
+    text: &str,
+) -> Result<Vec<DocumentChunk>> {
+    let contents = chunk_text(text);
+    let mut chunks = Vec::with_capacity(contents.len());
+
+    for batch in contents.chunks(EMBED_BATCH_SIZE) {
+        let embeddings = ollama::embed(
+            reqwest_client,
+            &CONFIG.ollama_url,
+            &CONFIG.lokai_embedding_model,
+            batch,
+        )
+        .await?;
+        if embeddings.len() != batch.len() {
+            return Err(Error::Document(
+                "embedding model returned unexpected number of vectors".into(),
+            ));
+        }
+        for (content, embedding) in batch.iter().zip(embeddings) {
+            chunks.push(DocumentChunk::new(
+                document.id,
+                document.conversation_id,
+                chunks.len() as i64,
+                content.clone(),
+                &embedding,
+            ));
+        }
+    }
+
+    Ok(chunks)
+}
+
+pub fn embedding_to_bytes(embedding: &[f32]) -> Vec<u8> {
+    embedding.iter().flat_map(|v| v.to_le_bytes()).collect()
+}
+
+pub fn embedding_from_bytes(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+        .collect()
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot: f32 = a.iter().zip(b).map(|(x, y)| x * y).sum();
+    let norm_a = a.iter().map(|x| x * x).sum::<f32>().sqrt();
+    let norm_b = b.iter().map(|x| x * x).sum::<f32>().sqrt();
+    if norm_a == 0.0 || norm_b == 0.0 {
+        return 0.0;
+    }
+    dot / (norm_a * norm_b)
+}
+
+pub fn top_k(query: &[f32], chunks: Vec<DocumentChunk>, k: usize) -> Vec<DocumentChunk> {
+    let mut scored: Vec<(f32, DocumentChunk)> = chunks
+        .into_iter()
+        .map(|chunk| {
+            let score = cosine_similarity(query, &embedding_from_bytes(&chunk.embedding));
+            (score, chunk)
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+    scored.into_iter().take(k).map(|(_, chunk)| chunk).collect()
+}
+
+/// Builds a system prompt with the retrieved chunks, numbered the same way as citations.
+pub fn context_prompt(chunks: &[(String, DocumentChunk)]) -> String {
+    let mut prompt = String::from(
+        "Answer using the excerpts from the user's documents below when they are relevant. \
+        Cite the excerpts you used by their number, e.g. [1].\n",
+    );
+    for (i, (document_name, chunk)) in chunks.iter().enumerate() {
+        prompt.push_str(&format!(
+            "\n[{}] {}:\n{}\n",
+            i + 1,
+            document_name,
+            chunk.content
+        ));
+    }
+    prompt
+}
+
+#[cfg(test)]
+mod tests {
+    use uuid::Uuid;
+
+    use super::*;
+
+    fn chunk(embedding: &[f32]) -> DocumentChunk {
+        DocumentChunk::new(Uuid::new_v4(), Uuid::new_v4(), 0, "".to_string(), embedding)
+    }
+
+    #[test]
+    fn test_chunk_text_keeps_paragraphs_together() {
+        // given:
+        let text = "first paragraph\n\nsecond paragraph\n\n\n\nthird";
+
+        // when:
+        let chunks = chunk_text(text);
+
+        // then:
+        assert_eq!(chunks, vec!["first paragraph\n\nsecond paragraph\n\nthird"]);
+    }
+
+    #[test]
+    fn test_chunk_text_splits_long_text() {
+        // given:
+        let paragraph = "word ".repeat(300);
+        let text = format!("{paragraph}\n\n{paragraph}");
+
+        // when:
+        let chunks = chunk_text(&text);
+
+        // then:
+        assert!(chunks.len() >= 3);
+        assert!(chunks.iter().all(|c| c.chars().count() <= CHUNK_MAX_CHARS));
+    }
+
+    #[test]
+    fn test_embedding_bytes_roundtrip() {
+        // given:
+        let embedding = vec![0.5, -1.25, 3.0];
+
+        // when:
+        let bytes = embedding_to_bytes(&embedding);
+
+        // then:
+        assert_eq!(bytes.len(), 12);
+        assert_eq!(embedding_from_bytes(&bytes), embedding);
+    }
+
+    #[test]
+    fn test_top_k_orders_by_similarity() {
+        // given:
+        let chunks = vec![chunk(&[0.0, 1.0]), chunk(&[1.0, 0.1]), chunk(&[1.0, 0.0])];
+        let best = chunks[2].id;
+        let second = chunks[1].id;
+
+        // when:
+        let top = top_k(&[1.0, 0.0], chunks, 2);
+
+        // then:
+        assert_eq!(
+            top.iter().map(|c| c.id).collect::<Vec<_>>(),
+            vec![best, second]
+        );
+    }
+
+    #[test]
+    fn test_detect_document_kind() {
+        assert!(matches!(
+            DocumentKind::detect("a.md", "application/octet-stream"),
+            Some(DocumentKind::Text)
+        ));
+        assert!(matches!(
+            DocumentKind::detect("a", "application/pdf"),
+            Some(DocumentKind::Pdf)
+        ));
+        assert!(DocumentKind::detect("a.png", "image/png").is_none());
+    }
+}
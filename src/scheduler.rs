@@ -0,0 +1,258 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::{Arc, Mutex};
+
+use tokio::sync::watch;
+use tracing::debug;
+use uuid::Uuid;
+
+#[derive(Default)]
+struct Backend {
+    running: usize,
+    /// slots granted so far, orders the users by their last turn
+    granted: u64,
+    last_turns: HashMap<Uuid, u64>,
+    /// tickets of every user in the order they were queued, with their queue positions
+    waiting: HashMap<Uuid, VecDeque<(u64, watch::Sender<usize>)>>,
+    /// users with waiting tickets, whoever had a slot least recently is first
+    turns: VecDeque<Uuid>,
+}
+
+impl Backend {
+    fn grant(&mut self, user_id: Uuid) {
+        self.running += 1;
+        self.granted += 1;
+        self.last_turns.insert(user_id, self.granted);
+    }
+
+    fn push(&mut self, user_id: Uuid, ticket: u64, position: watch::Sender<usize>) {
+        let tickets = self.waiting.entry(user_id).or_default();
+        if tickets.is_empty() {
+            let last_turn = self.last_turns.get(&user_id).copied().unwrap_or(0);
+            let i = self.turns.partition_point(|waiting| {
+                self.last_turns.get(waiting).copied().unwrap_or(0) <= last_turn
+            });
+            self.turns.insert(i, user_id);
+        }
+        tickets.push_back((ticket, position));
+    }
+
+    /// Takes the first ticket of the user whose turn it is, the user then waits for all others.
+    fn pop(&mut self) -> Option<(Uuid, watch::Sender<usize>)> {
+        let user_id = self.turns.pop_front()?;
+        let tickets = self.waiting.get_mut(&user_id)?;
+        let (_, position) = tickets.pop_front()?;
+        if tickets.is_empty() {
+            self.waiting.remove(&user_id);
+        } else {
+            self.turns.push_back(user_id);
+        }
+        Some((user_id, position))
+    }
+
+    /// Drops a ticket which gave up before its turn, false when it isn't waiting.
+    fn remove(&mut self, user_id: Uuid, ticket: u64) -> bool {
+        let Some(tickets) = self.waiting.get_mut(&user_id) else {
+            return false;
+        };
+        let Some(i) = tickets.iter().position(|(waiting, _)| *waiting == ticket) else {
+            return false;
+        };
+        tickets.remove(i);
+        if tickets.is_empty() {
+            self.waiting.remove(&user_id);
+            self.turns.retain(|waiting| *waiting != user_id);
+        }
+        true
+    }
+
+    /// Waiting tickets in the order they get a slot, users take turns.
+    fn queue(&self) -> impl Iterator<Item = &watch::Sender<usize>> {
+        let longest = self.waiting.values().map(VecDeque::len).max().unwrap_or(0);
+        (0..longest).flat_map(move |turn| {
+            self.turns.iter().filter_map(move |user_id| {
+                let (_, position) = self.waiting.get(user_id)?.get(turn)?;
+                Some(position)
+            })
+        })
+    }
+
+    fn notify_positions(&self) {
+        for (i, position) in self.queue().enumerate() {
+            let _ = position.send(i + 1);
+        }
+    }
+}
+
+/// Limits concurrent generations per backend, waiting users take turns so no one holds up the others.
+pub struct Scheduler {
+    max_concurrent: usize,
+    next_ticket: Mutex<u64>,
+    backends: Mutex<HashMap<String, Backend>>,
+}
+
+impl Scheduler {
+    pub fn new(max_concurrent: usize) -> Self {
+        Self {
+            max_concurrent: max_concurrent.max(1),
+            next_ticket: Mutex::new(0),
+            backends: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Takes a place in the queue of the backend, the generation may start once the slot is ready.
+    pub fn enqueue(self: &Arc<Self>, backend: &str, user_id: Uuid) -> Slot {
+        let ticket = {
+            let mut next_ticket = self.next_ticket.lock().unwrap();
+            *next_ticket += 1;
+            *next_ticket
+        };
+        let mut backends = self.backends.lock().unwrap();
+        let queue = backends.entry(backend.to_string()).or_default();
+        let (position_tx, position) = watch::channel(0);
+        if queue.running < self.max_concurrent && queue.turns.is_empty() {
+            queue.grant(user_id);
+        } else {
+            queue.push(user_id, ticket, position_tx);
+            queue.notify_positions();
+        }
+        debug!(
+            backend,
+            user_id = user_id.to_string(),
+            ticket,
+            position = *position.borrow(),
+            "generation queued"
+        );
+
+        Slot {
+            scheduler: self.clone(),
+            backend: backend.to_string(),
+            user_id,
+            ticket,
+            position,
+        }
+    }
+
+    fn leave(&self, backend: &str, user_id: Uuid, ticket: u64) {
+        let mut backends = self.backends.lock().unwrap();
+        let Some(queue) = backends.get_mut(backend) else {
+            return;
+        };
+        // gave up before its turn
+        if !queue.remove(user_id, ticket) {
+            queue.running -= 1;
+            if let Some((next_user_id, position)) = queue.pop() {
+                queue.grant(next_user_id);
+                let _ = position.send(0);
+            }
+        }
+        queue.notify_positions();
+        if queue.running == 0 && queue.turns.is_empty() {
+            backends.remove(backend);
+        }
+    }
+}
+
+/// Place of a generation in the queue, the running generation frees it when dropped.
+pub struct Slot {
+    scheduler: Arc<Scheduler>,
+    backend: String,
+    user_id: Uuid,
+    ticket: u64,
+    /// 0 once the generation may start
+    position: watch::Receiver<usize>,
+}
+
+impl Slot {
+    /// Waits for the turn of the generation, reporting every change of its position meanwhile.
+    pub async fn ready(&mut self, mut on_position: impl FnMut(usize)) {
+        loop {
+            let position = *self.position.borrow_and_update();
+            if position == 0 {
+                return;
+            }
+            on_position(position);
+            if self.position.changed().await.is_err() {
+                return;
+            }
+        }
+    }
+}
+
+impl Drop for Slot {
+    fn drop(&mut self) {
+        self.scheduler
+            .leave(&self.backend, self.user_id, self.ticket);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BACKEND: &str = "http://localhost:11434";
+    const USER_ID: Uuid = Uuid::from_u128(1);
+
+    #[test]
+    fn test_slots_are_granted_in_fifo_order() {
+        // given:
+        let scheduler = Arc::new(Scheduler::new(1));
+        let first = scheduler.enqueue(BACKEND, USER_ID);
+        let second = scheduler.enqueue(BACKEND, USER_ID);
+        let third = scheduler.enqueue(BACKEND, USER_ID);
+        let other_backend = scheduler.enqueue("http://gpu:11434", USER_ID);
+        let positions = |slots: &[&Slot]| -> Vec<usize> {
+            slots.iter().map(|slot| *slot.position.borrow()).collect()
+        };
+        assert_eq!(positions(&[&first, &second, &third]), vec![0, 1, 2]);
+        assert_eq!(positions(&[&other_backend]), vec![0]);
+
+        // when:
+        drop(first);
+
+        // then:
+        assert_eq!(positions(&[&second, &third]), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_leaving_queue_moves_others_forward() {
+        // given:
+        let scheduler = Arc::new(Scheduler::new(1));
+        let running = scheduler.enqueue(BACKEND, USER_ID);
+        let waiting = scheduler.enqueue(BACKEND, USER_ID);
+        let last = scheduler.enqueue(BACKEND, USER_ID);
+
+        // when:
+        drop(waiting);
+
+        // then:
+        assert_eq!(*last.position.borrow(), 1);
+        drop(running);
+        assert_eq!(*last.position.borrow(), 0);
+    }
+
+    #[test]
+    fn test_users_take_turns() {
+        // given:
+        let scheduler = Arc::new(Scheduler::new(1));
+        let other_user_id = Uuid::from_u128(2);
+        let first = scheduler.enqueue(BACKEND, USER_ID);
+        let second = scheduler.enqueue(BACKEND, USER_ID);
+        let third = scheduler.enqueue(BACKEND, USER_ID);
+        let others_first = scheduler.enqueue(BACKEND, other_user_id);
+        let positions = |slots: &[&Slot]| -> Vec<usize> {
+            slots.iter().map(|slot| *slot.position.borrow()).collect()
+        };
+        assert_eq!(
+            positions(&[&first, &second, &third, &others_first]),
+            vec![0, 2, 3, 1]
+        );
+
+        // when:
+        drop(first);
+
+        // then:
+        assert_eq!(positions(&[&second, &third, &others_first]), vec![1, 2, 0]);
+        drop(others_first);
+        assert_eq!(positions(&[&second, &third]), vec![0, 1]);
+    }
+}
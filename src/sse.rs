@@ -0,0 +1,157 @@
+use std::{collections::VecDeque, convert::Infallible};
+
+use axum::{
+    extract::{Query, State},
+    http::{HeaderMap, StatusCode},
+    response::{
+        sse::{Event, KeepAlive, Sse},
+        IntoResponse, Response,
+    },
+    Extension, Form,
+};
+use futures_util::{stream, Stream};
+use tokio::sync::broadcast;
+use tracing::{debug, error};
+use uuid::Uuid;
+
+use crate::{
+    db,
+    frontend::templates::ChatAreaPromptError,
+    hub::Topic,
+    models::{self, User},
+    state::AppState,
+    validation,
+    ws::{self, WebsocketQuery},
+    CONFIG,
+};
+
+/// Fragments for a single client, see [`ws::websocket`] which streams the same ones.
+struct Subscriptions {
+    replay: VecDeque<String>,
+    conversation: Option<broadcast::Receiver<String>>,
+    sidebar: broadcast::Receiver<String>,
+}
+
+/// Streams the same HTML fragments as the websocket, for networks where websocket upgrades fail.
+pub async fn events(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    Query(query): Query<WebsocketQuery>,
+) -> Sse<impl Stream<Item = Result<Event, Infallible>>> {
+    debug!("start streaming events");
+
+    let sidebar = state.hub.subscribe(Topic::Sidebar(user.id));
+    let subscription = match query.conversation_id {
+        Some(conversation_id) => ws::watch_conversation(&state, &user, conversation_id)
+            .await
+            .unwrap_or_else(|err| {
+                error!(?err, "cannot subscribe to conversation");
+                None
+            }),
+        None => None,
+    };
+    let subscriptions = match subscription {
+        Some(subscription) => Subscriptions {
+            replay: subscription.replay.into(),
+            conversation: Some(subscription.receiver),
+            sidebar,
+        },
+        None => Subscriptions {
+            replay: VecDeque::new(),
+            conversation: None,
+            sidebar,
+        },
+    };
+
+    let events = stream::unfold(subscriptions, |mut subscriptions| async move {
+        let html = match subscriptions.replay.pop_front() {
+            Some(html) => html,
+            None => tokio::select! {
+                html = ws::next_fragment(&mut subscriptions.conversation) => html,
+                html = ws::next_sidebar_fragment(&mut subscriptions.sidebar) => html,
+            }?,
+        };
+        debug!(?html, "sending event");
+        Some((Ok(Event::default().data(html)), subscriptions))
+    });
+
+    // proxies close idle connections, comments keep the stream alive between answers
+    Sse::new(events).keep_alive(KeepAlive::default())
+}
+
+/// Submits a prompt sent by a client streaming answers over [`events`].
+pub async fn prompt(
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    headers: HeaderMap,
+    // attachment ids repeat, so fields can't be deserialised into a struct
+    Form(fields): Form<Vec<(String, String)>>,
+) -> Response {
+    let Some(conversation_id) = headers
+        .get("HX-Current-URL")
+        .and_then(|url| url.to_str().ok())
+        .and_then(models::conversation_id_from_url)
+    else {
+        return (
+            StatusCode::UNPROCESSABLE_ENTITY,
+            "prompt has to be sent from a conversation",
+        )
+            .into_response();
+    };
+
+    let mut user_prompt = None;
+    let mut attachment_ids = Vec::new();
+    for (name, value) in fields {
+        match name.as_str() {
+            "user_prompt" => user_prompt = Some(value),
+            "attachment_ids" => match Uuid::try_parse(&value) {
+                Ok(attachment_id) => attachment_ids.push(attachment_id),
+                Err(_) => {
+                    return (StatusCode::UNPROCESSABLE_ENTITY, "invalid attachment id")
+                        .into_response()
+                }
+            },
+            _ => {}
+        }
+    }
+    let user_prompt = user_prompt.unwrap_or_default();
+    if let Err(err) = validation::prompt(&user_prompt, CONFIG.lokai_max_prompt_bytes) {
+        // only the prompt form posts here, the error is shown above it
+        let notice = ChatAreaPromptError {
+            error: err.to_string(),
+        };
+        return (StatusCode::UNPROCESSABLE_ENTITY, notice).into_response();
+    }
+
+    match db::conversation_exists(state.sqlite.clone(), conversation_id, user.id).await {
+        Ok(true) => {}
+        Ok(false) => return StatusCode::NOT_FOUND.into_response(),
+        Err(err) => {
+            error!(
+                conversation_id = conversation_id.to_string(),
+                "Error when checking conversation: {:?}", err
+            );
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    }
+
+    let stream = match state
+        .limits
+        .check_prompt(state.sqlite.clone(), user.id)
+        .await
+    {
+        Ok(Ok(stream)) => stream,
+        Ok(Err(rejection)) => return rejection.into_response(),
+        Err(err) => {
+            error!("Error when checking limits: {:?}", err);
+            return StatusCode::INTERNAL_SERVER_ERROR.into_response();
+        }
+    };
+
+    let mut user_prompt = models::Message::user(user_prompt, conversation_id);
+    user_prompt.attachment_ids = attachment_ids;
+    state.jobs.submit(state.clone(), user, user_prompt, stream);
+
+    // the prompt and the answer come through the event stream
+    StatusCode::NO_CONTENT.into_response()
+}
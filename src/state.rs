@@ -1,8 +1,21 @@
+use std::sync::Arc;
+
 use axum::extract::FromRef;
 use sqlx::SqlitePool;
 
+use crate::hub::Hub;
+use crate::jobs::Jobs;
+use crate::limits::Limits;
+use crate::scheduler::Scheduler;
+use crate::tools::ToolRegistry;
+
 #[derive(FromRef, Clone)]
 pub struct AppState {
     pub sqlite: SqlitePool,
     pub reqwest_client: reqwest::Client,
+    pub tools: Arc<ToolRegistry>,
+    pub hub: Arc<Hub>,
+    pub jobs: Arc<Jobs>,
+    pub scheduler: Arc<Scheduler>,
+    pub limits: Arc<Limits>,
 }
@@ -0,0 +1,380 @@
+use chrono::{Local, Utc};
+use futures_util::future::BoxFuture;
+use serde_json::{json, Value};
+use sqlx::SqlitePool;
+use uuid::Uuid;
+
+use crate::db;
+use crate::error::{Error, Result};
+use crate::ollama::{OllamaTool, OllamaToolFunction};
+
+const SEARCH_CONVERSATIONS_LIMIT: i64 = 5;
+
+/// What a tool may access on behalf of the user who sent the prompt.
+pub struct ToolContext {
+    pub sqlite: SqlitePool,
+    pub user_id: Uuid,
+}
+
+/// A function the model can call during inference. Results are fed back to the model as text.
+pub trait Tool: Send + Sync {
+    fn name(&self) -> &'static str;
+
+    fn description(&self) -> &'static str;
+
+    /// JSON Schema of the arguments object.
+    fn parameters(&self) -> Value;
+
+    fn call<'a>(
+        &'a self,
+        context: &'a ToolContext,
+        arguments: Value,
+    ) -> BoxFuture<'a, Result<String>>;
+}
+
+pub struct ToolRegistry {
+    tools: Vec<Box<dyn Tool>>,
+}
+
+impl ToolRegistry {
+    pub fn new(tools: Vec<Box<dyn Tool>>) -> Self {
+        Self { tools }
+    }
+
+    pub fn builtin() -> Self {
+        Self::new(vec![
+            Box::new(Calculator),
+            Box::new(CurrentTime),
+            Box::new(SearchConversations),
+        ])
+    }
+
+    pub fn definitions(&self) -> Vec<OllamaTool> {
+        self.tools
+            .iter()
+            .map(|tool| OllamaTool {
+                r#type: "function".to_string(),
+                function: OllamaToolFunction {
+                    name: tool.name().to_string(),
+                    description: tool.description().to_string(),
+                    parameters: tool.parameters(),
+                },
+            })
+            .collect()
+    }
+
+    /// Runs the tool and returns its output, errors are returned as text so the model can recover.
+    pub async fn call(&self, context: &ToolContext, name: &str, arguments: Value) -> String {
+        let Some(tool) = self.tools.iter().find(|tool| tool.name() == name) else {
+            return Error::Tool(format!("unknown tool `{name}`")).to_string();
+        };
+        match tool.call(context, arguments).await {
+            Ok(output) => output,
+            Err(err) => err.to_string(),
+        }
+    }
+}
+
+fn string_argument<'a>(arguments: &'a Value, name: &str) -> Result<&'a str> {
+    arguments
+        .get(name)
+        .and_then(Value::as_str)
+        .ok_or_else(|| Error::Tool(format!("missing `{name}` argument")))
+}
+
+pub struct Calculator;
+
+impl Tool for Calculator {
+    fn name(&self) -> &'static str {
+        "calculator"
+    }
+
+    fn description(&self) -> &'static str {
+        "Evaluates an arithmetic expression with +, -, *, /, % and ^ operators and parentheses."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "expression": {
+                    "type": "string",
+                    "description": "Arithmetic expression, e.g. (2 + 3) * 4",
+                },
+            },
+            "required": ["expression"],
+        })
+    }
+
+    fn call<'a>(&'a self, _: &'a ToolContext, arguments: Value) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let expression = string_argument(&arguments, "expression")?;
+            evaluate(expression).map(|value| value.to_string())
+        })
+    }
+}
+
+pub struct CurrentTime;
+
+impl Tool for CurrentTime {
+    fn name(&self) -> &'static str {
+        "current_time"
+    }
+
+    fn description(&self) -> &'static str {
+        "Returns the current date and time of the server, both local and UTC."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({ "type": "object", "properties": {} })
+    }
+
+    fn call<'a>(&'a self, _: &'a ToolContext, _: Value) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            Ok(format!(
+                "local: {}\nutc: {}",
+                Local::now().to_rfc3339(),
+                Utc::now().to_rfc3339()
+            ))
+        })
+    }
+}
+
+pub struct SearchConversations;
+
+impl Tool for SearchConversations {
+    fn name(&self) -> &'static str {
+        "search_conversations"
+    }
+
+    fn description(&self) -> &'static str {
+        "Searches the user's past conversations for messages containing the given text."
+    }
+
+    fn parameters(&self) -> Value {
+        json!({
+            "type": "object",
+            "properties": {
+                "query": {
+                    "type": "string",
+                    "description": "Text to look for",
+                },
+            },
+            "required": ["query"],
+        })
+    }
+
+    fn call<'a>(
+        &'a self,
+        context: &'a ToolContext,
+        arguments: Value,
+    ) -> BoxFuture<'a, Result<String>> {
+        Box::pin(async move {
+            let query = string_argument(&arguments, "query")?;
+            let results = db::search_messages(
+                context.sqlite.clone(),
+                context.user_id,
+                query,
+                SEARCH_CONVERSATIONS_LIMIT,
+            )
+            .await?;
+            if results.is_empty() {
+                return Ok("no matching messages".to_string());
+            }
+            Ok(results
+                .into_iter()
+                .map(|result| {
+                    format!(
+                        "conversation \"{}\" ({}), {} wrote:\n{}",
+                        result.conversation_name, result.created_at, result.role, result.content
+                    )
+                })
+                .collect::<Vec<String>>()
+                .join("\n\n"))
+        })
+    }
+}
+
+// deeper expressions would overflow the stack of the server
+const MAX_NESTING: usize = 64;
+
+/// Evaluates an arithmetic expression using a recursive descent parser.
+pub fn evaluate(expression: &str) -> Result<f64> {
+    let mut parser = Parser {
+        chars: expression.chars().filter(|c| !c.is_whitespace()).collect(),
+        position: 0,
+        depth: 0,
+    };
+    let value = parser.expression()?;
+    if parser.position != parser.chars.len() {
+        return Err(Error::Tool(format!(
+            "unexpected `{}` in expression",
+            parser.chars[parser.position]
+        )));
+    }
+    if !value.is_finite() {
+        return Err(Error::Tool("result is not a finite number".to_string()));
+    }
+    Ok(value)
+}
+
+struct Parser {
+    chars: Vec<char>,
+    position: usize,
+    depth: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<char> {
+        self.chars.get(self.position).copied()
+    }
+
+    /// Parses a nested part of the expression, bounding the recursion.
+    fn nested(&mut self, parse: fn(&mut Self) -> Result<f64>) -> Result<f64> {
+        if self.depth == MAX_NESTING {
+            return Err(Error::Tool("expression too deeply nested".to_string()));
+        }
+        self.depth += 1;
+        let value = parse(self);
+        self.depth -= 1;
+        value
+    }
+
+    // expression := term (('+' | '-') term)*
+    fn expression(&mut self) -> Result<f64> {
+        let mut value = self.term()?;
+        while let Some(op @ ('+' | '-')) = self.peek() {
+            self.position += 1;
+            let rhs = self.term()?;
+            value = if op == '+' { value + rhs } else { value - rhs };
+        }
+        Ok(value)
+    }
+
+    // term := power (('*' | '/' | '%') power)*
+    fn term(&mut self) -> Result<f64> {
+        let mut value = self.power()?;
+        while let Some(op @ ('*' | '/' | '%')) = self.peek() {
+            self.position += 1;
+            let rhs = self.power()?;
+            value = match op {
+                '*' => value * rhs,
+                '/' => value / rhs,
+                _ => value % rhs,
+            };
+        }
+        Ok(value)
+    }
+
+    // power := unary ('^' power)?
+    fn power(&mut self) -> Result<f64> {
+        let base = self.unary()?;
+        if self.peek() == Some('^') {
+            self.position += 1;
+            return Ok(base.powf(self.nested(Self::power)?));
+        }
+        Ok(base)
+    }
+
+    // unary := '-' unary | primary
+    fn unary(&mut self) -> Result<f64> {
+        if self.peek() == Some('-') {
+            self.position += 1;
+            return Ok(-self.nested(Self::unary)?);
+        }
+        self.primary()
+    }
+
+    // primary := number | '(' expression ')'
+    fn primary(&mut self) -> Result<f64> {
+        match self.peek() {
+            Some('(') => {
+                self.position += 1;
+                let value = self.nested(Self::expression)?;
+                if self.peek() != Some(')') {
+                    return Err(Error::Tool("missing closing parenthesis".to_string()));
+                }
+                self.position += 1;
+                Ok(value)
+            }
+            Some(c) if c.is_ascii_digit() || c == '.' => {
+                let start = self.position;
+                while matches!(self.peek(), Some(c) if c.is_ascii_digit() || c == '.') {
+                    self.position += 1;
+                }
+                let number: String = self.chars[start..self.position].iter().collect();
+                number
+                    .parse()
+                    .map_err(|_| Error::Tool(format!("invalid number `{number}`")))
+            }
+            Some(c) => Err(Error::Tool(format!("unexpected `{c}` in expression"))),
+            None => Err(Error::Tool("unexpected end of expression".to_string())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_evaluate_respects_precedence() {
+        assert_eq!(evaluate("2 + 3 * 4").unwrap(), 14.0);
+        assert_eq!(evaluate("(2 + 3) * 4").unwrap(), 20.0);
+        assert_eq!(evaluate("2 ^ 3 ^ 2").unwrap(), 512.0);
+        assert_eq!(evaluate("-2 ^ 2").unwrap(), 4.0);
+        assert_eq!(evaluate("10 % 4 - 1.5").unwrap(), 0.5);
+    }
+
+    #[test]
+    fn test_evaluate_invalid_expressions() {
+        assert!(evaluate("2 +").is_err());
+        assert!(evaluate("(2 + 3").is_err());
+        assert!(evaluate("2 x 3").is_err());
+        assert!(evaluate("1 / 0").is_err());
+    }
+
+    #[test]
+    fn test_evaluate_deeply_nested_expressions() {
+        // given:
+        let parentheses = format!("{}1{}", "(".repeat(100_000), ")".repeat(100_000));
+        let negations = format!("{}1", "-".repeat(100_000));
+        let powers = format!("1{}", "^1".repeat(100_000));
+        let nested = format!("{}1{}", "(".repeat(MAX_NESTING), ")".repeat(MAX_NESTING));
+        let long = "(1) + ".repeat(10_000) + "1";
+
+        // when:
+        let errors = [parentheses, negations, powers].map(|expression| evaluate(&expression));
+
+        // then:
+        for error in errors {
+            assert!(
+                matches!(error, Err(Error::Tool(reason)) if reason == "expression too deeply nested")
+            );
+        }
+        assert_eq!(evaluate(&nested).unwrap(), 1.0);
+        assert_eq!(evaluate(&long).unwrap(), 10_001.0);
+    }
+
+    #[sqlx::test]
+    async fn test_registry_calls_tool_by_name(pool: SqlitePool) {
+        // given:
+        let registry = ToolRegistry::builtin();
+        let context = ToolContext {
+            sqlite: pool,
+            user_id: Uuid::new_v4(),
+        };
+
+        // when:
+        let result = registry
+            .call(&context, "calculator", json!({ "expression": "6 * 7" }))
+            .await;
+        let unknown = registry.call(&context, "rm", json!({})).await;
+        let invalid = registry.call(&context, "calculator", json!({})).await;
+
+        // then:
+        assert_eq!(result, "42");
+        assert_eq!(unknown, "tool error: unknown tool `rm`");
+        assert!(invalid.starts_with("tool error:"));
+    }
+}
@@ -0,0 +1,149 @@
+use crate::error::{Error, Result};
+
+pub const MIN_PASSWORD_LENGTH: usize = 8;
+
+/// Trimmed name of a new conversation.
+pub fn conversation_name(name: &str, max_length: usize) -> Result<String> {
+    let name = name.trim();
+    if name.is_empty() {
+        return Err(Error::Validation("Name cannot be empty".to_string()));
+    }
+    if name.chars().count() > max_length {
+        return Err(Error::Validation(format!(
+            "Name cannot be longer than {max_length} characters"
+        )));
+    }
+    reject_control_characters("Name", name, false)?;
+    Ok(name.to_string())
+}
+
+/// Prompts are sent as typed, only line breaks and tabs are allowed among control characters.
+pub fn prompt(prompt: &str, max_bytes: usize) -> Result<()> {
+    if prompt.trim().is_empty() {
+        return Err(Error::Validation("Prompt cannot be empty".to_string()));
+    }
+    if prompt.len() > max_bytes {
+        return Err(Error::Validation(format!(
+            "Prompt cannot be larger than {} KiB",
+            max_bytes / 1024
+        )));
+    }
+    reject_control_characters("Prompt", prompt, true)
+}
+
+/// Trimmed username, checked together with the password of a new account.
+pub fn credentials(username: &str, password: &str) -> Result<String> {
+    let username = username.trim();
+    if username.is_empty() {
+        return Err(Error::Validation("Username cannot be empty".to_string()));
+    }
+    password_strength(password)?;
+    Ok(username.to_string())
+}
+
+pub fn password_strength(password: &str) -> Result<()> {
+    if password.chars().count() < MIN_PASSWORD_LENGTH {
+        return Err(Error::Validation(format!(
+            "Password must have at least {MIN_PASSWORD_LENGTH} characters"
+        )));
+    }
+    Ok(())
+}
+
+fn reject_control_characters(field: &str, value: &str, allow_whitespace: bool) -> Result<()> {
+    let is_allowed = |c: char| allow_whitespace && matches!(c, '\n' | '\r' | '\t');
+    if value.chars().any(|c| c.is_control() && !is_allowed(c)) {
+        return Err(Error::Validation(format!(
+            "{field} cannot contain control characters"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_conversation_name() {
+        // given:
+        let names = ["  Trip to Rome ", " \t ", "a very long name", "tab\there"];
+
+        // when:
+        let results: Vec<std::result::Result<String, String>> = names
+            .iter()
+            .map(|name| conversation_name(name, 10).map_err(|err| err.to_string()))
+            .collect();
+
+        // then:
+        assert_eq!(
+            results,
+            vec![
+                Err("Name cannot be longer than 10 characters".to_string()),
+                Err("Name cannot be empty".to_string()),
+                Err("Name cannot be longer than 10 characters".to_string()),
+                Err("Name cannot contain control characters".to_string()),
+            ]
+        );
+        assert_eq!(
+            conversation_name("  Trip to Rome ", 20).unwrap(),
+            "Trip to Rome"
+        );
+    }
+
+    #[test]
+    fn test_prompt() {
+        // given:
+        let prompts = [
+            "first line\n\tsecond line".to_string(),
+            "\n ".to_string(),
+            "x".repeat(2049),
+            "bell \u{7}".to_string(),
+        ];
+
+        // when:
+        let results: Vec<std::result::Result<(), String>> = prompts
+            .iter()
+            .map(|value| prompt(value, 2048).map_err(|err| err.to_string()))
+            .collect();
+
+        // then:
+        assert_eq!(
+            results,
+            vec![
+                Ok(()),
+                Err("Prompt cannot be empty".to_string()),
+                Err("Prompt cannot be larger than 2 KiB".to_string()),
+                Err("Prompt cannot contain control characters".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_credentials() {
+        // given:
+        let credentials = [
+            (" admin ", "password123"),
+            ("  ", "password123"),
+            ("admin", "short"),
+        ];
+
+        // when:
+        let results: Vec<std::result::Result<String, String>> = credentials
+            .iter()
+            .map(|(username, password)| {
+                super::credentials(username, password).map_err(|err| err.to_string())
+            })
+            .collect();
+
+        // then:
+        assert_eq!(
+            results,
+            vec![
+                Ok("admin".to_string()),
+                Err("Username cannot be empty".to_string()),
+                Err("Password must have at least 8 characters".to_string()),
+            ]
+        );
+    }
+}
@@ -1,63 +1,185 @@
 use axum::{
     extract::{
         ws::{Message, WebSocket},
-        State, WebSocketUpgrade,
+        Query, State, WebSocketUpgrade,
     },
     response::Response,
+    Extension,
+};
+use std::pin::pin;
+
+use base64::prelude::{Engine as _, BASE64_STANDARD};
+use futures_util::{future::join_all, stream, SinkExt as _, StreamExt as _};
+use serde::Deserialize;
+use sqlx::types::Json;
+use tokio::{
+    sync::{broadcast, mpsc},
+    time::{Duration, Instant},
 };
-use futures_util::{SinkExt as _, StreamExt as _};
-use tokio::sync::mpsc;
 use tracing::{debug, error, info, warn};
+use uuid::Uuid;
 
 use crate::{
     db,
-    error::Result,
-    frontend::templates::{ChatAreaAppendMessage, ChatAreaSwapMessage},
-    ollama::{OllamaChatParams, OllamaChatResponseStream},
+    error::{Error, Result},
+    frontend::templates::{
+        ChatAreaAppendComparison, ChatAreaAppendMessage, ChatAreaPromptError,
+        ChatAreaQueuePosition, ChatAreaSwapMessage, SidebarRefresh,
+    },
+    hub::{Publisher, Topic},
+    jobs::Progress,
+    json_mode::{self, OutputFormat},
+    limits::{Rejection, Requester, Stream},
+    ollama::{self, ModelCapabilities, OllamaChatParams, OllamaChatResponseStream, OllamaMessage},
     CONFIG,
 };
-use crate::{models, state::AppState};
+use crate::{
+    models::{
+        self, ApiToken, Citation, DocumentChunk, GenerationStats, MessageGroup, ToolCall, User,
+    },
+    rag,
+    state::AppState,
+    tools::ToolContext,
+    validation,
+};
+
+// upper bound of model -> tools -> model round trips for a single prompt
+const MAX_TOOL_ROUNDS: usize = 5;
+// how many times the model may fix a response which doesn't match the output format
+const JSON_REPAIR_ATTEMPTS: usize = 1;
+// form fields other than the prompt, e.g. HTMX headers and attachment ids
+const MAX_MESSAGE_OVERHEAD_BYTES: usize = 64 * 1024;
+// how often partial answers are written to the db while they're generated
+const PERSIST_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Deserialize)]
+pub struct WebsocketQuery {
+    /// conversation open in the page, its updates are streamed to the client
+    pub conversation_id: Option<Uuid>,
+}
+
+pub async fn websocket(
+    ws: WebSocketUpgrade,
+    State(state): State<AppState>,
+    Extension(user): Extension<User>,
+    api_token: Option<Extension<ApiToken>>,
+    Query(query): Query<WebsocketQuery>,
+) -> Response {
+    let requester = Requester::new(&user, api_token.as_ref().map(|Extension(token)| token));
+    // larger prompts are rejected with a message, only frames far beyond the limit close the socket
+    ws.max_message_size(4 * CONFIG.lokai_max_prompt_bytes + MAX_MESSAGE_OVERHEAD_BYTES)
+        .on_upgrade(move |socket| {
+            handle_socket(socket, state, user, requester, query.conversation_id)
+        })
+}
+
+/// Live fragments of a conversation and the latest snapshots of answers being generated in it.
+pub struct Subscription {
+    pub receiver: broadcast::Receiver<String>,
+    pub replay: Vec<String>,
+}
+
+/// Subscription to the conversation, only if the user owns it.
+pub async fn watch_conversation(
+    state: &AppState,
+    user: &User,
+    conversation_id: Uuid,
+) -> Result<Option<Subscription>> {
+    if !db::conversation_exists(state.sqlite.clone(), conversation_id, user.id).await? {
+        return Ok(None);
+    }
+    // snapshots are taken after subscribing, so nothing falls in between
+    let receiver = state.hub.subscribe(Topic::Conversation(conversation_id));
+    let replay = state.jobs.progress(conversation_id);
+    Ok(Some(Subscription { receiver, replay }))
+}
 
-pub async fn websocket(ws: WebSocketUpgrade, State(state): State<AppState>) -> Response {
-    ws.on_upgrade(|socket| handle_socket(socket, state))
+/// Next fragment of an optional subscription, lagging behind only skips fragments.
+pub async fn next_fragment(receiver: &mut Option<broadcast::Receiver<String>>) -> Option<String> {
+    let Some(receiver) = receiver else {
+        return std::future::pending().await;
+    };
+    loop {
+        match receiver.recv().await {
+            Ok(html) => return Some(html),
+            Err(broadcast::error::RecvError::Lagged(skipped)) => {
+                warn!(skipped, "ws client lagging behind, skipping fragments")
+            }
+            Err(broadcast::error::RecvError::Closed) => return None,
+        }
+    }
+}
+
+/// Next sidebar fragment, a client lagging behind reloads its whole sidebar instead.
+pub async fn next_sidebar_fragment(receiver: &mut broadcast::Receiver<String>) -> Option<String> {
+    match receiver.recv().await {
+        Ok(html) => Some(html),
+        Err(broadcast::error::RecvError::Lagged(skipped)) => {
+            warn!(skipped, "client lagging behind, refreshing its sidebar");
+            Some(SidebarRefresh.to_string())
+        }
+        Err(broadcast::error::RecvError::Closed) => None,
+    }
 }
 
-async fn handle_socket(socket: WebSocket, state: AppState) {
+async fn handle_socket(
+    socket: WebSocket,
+    state: AppState,
+    user: User,
+    requester: Requester,
+    conversation_id: Option<Uuid>,
+) {
     debug!("start handling a socket");
 
-    let (inference_request_tx, mut inference_request_rx) = mpsc::channel::<models::Message>(100);
-    let (inference_response_tx, mut inference_response_rx) = mpsc::channel::<String>(100);
+    // conversation the client watches changes when it sends a prompt to another one
+    let (subscription_tx, mut subscription_rx) = mpsc::channel::<Subscription>(10);
+    // fragments only for this client, e.g. rejected prompts
+    let (notice_tx, mut notice_rx) = mpsc::channel::<String>(10);
     let (mut sender, mut receiver) = socket.split();
 
-    let mut inference_thread = tokio::spawn(async move {
-        info!("inference thread started");
-        while let Some(user_prompt) = inference_request_rx.recv().await {
-            let inference_response_tx_clone = inference_response_tx.clone();
-            let state_clone = state.clone();
-            match inference(user_prompt, inference_response_tx_clone, state_clone).await {
-                Ok(_) => {}
-                // consider sending a message through the channel to indicate failure
-                Err(err) => {
-                    error!(?err, "error while processing inference request, exiting...");
-                    break;
-                }
-            };
-        }
-        info!("inference thread exited");
+    let mut sidebar = state.hub.subscribe(Topic::Sidebar(user.id));
+    let subscription = match conversation_id {
+        Some(conversation_id) => watch_conversation(&state, &user, conversation_id)
+            .await
+            .unwrap_or_else(|err| {
+                error!(?err, "cannot subscribe to conversation");
+                None
+            }),
+        None => None,
+    };
+    let mut watched_conversation_id = subscription.as_ref().and(conversation_id);
+    // a reloaded page resumes answers which are still being generated
+    let mut replay = Vec::new();
+    let mut conversation = subscription.map(|subscription| {
+        replay = subscription.replay;
+        subscription.receiver
     });
 
     let mut sender_thread = tokio::spawn(async move {
         info!("ws sender thread started");
-        while let Some(assistant_response_chunk_html) = inference_response_rx.recv().await {
-            debug!(
-                ?assistant_response_chunk_html,
-                "got assistant response chunk"
-            );
-            if sender
-                .send(Message::Text(assistant_response_chunk_html))
-                .await
-                .is_err()
-            {
+        loop {
+            for html in replay.drain(..) {
+                debug!(?html, "replaying fragment");
+                if sender.send(Message::Text(html)).await.is_err() {
+                    // client disconnected
+                    return;
+                }
+            }
+            let html = tokio::select! {
+                Some(subscription) = subscription_rx.recv() => {
+                    conversation = Some(subscription.receiver);
+                    replay = subscription.replay;
+                    continue;
+                }
+                Some(html) = notice_rx.recv() => Some(html),
+                html = next_fragment(&mut conversation) => html,
+                html = next_sidebar_fragment(&mut sidebar) => html,
+            };
+            let Some(html) = html else {
+                break;
+            };
+            debug!(?html, "sending fragment");
+            if sender.send(Message::Text(html)).await.is_err() {
                 // client disconnected
                 return;
             }
@@ -85,34 +207,65 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                     break;
                 }
             };
-            match inference_request_tx.send(user_prompt).await {
-                Ok(_) => {}
+            if let Err(err) =
+                validation::prompt(&user_prompt.content, CONFIG.lokai_max_prompt_bytes)
+            {
+                let notice = ChatAreaPromptError {
+                    error: err.to_string(),
+                };
+                if notice_tx.send(notice.to_string()).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            // subscribe before the prompt is processed, so no fragment of the answer is missed
+            if watched_conversation_id != Some(user_prompt.conversation_id) {
+                match watch_conversation(&state, &user, user_prompt.conversation_id).await {
+                    Ok(Some(subscription)) => {
+                        watched_conversation_id = Some(user_prompt.conversation_id);
+                        if subscription_tx.send(subscription).await.is_err() {
+                            break;
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(err) => {
+                        error!(?err, "cannot subscribe to conversation, exiting...");
+                        break;
+                    }
+                }
+            }
+            let stream = match check_limits(&state, requester).await {
+                Ok(Ok(stream)) => stream,
+                Ok(Err(rejection)) => {
+                    debug!(user_id = user.id.to_string(), ?rejection, "prompt rejected");
+                    let notice = ChatAreaPromptError {
+                        error: rejection.to_string(),
+                    };
+                    if notice_tx.send(notice.to_string()).await.is_err() {
+                        break;
+                    }
+                    continue;
+                }
                 Err(err) => {
-                    error!(?err, "cannot send inference request, exiting...");
+                    error!(?err, "cannot check limits, exiting...");
                     break;
                 }
             };
+            // generation outlives the socket, closing the page doesn't lose the answer
+            state
+                .jobs
+                .submit(state.clone(), user.clone(), user_prompt, stream);
         }
         info!("ws receiver thread exited");
     });
 
     tokio::select! {
-        inference_thread_result = (&mut inference_thread) => {
-            match inference_thread_result {
-                Ok(_) => info!("inference thread exited without errors"),
-                Err(err) => error!(?err, "error returned by inference thread"),
-            }
-            warn!("aborting other threads");
-            sender_thread.abort();
-            receiver_thread.abort();
-        },
         sender_thread_result = (&mut sender_thread) => {
             match sender_thread_result {
                 Ok(_) => info!("sender thread exited without errors"),
                 Err(err) => error!(?err, "error returned by sender thread"),
             }
-            warn!("aborting other threads");
-            inference_thread.abort();
+            warn!("aborting receiver thread");
             receiver_thread.abort();
         },
         receiver_thread_result = (&mut receiver_thread) => {
@@ -120,78 +273,512 @@ async fn handle_socket(socket: WebSocket, state: AppState) {
                 Ok(_) => info!("receiver thread exited without errors"),
                 Err(err) => error!(?err, "error returned by receiver thread"),
             }
-            warn!("aborting other threads");
+            warn!("aborting sender thread");
             sender_thread.abort();
-            inference_thread.abort();
         },
     }
 
     debug!("finished handling a socket");
 }
 
-// TODO: pass the name of the model used for the inference
-async fn inference(
+/// Prompts over the websocket count as requests too, only the upgrade goes through the middleware.
+async fn check_limits(
+    state: &AppState,
+    requester: Requester,
+) -> Result<std::result::Result<Stream, Rejection>> {
+    if let Err(rejection) = state.limits.check_request(requester, Instant::now()) {
+        return Ok(Err(rejection));
+    }
+    state
+        .limits
+        .check_prompt(state.sqlite.clone(), requester.user_id)
+        .await
+}
+
+pub async fn inference(
     user_prompt: models::Message,
-    inference_response_tx: mpsc::Sender<String>,
     state: AppState,
+    user: &User,
+    progress: &Progress,
 ) -> Result<()> {
     debug!(
         conversation_id = user_prompt.conversation_id.to_string(),
         "start inference"
     );
 
-    // SAFETY: conversation exists at this point as we navigated from web browser and router validated this rule
-    let conversation = db::get_conversation(state.sqlite.clone(), user_prompt.conversation_id)
-        .await?
-        .unwrap();
+    let Some(conversation) =
+        db::get_conversation(state.sqlite.clone(), user_prompt.conversation_id, user.id).await?
+    else {
+        warn!(
+            conversation_id = user_prompt.conversation_id.to_string(),
+            "prompt for a conversation the user doesn't own"
+        );
+        return Ok(());
+    };
     let conversation_id = conversation.id;
+    // every client watching the conversation sees the prompt and the answer
+    let inference_response_tx =
+        Publisher::new(state.hub.clone(), Topic::Conversation(conversation_id))
+            .with_progress(progress.clone());
 
-    let mut messages = db::get_conversation_messages(state.sqlite.clone(), conversation_id).await?;
-    messages.push(user_prompt.clone());
+    // attachment ids come from the client, only the user's own uploads can be linked to the prompt
+    let mut user_prompt = user_prompt;
+    let mut attachment_ids = Vec::with_capacity(user_prompt.attachment_ids.len());
+    for attachment_id in user_prompt.attachment_ids.drain(..) {
+        if db::get_attachment(state.sqlite.clone(), attachment_id, user.id)
+            .await?
+            .is_some()
+        {
+            attachment_ids.push(attachment_id);
+        }
+    }
+    user_prompt.attachment_ids = attachment_ids;
 
-    {
+    let settings = db::get_conversation_settings(state.sqlite.clone(), conversation_id).await?;
+    let llm_model = settings
+        .as_ref()
+        .map(|settings| settings.llm_model.clone())
+        .unwrap_or_else(|| CONFIG.lokai_default_llm_model.to_string());
+    let comparison_models = settings
+        .as_ref()
+        .and_then(|settings| settings.comparison_models.clone())
+        .map(|models| models.0)
+        .filter(|models| models.len() >= models::MIN_COMPARISON_MODELS);
+    let system_prompt = settings
+        .as_ref()
+        .and_then(|settings| settings.system_prompt.clone())
+        .filter(|system_prompt| !system_prompt.trim().is_empty());
+    let output_format = settings
+        .and_then(|settings| settings.output_format)
+        .and_then(|raw| match OutputFormat::parse(&raw) {
+            Ok(output_format) => Some(output_format),
+            Err(err) => {
+                warn!(?err, "ignoring invalid output format");
+                None
+            }
+        });
+
+    let mut messages = models::prune_comparisons(
+        db::get_conversation_messages(state.sqlite.clone(), conversation_id).await?,
+    );
+
+    let user_prompt_id = {
         let sqlite = state.sqlite.clone();
-        let _ = db::create_message(sqlite, user_prompt.clone()).await?;
-        let inference_response_tx = inference_response_tx.clone();
-        inference_response_tx
-            .send(
-                ChatAreaAppendMessage {
-                    message: user_prompt,
+        let user_prompt = db::create_message(sqlite, user_prompt).await?;
+        let user_prompt_id = user_prompt.id;
+        messages.push(user_prompt.clone());
+        inference_response_tx.send(
+            ChatAreaAppendMessage {
+                message: user_prompt,
+            }
+            .to_string(),
+        );
+        // the conversation moves to the top of the sidebar
+        state
+            .hub
+            .publish(Topic::Sidebar(user.id), SidebarRefresh.to_string());
+        user_prompt_id
+    };
+
+    let context = retrieve_context(&state, conversation_id, &messages).await?;
+    let citations: Vec<Citation> = context
+        .iter()
+        .enumerate()
+        .map(|(i, (document_name, chunk))| Citation {
+            message_id: Uuid::nil(),
+            chunk_id: chunk.id,
+            rank: i as i64 + 1,
+            document_id: chunk.document_id,
+            document_name: document_name.clone(),
+            chunk_index: chunk.chunk_index,
+        })
+        .collect();
+
+    if let Some(system_prompt) = system_prompt {
+        // lives in the conversation settings, it's never stored as a message
+        messages.insert(0, models::Message::system(system_prompt, conversation_id));
+    }
+
+    if let Some(comparison_models) = comparison_models {
+        return compare(
+            &state,
+            Comparison {
+                id: user_prompt_id,
+                conversation_id,
+                user_id: user.id,
+                llm_models: comparison_models,
+            },
+            messages,
+            &context,
+            &citations,
+            output_format.as_ref(),
+            &inference_response_tx,
+        )
+        .await;
+    }
+
+    let (capabilities, mut messages) =
+        prepare_messages(&state, &llm_model, messages, &context).await?;
+
+    let tool_context = ToolContext {
+        sqlite: state.sqlite.clone(),
+        user_id: user.id,
+    };
+    for round in 0..=MAX_TOOL_ROUNDS {
+        // the last round goes without tools, so the model has to answer
+        let tools =
+            (capabilities.tools && round < MAX_TOOL_ROUNDS).then(|| state.tools.definitions());
+        let params = OllamaChatParams {
+            model: llm_model.clone(),
+            messages: messages.clone(),
+            stream: true,
+            tools,
+            format: output_format.as_ref().map(OutputFormat::to_ollama),
+        };
+
+        let mut assistant_response = models::Message::assistant("".to_string(), conversation_id);
+        assistant_response.citations = cite(&citations, assistant_response.id);
+        // stored right away, so the partial answer survives a restart
+        let mut assistant_response =
+            db::create_message(state.sqlite.clone(), assistant_response).await?;
+        inference_response_tx.send(
+            ChatAreaAppendMessage {
+                message: assistant_response.clone(),
+            }
+            .to_string(),
+        );
+        let tool_calls = match stream_chat(
+            &state,
+            user.id,
+            &params,
+            &mut assistant_response,
+            &inference_response_tx,
+        )
+        .await
+        {
+            Ok(tool_calls) => requested_tools(round, tool_calls),
+            Err(err) => {
+                return Err(
+                    fail(&state, &mut assistant_response, err, &inference_response_tx).await,
+                )
+            }
+        };
+
+        if tool_calls.is_empty() {
+            if let Some(output_format) = &output_format {
+                if let Err(err) = validate_response(
+                    &state,
+                    user.id,
+                    output_format,
+                    params,
+                    &mut assistant_response,
+                    &inference_response_tx,
+                )
+                .await
+                {
+                    return Err(
+                        fail(&state, &mut assistant_response, err, &inference_response_tx).await,
+                    );
                 }
-                .to_string(),
+            }
+            finish(&state, &assistant_response, &inference_response_tx).await?;
+            break;
+        }
+
+        debug!(
+            conversation_id = conversation_id.to_string(),
+            ?tool_calls,
+            "model requested tools"
+        );
+        assistant_response.citations = Vec::new();
+        assistant_response.tool_calls = Some(Json(tool_calls.clone()));
+        finish(&state, &assistant_response, &inference_response_tx).await?;
+        messages.push(OllamaMessage::from(assistant_response));
+
+        for tool_call in tool_calls {
+            let output = state
+                .tools
+                .call(&tool_context, &tool_call.name, tool_call.arguments)
+                .await;
+            let tool_result = db::create_message(
+                state.sqlite.clone(),
+                models::Message::tool(output, tool_call.name, conversation_id),
             )
             .await?;
+            inference_response_tx.send(
+                ChatAreaAppendMessage {
+                    message: tool_result.clone(),
+                }
+                .to_string(),
+            );
+            messages.push(OllamaMessage::from(tool_result));
+        }
     }
 
-    let params = OllamaChatParams {
-        model: CONFIG.lokai_default_llm_model.to_string(),
-        messages: messages.into_iter().map(|m| m.into()).collect(),
-        stream: true,
-    };
+    debug!(
+        conversation_id = conversation_id.to_string(),
+        "inference done"
+    );
+
+    Ok(())
+}
+
+/// Tools to call after the round, the last round is the answer even when the model still asks for tools.
+fn requested_tools(round: usize, tool_calls: Vec<ToolCall>) -> Vec<ToolCall> {
+    if round < MAX_TOOL_ROUNDS || tool_calls.is_empty() {
+        return tool_calls;
+    }
+    warn!(?tool_calls, "tool calls ignored, no tool rounds left");
+    Vec::new()
+}
+
+struct Comparison {
+    /// id of the user prompt
+    id: Uuid,
+    conversation_id: Uuid,
+    /// owner of the conversation, its generations wait for their turn in the scheduler
+    user_id: Uuid,
+    llm_models: Vec<String>,
+}
+
+/// Answers the prompt with every model of the comparison concurrently, tools aren't offered to keep answers comparable.
+#[allow(clippy::too_many_arguments)]
+async fn compare(
+    state: &AppState,
+    comparison: Comparison,
+    messages: Vec<models::Message>,
+    context: &[(String, DocumentChunk)],
+    citations: &[Citation],
+    output_format: Option<&OutputFormat>,
+    inference_response_tx: &Publisher,
+) -> Result<()> {
+    let mut answers = Vec::with_capacity(comparison.llm_models.len());
+    for llm_model in comparison.llm_models {
+        let mut answer =
+            models::Message::comparison(llm_model, comparison.id, comparison.conversation_id);
+        answer.citations = cite(citations, answer.id);
+        answers.push(db::create_message(state.sqlite.clone(), answer).await?);
+    }
+    inference_response_tx.send(
+        ChatAreaAppendComparison {
+            group: MessageGroup {
+                comparison_id: Some(comparison.id),
+                messages: answers.clone(),
+            },
+        }
+        .to_string(),
+    );
+
+    let generations = answers.into_iter().map(|mut answer| {
+        let messages = messages.clone();
+        async move {
+            let llm_model = answer.stats.llm_model.clone().unwrap_or_default();
+            let (_, messages) = prepare_messages(state, &llm_model, messages, context).await?;
+            let params = OllamaChatParams {
+                model: llm_model,
+                messages,
+                stream: true,
+                tools: None,
+                format: output_format.map(OutputFormat::to_ollama),
+            };
+            let generated = async {
+                stream_chat(
+                    state,
+                    comparison.user_id,
+                    &params,
+                    &mut answer,
+                    inference_response_tx,
+                )
+                .await?;
+                if let Some(output_format) = output_format {
+                    validate_response(
+                        state,
+                        comparison.user_id,
+                        output_format,
+                        params,
+                        &mut answer,
+                        inference_response_tx,
+                    )
+                    .await?;
+                }
+                Ok(())
+            }
+            .await;
+            match generated {
+                Ok(()) => finish(state, &answer, inference_response_tx).await,
+                Err(err) => Err(fail(state, &mut answer, err, inference_response_tx).await),
+            }
+        }
+    });
+    for result in join_all(generations).await {
+        result?;
+    }
+
+    debug!(
+        conversation_id = comparison.conversation_id.to_string(),
+        "comparison done"
+    );
+
+    Ok(())
+}
+
+/// Converts the history for the model and adds retrieved context right before the question.
+async fn prepare_messages(
+    state: &AppState,
+    llm_model: &str,
+    messages: Vec<models::Message>,
+    context: &[(String, DocumentChunk)],
+) -> Result<(ModelCapabilities, Vec<OllamaMessage>)> {
+    let capabilities =
+        ollama::model_capabilities(&state.reqwest_client, &CONFIG.ollama_url, llm_model)
+            .await
+            .unwrap_or_else(|err| {
+                warn!(?err, llm_model, "cannot check model capabilities");
+                ModelCapabilities::default()
+            });
+
+    let mut messages = ollama_messages(state, llm_model, capabilities, messages).await?;
+    if !context.is_empty() {
+        // keep the context right before the question it was retrieved for
+        let at = messages.len() - 1;
+        messages.insert(at, OllamaMessage::system(rag::context_prompt(context)));
+    }
+
+    Ok((capabilities, messages))
+}
+
+/// Stores the complete answer and sends its final snapshot.
+async fn finish(
+    state: &AppState,
+    assistant_response: &models::Message,
+    inference_response_tx: &Publisher,
+) -> Result<()> {
+    db::finish_message(state.sqlite.clone(), assistant_response).await?;
+    inference_response_tx.send_finished(
+        assistant_response.id,
+        ChatAreaSwapMessage {
+            message: assistant_response.clone(),
+        }
+        .to_string(),
+    );
+    Ok(())
+}
+
+/// Keeps what was generated before the error and marks the answer as failed, returns the error.
+async fn fail(
+    state: &AppState,
+    assistant_response: &mut models::Message,
+    err: Error,
+    inference_response_tx: &Publisher,
+) -> Error {
+    assistant_response.generation_error = Some(err.public_message());
+    if let Err(err) = db::finish_message(state.sqlite.clone(), assistant_response).await {
+        error!(
+            message_id = assistant_response.id.to_string(),
+            ?err,
+            "cannot store failed answer"
+        );
+    }
+    // clients stop waiting for the answer even when it couldn't be stored
+    inference_response_tx.send_finished(
+        assistant_response.id,
+        ChatAreaSwapMessage {
+            message: assistant_response.clone(),
+        }
+        .to_string(),
+    );
+    err
+}
+
+fn cite(citations: &[Citation], message_id: Uuid) -> Vec<Citation> {
+    citations
+        .iter()
+        .map(|citation| Citation {
+            message_id,
+            ..citation.clone()
+        })
+        .collect()
+}
+
+/// Streams the model response into `assistant_response` and returns tools requested by the model.
+async fn stream_chat(
+    state: &AppState,
+    user_id: Uuid,
+    params: &OllamaChatParams,
+    assistant_response: &mut models::Message,
+    inference_response_tx: &Publisher,
+) -> Result<Vec<ToolCall>> {
+    // held until the response is streamed, so others wait instead of contending for the model
+    let mut slot = state.scheduler.enqueue(&CONFIG.ollama_url, user_id);
+    slot.ready(|position| {
+        inference_response_tx.send_progress(
+            assistant_response.id,
+            ChatAreaQueuePosition {
+                message_id: assistant_response.id,
+                position,
+            }
+            .to_string(),
+        )
+    })
+    .await;
 
-    let mut stream = state
+    let chunks = state
         .reqwest_client
         .post(format!("{}/api/chat", CONFIG.ollama_url))
-        .json(&params)
+        .json(params)
         .send()
         .await?
-        .bytes_stream()
-        .map(|chunk| chunk.unwrap())
-        .map(|chunk| serde_json::from_slice::<OllamaChatResponseStream>(&chunk));
+        .error_for_status()?
+        .bytes_stream();
 
-    let mut assistant_response = models::Message::assistant("".to_string(), conversation_id);
-    inference_response_tx
-        .send(
-            ChatAreaAppendMessage {
-                message: assistant_response.clone(),
-            }
-            .to_string(),
-        )
-        .await?;
+    receive_chat(state, chunks, assistant_response, inference_response_tx).await
+}
 
+/// Reads the chunks of a streamed model response, a broken connection ends the answer with an error.
+async fn receive_chat<B, E>(
+    state: &AppState,
+    chunks: impl stream::Stream<Item = std::result::Result<B, E>>,
+    assistant_response: &mut models::Message,
+    inference_response_tx: &Publisher,
+) -> Result<Vec<ToolCall>>
+where
+    B: AsRef<[u8]>,
+    Error: From<E>,
+{
+    let mut chunks = pin!(chunks);
+    // the response is newline delimited JSON, a line can be split across chunks
+    let mut buffer = Vec::new();
+    let mut is_stream_done = false;
+    let mut tool_calls = Vec::new();
     let mut is_first_chunk = true;
-    while let Some(chunk) = stream.next().await {
-        if let Ok(chunk) = chunk {
+    let mut persisted_at = Instant::now();
+    while !is_stream_done {
+        let lines = match chunks.next().await {
+            Some(bytes) => {
+                buffer.extend_from_slice(bytes?.as_ref());
+                take_lines(&mut buffer)
+            }
+            None => {
+                is_stream_done = true;
+                vec![std::mem::take(&mut buffer)]
+            }
+        };
+        for line in lines {
+            if line.iter().all(u8::is_ascii_whitespace) {
+                continue;
+            }
+            let chunk = match serde_json::from_slice::<OllamaChatResponseStream>(&line) {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    warn!(
+                        message_id = assistant_response.id.to_string(),
+                        line = %String::from_utf8_lossy(&line),
+                        %err,
+                        "skipping unparsable line of the model response"
+                    );
+                    continue;
+                }
+            };
             let msg_content = &chunk.message.content;
             let msg_content = if is_first_chunk {
                 is_first_chunk = false;
@@ -201,31 +788,354 @@ async fn inference(
             };
 
             assistant_response.update_content(msg_content);
+            if let Some(chunk_tool_calls) = chunk.message.tool_calls.clone() {
+                tool_calls.extend(chunk_tool_calls.into_iter().map(ToolCall::from));
+            }
+            if chunk.done {
+                assistant_response.stats = GenerationStats::from(&chunk);
+            }
 
-            if inference_response_tx
-                .send(
-                    ChatAreaSwapMessage {
-                        message: assistant_response.clone(),
-                    }
-                    .to_string(),
+            inference_response_tx.send_progress(
+                assistant_response.id,
+                ChatAreaSwapMessage {
+                    message: assistant_response.clone(),
+                }
+                .to_string(),
+            );
+
+            if chunk.done {
+                return Ok(tool_calls);
+            }
+            if persisted_at.elapsed() >= PERSIST_INTERVAL {
+                db::update_message_content(
+                    state.sqlite.clone(),
+                    assistant_response.id,
+                    &assistant_response.content,
                 )
-                .await
-                .is_err()
-            {
+                .await?;
+                persisted_at = Instant::now();
+            }
+        }
+    }
+
+    Ok(tool_calls)
+}
+
+/// Takes the complete lines off the buffer, a partial last line stays for the next chunk.
+fn take_lines(buffer: &mut Vec<u8>) -> Vec<Vec<u8>> {
+    match buffer.iter().rposition(|byte| *byte == b'\n') {
+        Some(end) => buffer
+            .drain(..=end)
+            .collect::<Vec<u8>>()
+            .split(|byte| *byte == b'\n')
+            .map(<[u8]>::to_vec)
+            .collect(),
+        None => Vec::new(),
+    }
+}
+
+/// Validates the response against the output format, asking the model to repair it when it doesn't match.
+async fn validate_response(
+    state: &AppState,
+    user_id: Uuid,
+    output_format: &OutputFormat,
+    mut params: OllamaChatParams,
+    assistant_response: &mut models::Message,
+    inference_response_tx: &Publisher,
+) -> Result<()> {
+    for attempt in 0..=JSON_REPAIR_ATTEMPTS {
+        let errors = match output_format.validate(&assistant_response.content) {
+            Ok(()) => {
+                assistant_response.validation_errors = None;
                 break;
-            };
+            }
+            Err(errors) => errors,
+        };
+        debug!(
+            message_id = assistant_response.id.to_string(),
+            attempt,
+            ?errors,
+            "response doesn't match output format"
+        );
+        assistant_response.validation_errors = Some(Json(errors.clone()));
+        if attempt == JSON_REPAIR_ATTEMPTS {
+            break;
+        }
 
-            if chunk.done {
+        // the invalid response and the repair request are only sent to the model, never stored
+        params.tools = None;
+        params
+            .messages
+            .push(OllamaMessage::from(assistant_response.clone()));
+        params
+            .messages
+            .push(OllamaMessage::user(json_mode::repair_prompt(&errors)));
+        assistant_response.content = String::new();
+        assistant_response.stats = GenerationStats::default();
+        stream_chat(
+            state,
+            user_id,
+            &params,
+            assistant_response,
+            inference_response_tx,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+/// Finds the document chunks most relevant to the last message, paired with their document names.
+async fn retrieve_context(
+    state: &AppState,
+    conversation_id: Uuid,
+    messages: &[models::Message],
+) -> Result<Vec<(String, DocumentChunk)>> {
+    let chunks = db::get_conversation_chunks(state.sqlite.clone(), conversation_id).await?;
+    let Some(question) = messages.last() else {
+        return Ok(Vec::new());
+    };
+    if chunks.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    let query = match ollama::embed(
+        &state.reqwest_client,
+        &CONFIG.ollama_url,
+        &CONFIG.lokai_embedding_model,
+        std::slice::from_ref(&question.content),
+    )
+    .await
+    {
+        Ok(mut embeddings) if !embeddings.is_empty() => embeddings.swap_remove(0),
+        Ok(_) => return Ok(Vec::new()),
+        Err(err) => {
+            warn!(?err, "cannot embed prompt, answering without documents");
+            return Ok(Vec::new());
+        }
+    };
+
+    let documents = db::get_conversation_documents(state.sqlite.clone(), conversation_id).await?;
+    let context = rag::top_k(&query, chunks, CONFIG.lokai_rag_top_k)
+        .into_iter()
+        .map(|chunk| {
+            let document_name = documents
+                .iter()
+                .find(|document| document.id == chunk.document_id)
+                .map(|document| document.name.clone())
+                .unwrap_or_default();
+            (document_name, chunk)
+        })
+        .collect();
+
+    Ok(context)
+}
+
+async fn ollama_messages(
+    state: &AppState,
+    llm_model: &str,
+    capabilities: ModelCapabilities,
+    messages: Vec<models::Message>,
+) -> Result<Vec<OllamaMessage>> {
+    let has_attachments = messages.iter().any(|m| !m.attachment_ids.is_empty());
+    let supports_vision = capabilities.vision;
+    if has_attachments && !supports_vision {
+        warn!(
+            llm_model,
+            "model doesn't support vision, images won't be sent"
+        );
+    }
+
+    let mut ollama_messages = Vec::with_capacity(messages.len());
+    for message in messages {
+        let images = if supports_vision && !message.attachment_ids.is_empty() {
+            let attachments = db::get_message_attachments(state.sqlite.clone(), message.id).await?;
+            Some(
+                attachments
+                    .into_iter()
+                    .map(|attachment| BASE64_STANDARD.encode(attachment.data))
+                    .collect(),
+            )
+        } else {
+            None
+        };
+        let mut ollama_message = OllamaMessage::from(message);
+        ollama_message.images = images;
+        ollama_messages.push(ollama_message);
+    }
+
+    Ok(ollama_messages)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io;
+    use std::sync::Arc;
+
+    use sqlx::SqlitePool;
+
+    use super::*;
+    use crate::{
+        hub::Hub, jobs::Jobs, limits::Limits, models::Conversation, scheduler::Scheduler,
+        tools::ToolRegistry,
+    };
+
+    fn state(sqlite: SqlitePool) -> AppState {
+        AppState {
+            sqlite,
+            reqwest_client: reqwest::Client::new(),
+            tools: Arc::new(ToolRegistry::builtin()),
+            hub: Arc::new(Hub::default()),
+            jobs: Arc::new(Jobs::default()),
+            scheduler: Arc::new(Scheduler::new(1)),
+            limits: Arc::new(Limits::new(0, 0, 0)),
+        }
+    }
+
+    #[sqlx::test]
+    async fn test_broken_stream_fails_the_answer(pool: SqlitePool) -> Result<()> {
+        // given:
+        let state = state(pool.clone());
+        let conversation = db::create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), Uuid::new_v4()),
+            "model".to_string(),
+        )
+        .await?;
+        let mut answer = db::create_message(
+            pool.clone(),
+            models::Message::assistant(String::new(), conversation.id),
+        )
+        .await?;
+        let progress = Progress::default();
+        let publisher = Publisher::new(state.hub.clone(), Topic::Conversation(conversation.id))
+            .with_progress(progress.clone());
+        let chunks = stream::iter([
+            Ok(
+                br#"{"model":"model","message":{"role":"assistant","content":"Hel"},"done":false}
+"#
+                .to_vec(),
+            ),
+            Err(io::Error::new(
+                io::ErrorKind::ConnectionReset,
+                "reset by peer",
+            )),
+            Ok(
+                br#"{"model":"model","message":{"role":"assistant","content":"lo"},"done":true}
+"#
+                .to_vec(),
+            ),
+        ]);
+
+        // when:
+        let err = match receive_chat(&state, chunks, &mut answer, &publisher).await {
+            Ok(_) => panic!("the broken stream must fail"),
+            Err(err) => fail(&state, &mut answer, err, &publisher).await,
+        };
+
+        // then:
+        assert!(matches!(err, Error::Io(_)));
+        assert!(progress.fragments().is_empty());
+        let stored = db::get_conversation_messages(pool, conversation.id).await?;
+        assert_eq!(stored.len(), 1);
+        assert_eq!(stored[0].content, "Hel");
+        assert_eq!(
+            stored[0].generation_error.as_deref(),
+            Some("Something went wrong on our side")
+        );
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_lines_split_across_chunks_are_joined(pool: SqlitePool) -> Result<()> {
+        // given:
+        let state = state(pool.clone());
+        let conversation = db::create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), Uuid::new_v4()),
+            "model".to_string(),
+        )
+        .await?;
+        let mut answer = models::Message::assistant(String::new(), conversation.id);
+        let publisher = Publisher::new(state.hub.clone(), Topic::Conversation(conversation.id));
+        let chunks = stream::iter([
+            Ok::<_, io::Error>(
+                br#"{"model":"model","message":{"role":"assistant","content":"Hel"},"done":false}
+{"model":"model","message":{"role":"#
+                    .to_vec(),
+            ),
+            Ok(br#""assistant","content":"lo"},"done":false}
+not json
+{"model":"model","message":{"role":"assistant","content":"!"},"done":true}"#
+                .to_vec()),
+        ]);
+
+        // when:
+        let tool_calls = receive_chat(&state, chunks, &mut answer, &publisher).await?;
+
+        // then:
+        assert!(tool_calls.is_empty());
+        assert_eq!(answer.content, "Hello!");
+
+        Ok(())
+    }
+
+    #[sqlx::test]
+    async fn test_model_asking_for_tools_forever_answers_at_last(pool: SqlitePool) -> Result<()> {
+        // given:
+        let state = state(pool.clone());
+        let conversation = db::create_conversation(
+            pool.clone(),
+            Conversation::new("name".to_string(), Uuid::new_v4()),
+            "model".to_string(),
+        )
+        .await?;
+        let publisher = Publisher::new(state.hub.clone(), Topic::Conversation(conversation.id));
+        let chunk = br#"{"model":"model","message":{"role":"assistant","content":"Let me check.","tool_calls":[{"function":{"name":"calculator","arguments":{"expression":"1 + 1"}}}]},"done":true}
+"#;
+
+        // when:
+        let mut tool_rounds = 0;
+        let mut answer = None;
+        for round in 0..=MAX_TOOL_ROUNDS {
+            let mut response = models::Message::assistant(String::new(), conversation.id);
+            let chunks = stream::iter([Ok::<_, io::Error>(chunk.to_vec())]);
+            let tool_calls = receive_chat(&state, chunks, &mut response, &publisher).await?;
+            if requested_tools(round, tool_calls).is_empty() {
+                answer = Some(response);
                 break;
             }
+            tool_rounds += 1;
         }
+
+        // then:
+        assert_eq!(tool_rounds, MAX_TOOL_ROUNDS);
+        assert_eq!(
+            answer.map(|answer| answer.content),
+            Some("Let me check.".to_string())
+        );
+
+        Ok(())
     }
 
-    let _ = db::create_message(state.sqlite, assistant_response).await;
-    debug!(
-        conversation_id = conversation_id.to_string(),
-        "inference done"
-    );
+    #[tokio::test]
+    async fn test_lagging_sidebar_is_refreshed() {
+        // given:
+        let (sender, mut receiver) = broadcast::channel(1);
+        for html in ["first", "second"] {
+            sender.send(html.to_string()).unwrap();
+        }
 
-    Ok(())
+        // when:
+        let lagged = next_sidebar_fragment(&mut receiver).await;
+        let latest = next_sidebar_fragment(&mut receiver).await;
+        drop(sender);
+        let closed = next_sidebar_fragment(&mut receiver).await;
+
+        // then:
+        assert_eq!(lagged, Some(SidebarRefresh.to_string()));
+        assert_eq!(latest, Some("second".to_string()));
+        assert_eq!(closed, None);
+    }
 }